@@ -0,0 +1,159 @@
+/*
+Time and peak memory of frame calibration at each precision, against the
+original all-f64 path, on a synthetic full-size Emotion 75 frame.
+
+    cargo bench --bench calibration
+*/
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use iatodng::calibrate::{self, Sample};
+use ndarray::{Array1, Zip};
+
+struct PeakAlloc;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for PeakAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let now = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(now, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: PeakAlloc = PeakAlloc;
+
+const WIDTH: usize = 4992;
+const HEIGHT: usize = 6668;
+const RUNS: u32 = 5;
+
+// Little-endian lumps as they come out of the IA/BR/WR files.
+fn synthetic_lumps() -> (Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>) {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut noise = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state % 64) as u16
+    };
+    let (mut raw, mut black0, mut black1, mut white) = (vec![], vec![], vec![], vec![]);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let dx = x as f64 / WIDTH as f64 - 0.5;
+            let dy = y as f64 / HEIGHT as f64 - 0.5;
+            let falloff = 1.0 - (dx * dx + dy * dy);
+            let dark = 1024 + noise();
+            let scene = ((x * 7 + y * 3) % 40_000) as f64;
+            raw.extend_from_slice(&(dark + (scene * falloff) as u16).to_le_bytes());
+            black0.extend_from_slice(&(1024 + noise()).to_le_bytes());
+            black1.extend_from_slice(&dark.to_le_bytes());
+            white.extend_from_slice(&((50_000.0 * falloff) as u16).to_le_bytes());
+        }
+    }
+    (raw, black0, black1, white)
+}
+
+// The pre-generic pipeline: every lump expanded to normalised f64.
+fn legacy_f64(raw: &[u8], black0: &[u8], black1: &[u8], white: &[u8]) -> Array1<f64> {
+    let expand = |buffer: &[u8]| {
+        Array1::from_iter(
+            buffer
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]) as f64 / u16::MAX as f64),
+        )
+    };
+    let mut image = expand(raw);
+    let (black0, black1, white) = (expand(black0), expand(black1), expand(white));
+    Zip::from(&mut image)
+        .and(&black0)
+        .and(&black1)
+        .par_for_each(|i, &b0, &b1| *i = (*i - b0) - (b1 - b0));
+    Zip::from(&mut image)
+        .and(&white)
+        .par_for_each(|i, &w| *i /= w);
+    image
+}
+
+fn generic<S: Sample>(raw: &[u8], black1: &[u8], white: &[u8]) -> Array1<S> {
+    let raw = calibrate::decode_u16_le(raw, WIDTH, HEIGHT);
+    let black = calibrate::decode_u16_le(black1, WIDTH, HEIGHT);
+    let gains = S::gains(&calibrate::decode_u16_le(white, WIDTH, HEIGHT));
+    calibrate::calibrate::<S>(&raw, &black, Some(&gains))
+}
+
+// Scale to 16-bit output DN the way the DNG writer does.
+fn output_dn<S: Sample>(image: &Array1<S>) -> Vec<u16> {
+    let (min, max) = image
+        .iter()
+        .map(|v| v.to_f64())
+        .fold((0.0_f64, 0.0_f64), |(lo, hi), v| (lo.min(v), hi.max(v)));
+    let scale = u16::MAX as f64 / (max - min);
+    image
+        .iter()
+        .map(|v| ((v.to_f64() - min) * scale).round() as u16)
+        .collect()
+}
+
+fn measure<T>(mut run: impl FnMut() -> T) -> (T, Duration, usize) {
+    let mut best = Duration::MAX;
+    let mut peak = 0;
+    let mut result = None;
+    for _ in 0..RUNS {
+        let base = CURRENT.load(Ordering::Relaxed);
+        PEAK.store(base, Ordering::Relaxed);
+        let start = Instant::now();
+        let out = run();
+        best = best.min(start.elapsed());
+        peak = peak.max(PEAK.load(Ordering::Relaxed) - base);
+        result = Some(out);
+    }
+    (result.unwrap(), best, peak)
+}
+
+fn report<S: Sample>(name: &str, run: impl FnMut() -> Array1<S>, reference: &[u16]) {
+    let (image, time, peak) = measure(run);
+    let max_diff = output_dn(&image)
+        .iter()
+        .zip(reference)
+        .map(|(&a, &b)| (a as i32 - b as i32).abs())
+        .max()
+        .unwrap_or(0);
+    println!(
+        "{:<10} {:>10.1?} {:>10.1} MiB {:>8}",
+        name,
+        time,
+        peak as f64 / (1024.0 * 1024.0),
+        max_diff
+    );
+}
+
+fn main() {
+    let (raw, black0, black1, white) = synthetic_lumps();
+    println!(
+        "{}x{} frame, best of {} runs, peak excludes the input lumps",
+        WIDTH, HEIGHT, RUNS
+    );
+    println!(
+        "{:<10} {:>10} {:>14} {:>8}",
+        "path", "time", "peak", "max DN"
+    );
+    let reference = output_dn(&legacy_f64(&raw, &black0, &black1, &white));
+    report(
+        "legacy",
+        || legacy_f64(&raw, &black0, &black1, &white),
+        &reference,
+    );
+    report("f64", || generic::<f64>(&raw, &black1, &white), &reference);
+    report("f32", || generic::<f32>(&raw, &black1, &white), &reference);
+    report("u16", || generic::<u16>(&raw, &black1, &white), &reference);
+}
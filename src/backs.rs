@@ -0,0 +1,519 @@
+/*
+Per-back calibration database.
+
+What is learnt by characterising a back (its colour matrices, defective
+sites, noise, black and white levels) belongs to that back, identified by
+its serial number. The database is a directory holding one YAML file per
+back, named after the serial (`e75-0042.yaml`):
+
+    description: Studio 2
+    white_level: 64000
+    defects:
+      - [1042, 377]
+    colour:
+      - illuminant: d65
+        color_matrix: [1.02, -0.29, -0.07, -0.41, 1.20, 0.23, -0.05, 0.17, 0.58]
+
+With `--backs DIR`, frames from a back in the database use its entry; fields
+left out keep the built-in behaviour. `iatodng backs` creates, updates, shows
+and lists entries.
+
+* Colour matrices replace the placeholder sRGB matrix in DNGs.
+* The noise model, in sensor counts, replaces the one measured per frame.
+* Black and white levels replace those of uncalibrated DNGs, the only output
+  holding sensor counts.
+* Defects are listed in CFA DNGs as a FixBadPixelsList opcode, and
+  interpolated before other formats are demosaiced.
+
+A back can also have a DNG camera profile, `<serial>.dcp`, embedded in its
+DNGs in place of its colour matrices; see `dcp`.
+*/
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::dcp::Dcp;
+use crate::iadng;
+use crate::noise::NoiseModel;
+
+const EXTENSION: &str = "yaml";
+const PROFILE_EXTENSION: &str = "dcp";
+
+const FIX_BAD_PIXELS_LIST_ID: u32 = 5;
+
+/// Light a colour matrix was measured under, from warmest to coolest.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Illuminant {
+    /// Tungsten, 2856 K
+    A,
+    D50,
+    D55,
+    D65,
+    D75,
+}
+
+impl Illuminant {
+    /// EXIF LightSource, as DNG CalibrationIlluminant.
+    pub fn light_source(&self) -> u16 {
+        match self {
+            Illuminant::A => 17,
+            Illuminant::D55 => 20,
+            Illuminant::D65 => 21,
+            Illuminant::D75 => 22,
+            Illuminant::D50 => 23,
+        }
+    }
+
+    /// White point, XYZ with Y = 1.
+    pub fn white(&self) -> [f64; 3] {
+        match self {
+            Illuminant::A => [1.09850, 1.0, 0.35585],
+            Illuminant::D50 => [0.96422, 1.0, 0.82521],
+            Illuminant::D55 => [0.95682, 1.0, 0.92149],
+            Illuminant::D65 => [0.95047, 1.0, 1.08883],
+            Illuminant::D75 => [0.94972, 1.0, 1.22638],
+        }
+    }
+}
+
+/// The matrices of a back under one illuminant.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ColourCalibration {
+    pub illuminant: Illuminant,
+    /// XYZ to camera, row by row (DNG ColorMatrix)
+    pub color_matrix: [f64; 9],
+    /// White balanced camera to XYZ D50, row by row (DNG ForwardMatrix)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward_matrix: Option<[f64; 9]>,
+}
+
+/// One back's entry.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackCalibration {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Dark level of each position in the 2x2 CFA repeat, row by row
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub black_level: Option<[f64; 4]>,
+    /// Counts from which the sensor clips
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub white_level: Option<u16>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub colour: Vec<ColourCalibration>,
+    /// In sensor counts above black
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub noise: Option<NoiseModel>,
+    /// Defective sites, as [column, row]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub defects: Vec<[u32; 2]>,
+    /// When the entry was last saved
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated: Option<String>,
+}
+
+impl BackCalibration {
+    pub fn check(&self) -> Result<(), String> {
+        if let (Some(black), Some(white)) = (self.black_level, self.white_level) {
+            if black.iter().any(|&b| b < 0.0 || b >= white as f64) {
+                return Err("black levels must be between 0 and the white level".to_string());
+            }
+        }
+        for (i, colour) in self.colour.iter().enumerate() {
+            if self.colour[..i]
+                .iter()
+                .any(|c| c.illuminant == colour.illuminant)
+            {
+                return Err(format!("two colour matrices for {:?}", colour.illuminant));
+            }
+        }
+        if let Some(noise) = &self.noise {
+            if noise.shot.iter().chain(&noise.read).any(|&v| v < 0.0) {
+                return Err("noise can't be negative".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Add `colour`, replacing any calibration for the same illuminant.
+    pub fn set_colour(&mut self, colour: ColourCalibration) {
+        match self
+            .colour
+            .iter_mut()
+            .find(|c| c.illuminant == colour.illuminant)
+        {
+            Some(existing) => *existing = colour,
+            None => self.colour.push(colour),
+        }
+    }
+
+    /// The colour calibrations a DNG can hold: the warmest and the coolest.
+    pub fn dng_colour(&self) -> Vec<&ColourCalibration> {
+        let mut colour: Vec<&ColourCalibration> = self.colour.iter().collect();
+        colour.sort_by_key(|c| c.illuminant);
+        if colour.len() > 2 {
+            colour.drain(1..colour.len() - 1);
+        }
+        colour
+    }
+
+    /// What the entry holds, in a line.
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(description) = &self.description {
+            parts.push(description.clone());
+        }
+        if !self.colour.is_empty() {
+            let names: Vec<String> = self
+                .colour
+                .iter()
+                .map(|c| format!("{:?}", c.illuminant))
+                .collect();
+            parts.push(format!("colour {}", names.join("/")));
+        }
+        if self.noise.is_some() {
+            parts.push("noise".to_string());
+        }
+        if self.black_level.is_some() {
+            parts.push("black level".to_string());
+        }
+        if let Some(white) = self.white_level {
+            parts.push(format!("white level {}", white));
+        }
+        if !self.defects.is_empty() {
+            parts.push(format!("{} defects", self.defects.len()));
+        }
+        parts.join(", ")
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Serials name files, so only letters, digits, `-` and `_` are allowed.
+pub fn check_serial(serial: &str) -> Result<(), String> {
+    if serial.is_empty()
+        || !serial
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!("'{}' is not a back's serial number", serial));
+    }
+    Ok(())
+}
+
+/// The database: entries and camera profiles by serial.
+#[derive(Debug, Clone, Default)]
+pub struct BackDb {
+    dir: PathBuf,
+    backs: BTreeMap<String, Arc<BackCalibration>>,
+    profiles: BTreeMap<String, Arc<Dcp>>,
+}
+
+impl BackDb {
+    /// Read the entries in `dir`. A missing directory is an empty database.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let mut db = BackDb {
+            dir: dir.to_path_buf(),
+            backs: BTreeMap::new(),
+            profiles: BTreeMap::new(),
+        };
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(db),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            let serial = path
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some(EXTENSION) => {}
+                Some(PROFILE_EXTENSION) => {
+                    let dcp = Dcp::parse(&fs::read(&path)?)
+                        .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+                    db.profiles.insert(serial, Arc::new(dcp));
+                    continue;
+                }
+                _ => continue,
+            }
+            let back: BackCalibration = serde_yaml::from_str(&fs::read_to_string(&path)?)
+                .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+            back.check()
+                .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+            db.backs.insert(serial, Arc::new(back));
+        }
+        // A profile is enough to make an entry.
+        for serial in db.profiles.keys() {
+            db.backs.entry(serial.clone()).or_default();
+        }
+        Ok(db)
+    }
+
+    pub fn get(&self, serial: &str) -> Option<&Arc<BackCalibration>> {
+        self.backs.get(serial)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Arc<BackCalibration>)> {
+        self.backs.iter()
+    }
+
+    /// The camera profile to embed in the back's DNGs.
+    pub fn profile(&self, serial: &str) -> Option<&Arc<Dcp>> {
+        self.profiles.get(serial)
+    }
+
+    /// Copy the `.dcp` file at `source` into the database as the profile for
+    /// `serial`, replacing any it had.
+    pub fn save_profile(&mut self, serial: &str, source: &Path) -> io::Result<()> {
+        check_serial(serial).map_err(invalid)?;
+        let bytes = fs::read(source)?;
+        let dcp = Dcp::parse(&bytes)?;
+        let path = self.dir.join(format!("{}.{}", serial, PROFILE_EXTENSION));
+        iadng::write_atomically(&path, |tmp| {
+            let mut file = File::create(tmp)?;
+            file.write_all(&bytes)?;
+            file.sync_all()
+        })?;
+        self.profiles.insert(serial.to_string(), Arc::new(dcp));
+        Ok(())
+    }
+
+    /// Write `back` as the entry for `serial`, stamping the time.
+    pub fn save(&mut self, serial: &str, mut back: BackCalibration) -> io::Result<()> {
+        check_serial(serial).map_err(invalid)?;
+        back.check().map_err(invalid)?;
+        back.updated = Some(chrono::Local::now().to_rfc3339());
+        let text = serde_yaml::to_string(&back).map_err(|e| invalid(e.to_string()))?;
+        let path = self.dir.join(format!("{}.{}", serial, EXTENSION));
+        iadng::write_atomically(&path, |tmp| {
+            let mut file = File::create(tmp)?;
+            file.write_all(text.as_bytes())?;
+            file.sync_all()
+        })?;
+        self.backs.insert(serial.to_string(), Arc::new(back));
+        Ok(())
+    }
+}
+
+/// Parse the 4 black levels of a CFA repeat, e.g. `1024,1020,1021,1025`.
+pub fn parse_black_level(value: &str) -> Result<[f64; 4], String> {
+    parse_numbers(value)
+}
+
+/// Parse a noise model in NoiseProfile order: shot and read noise for red,
+/// green and blue.
+pub fn parse_noise(value: &str) -> Result<NoiseModel, String> {
+    let v: [f64; 6] = parse_numbers(value)?;
+    Ok(NoiseModel {
+        shot: [v[0], v[2], v[4]],
+        read: [v[1], v[3], v[5]],
+    })
+}
+
+/// Parse a matrix for an illuminant: `d65:` and 9 numbers, row by row.
+pub fn parse_matrix(value: &str) -> Result<(Illuminant, [f64; 9]), String> {
+    use clap::ValueEnum;
+    let (name, numbers) = value
+        .split_once(':')
+        .ok_or_else(|| format!("'{}' is not ILLUMINANT:9 numbers", value))?;
+    let illuminant = Illuminant::from_str(name.trim(), true)?;
+    Ok((illuminant, parse_numbers(numbers)?))
+}
+
+fn parse_numbers<const N: usize>(value: &str) -> Result<[f64; N], String> {
+    let numbers: Vec<f64> = value
+        .split(',')
+        .map(|v| v.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("'{}': {}", value, e))?;
+    numbers
+        .try_into()
+        .map_err(|v: Vec<f64>| format!("'{}': expected {} numbers, found {}", value, N, v.len()))
+}
+
+/// Read a defect list: one `column,row` per line. Blank lines and lines
+/// starting with `#` are skipped.
+pub fn read_defects(path: &Path) -> io::Result<Vec<[u32; 2]>> {
+    let mut defects = Vec::new();
+    for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let site = line
+            .split_once(',')
+            .and_then(|(x, y)| Some([x.trim().parse().ok()?, y.trim().parse().ok()?]))
+            .ok_or_else(|| {
+                invalid(format!(
+                    "{}: line {}: expected column,row",
+                    path.display(),
+                    number + 1
+                ))
+            })?;
+        defects.push(site);
+    }
+    Ok(defects)
+}
+
+/// An OpcodeList1 with a FixBadPixelsList for `defects`, on a sensor with
+/// the 2x2 CFA pattern `cfa`.
+pub fn fix_bad_pixels_opcode(defects: &[[u32; 2]], cfa: [u8; 4]) -> Vec<u8> {
+    // Where red sits in the 2x2 repeat: 0 top left, 1 top right, 2 bottom
+    // left, 3 bottom right.
+    let phase = cfa.iter().position(|&c| c == 0).unwrap_or(0) as u32;
+    let mut params = Vec::new();
+    for v in [phase, defects.len() as u32, 0] {
+        params.extend_from_slice(&v.to_be_bytes());
+    }
+    for [column, row] in defects {
+        params.extend_from_slice(&row.to_be_bytes());
+        params.extend_from_slice(&column.to_be_bytes());
+    }
+    iadng::opcode_list(&[(FIX_BAD_PIXELS_LIST_ID, params)])
+}
+
+/// Replace each defective site of a CFA `image` with the mean of its
+/// nearest sites of the same colour that aren't defective themselves.
+pub fn fix_defects(image: &mut [f64], width: usize, height: usize, defects: &[[u32; 2]]) {
+    let defective = |x: usize, y: usize| defects.contains(&[x as u32, y as u32]);
+    for &[x, y] in defects {
+        let (x, y) = (x as usize, y as usize);
+        if x >= width || y >= height {
+            continue;
+        }
+        let (mut sum, mut count) = (0.0, 0);
+        for (dx, dy) in [(-2, 0), (2, 0), (0, -2), (0, 2)] {
+            let (nx, ny) = (x as isize + dx, y as isize + dy);
+            if nx < 0 || ny < 0 || nx as usize >= width || ny as usize >= height {
+                continue;
+            }
+            let (nx, ny) = (nx as usize, ny as usize);
+            if !defective(nx, ny) {
+                sum += image[ny * width + nx];
+                count += 1;
+            }
+        }
+        if count > 0 {
+            image[y * width + x] = sum / count as f64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sinar_ia::{self, SinarIAMeta};
+
+    #[test]
+    fn test_backs() {
+        let dir = crate::test_dir("backs");
+        let mut db = BackDb::open(&dir).unwrap();
+        assert_eq!(db.iter().count(), 0);
+        let matrix = |scale: f64| -> [f64; 9] { std::array::from_fn(|i| scale * (i as f64 - 4.0)) };
+        let back = BackCalibration {
+            description: Some("Studio 2".to_string()),
+            white_level: Some(64000),
+            colour: vec![
+                ColourCalibration {
+                    illuminant: Illuminant::D65,
+                    color_matrix: matrix(0.1),
+                    forward_matrix: Some(matrix(0.2)),
+                },
+                ColourCalibration {
+                    illuminant: Illuminant::D50,
+                    color_matrix: matrix(0.3),
+                    forward_matrix: None,
+                },
+                ColourCalibration {
+                    illuminant: Illuminant::A,
+                    color_matrix: matrix(0.4),
+                    forward_matrix: None,
+                },
+            ],
+            noise: Some(NoiseModel {
+                shot: [0.5; 3],
+                read: [18.0; 3],
+            }),
+            defects: vec![[5, 3], [7, 3]],
+            ..BackCalibration::default()
+        };
+        assert!(db.save("../e75", back.clone()).is_err());
+        db.save("e75-0042", back.clone()).unwrap();
+        let reopened = BackDb::open(&dir).unwrap();
+        let saved = reopened.get("e75-0042").unwrap();
+        assert!(saved.updated.is_some());
+        assert_eq!(
+            **saved,
+            BackCalibration {
+                updated: saved.updated.clone(),
+                ..back.clone()
+            }
+        );
+        // The warmest and coolest illuminants go into the DNG.
+        let dng_colour: Vec<Illuminant> = saved.dng_colour().iter().map(|c| c.illuminant).collect();
+        assert_eq!(dng_colour, [Illuminant::A, Illuminant::D65]);
+        assert_eq!(
+            parse_matrix("D65: 1,0,0, 0,1,0, 0,0,1").unwrap(),
+            (
+                Illuminant::D65,
+                [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0]
+            )
+        );
+        assert!(parse_matrix("d65:1,2,3").is_err());
+
+        // Defects take the mean of their same-colour neighbours, leaving out
+        // other defects.
+        let (width, height) = (12, 8);
+        let mut image: Vec<f64> = (0..width * height).map(|i| (i % width) as f64).collect();
+        image[3 * width + 5] = 1000.0;
+        image[3 * width + 7] = 1000.0;
+        fix_defects(&mut image, width, height, &back.defects);
+        assert_eq!(image[3 * width + 5], (3.0 + 5.0 + 5.0) / 3.0);
+        assert_eq!(image[3 * width + 7], (9.0 + 7.0 + 7.0) / 3.0);
+        // Red sits at the top left of the E75's RGGB repeat.
+        let opcode = fix_bad_pixels_opcode(&back.defects, sinar_ia::E75_CFA);
+        let words: Vec<u32> = opcode
+            .chunks_exact(4)
+            .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+            .collect();
+        assert_eq!(words, [1, 5, 0x0103_0000, 1, 28, 0, 2, 0, 3, 5, 3, 7]);
+
+        let meta = SinarIAMeta {
+            width: 4,
+            height: 2,
+            back: Some(Arc::clone(saved)),
+            ..SinarIAMeta::default()
+        };
+        let iadng::TestDng { root, raw, .. } =
+            iadng::write_test_dng(&meta, &dir.join("e75-0042.dng"));
+        assert_eq!(root.get(50778).unwrap().as_u32s(), [17]);
+        assert_eq!(root.get(50779).unwrap().as_u32s(), [21]);
+        // Written as rationals over 10000.
+        let read_matrix = |tag: u16, expected: [f64; 9]| {
+            let read = root.get(tag).unwrap().as_f64s();
+            assert_eq!(read.len(), 9);
+            for (read, expected) in read.iter().zip(expected) {
+                assert!((read - expected).abs() < 1e-4);
+            }
+        };
+        read_matrix(50721, matrix(0.4));
+        read_matrix(50722, matrix(0.1));
+        assert!(root.get(50964).is_none());
+        read_matrix(50965, matrix(0.2));
+        assert_eq!(raw.get(51008).unwrap().data, opcode);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+/*
+Rebuilds IA files from DNGs written by iatodng, for round-trip checks.
+*/
+extern crate iatodng;
+
+use clap::Parser;
+use iatodng::restore;
+use log::error;
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// The DNG file, or directory of DNGs, to read
+    dng: PathBuf,
+    /// The directory to write IA files to
+    output_dir: PathBuf,
+    /// Extract the original IA (and BR/WR) files embedded with --embed-original instead of rebuilding
+    #[arg(long)]
+    extract: bool,
+    /// Log more detail
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+fn main() {
+    let args = Cli::parse();
+    let level = match args.verbose {
+        0 => "warn",
+        _ => "info",
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).init();
+
+    let mut files = if args.dng.is_file() {
+        vec![args.dng.clone()]
+    } else {
+        std::fs::read_dir(&args.dng)
+            .unwrap()
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                path.is_file()
+                    && path
+                        .extension()
+                        .is_some_and(|ext| ext.eq_ignore_ascii_case("dng"))
+            })
+            .collect()
+    };
+    files.sort();
+    let (mut restored, mut failed) = (0, 0);
+    for dng in &files {
+        let result = if args.extract {
+            restore::extract_originals(dng, &args.output_dir).map(|_| ())
+        } else {
+            restore::dng_to_ia(dng, &args.output_dir).map(|_| ())
+        };
+        match result {
+            Ok(()) => restored += 1,
+            Err(e) => {
+                error!("{}: {}", dng.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    println!("{} restored, {} failed", restored, failed);
+}
@@ -1,32 +1,762 @@
 extern crate iatodng;
-use clap::Parser;
-use std::path::PathBuf;
+use chrono::{Duration, FixedOffset};
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use iatodng::backs::{self, BackCalibration, BackDb, ColourCalibration, Illuminant};
+use iatodng::calibrate::{Calibration, Precision};
+use iatodng::capture::{self, TimeCorrection};
+use iatodng::cull::{self, Selection, ThumbFormat, ThumbOutcome};
+use iatodng::demosaic::Algorithm;
+use iatodng::export::OutputFormat;
+use iatodng::iadng::OnExist;
+use iatodng::lcc::{self, LccApply, LccOptions};
+use iatodng::lens::{self, LensDb, LensOptions};
+use iatodng::naming::{NameTemplate, OnCollision, DEFAULT_TEMPLATE};
+use iatodng::noise::NoiseModel;
+use iatodng::orientation::{self, Mount};
+use iatodng::original::Embed;
+use iatodng::plan::{self, PlannedFrame};
+use iatodng::profile::{self, Chart, ChartFrame, Profile};
+use iatodng::refcache::{self, RefCache};
+use iatodng::sheet::{self, Layout, SheetFormat};
+use iatodng::sinar_ia::{ConvertOptions, FrameOutcome};
+use iatodng::xmp::{Annotations, XmpOptions};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif_log_bridge::LogWrapper;
+use log::{debug, error, info, warn};
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
     /// The path to the file or directory to read
-    pub sinar_ai_dir: PathBuf,
+    #[arg(required = true)]
+    pub sinar_ai_dir: Option<PathBuf>,
     /// The directory to output DNGs to
-    pub output_dir: PathBuf,
+    #[arg(required = true)]
+    pub output_dir: Option<PathBuf>,
+    /// Output name template, relative to the output directory. Fields:
+    /// {serial} {model} {camera} {shutter_count} {date} {time} {ia_name} {iso} {f_stop} {wb}
+    #[arg(long, default_value = DEFAULT_TEMPLATE, value_parser = NameTemplate::parse)]
+    pub name: NameTemplate,
+    /// What to do when two frames render to the same output name
+    #[arg(long, value_enum, default_value_t = OnCollision::Suffix)]
+    pub on_collision: OnCollision,
+    /// What to do when the output file already exists
+    #[arg(long, value_enum, default_value_t = OnExist::Skip)]
+    pub on_exist: OnExist,
+    /// Output file format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Dng)]
+    pub format: OutputFormat,
+    /// Demosaic algorithm for demosaiced formats
+    #[arg(long, value_enum, default_value_t = Algorithm::Directional)]
+    pub demosaic: Algorithm,
+    /// References applied to the sensor data; none writes the untouched counts (DNG output only)
+    #[arg(long, value_enum, default_value_t = Calibration::DarkFlat)]
+    pub calibration: Calibration,
+    /// Sample type used for calibration; lower precision uses less memory
+    #[arg(long, value_enum, default_value_t = Precision::F64)]
+    pub precision: Precision,
+    /// UTC offset the back's clock was set to, e.g. +02:00 [default: this machine's zone]
+    #[arg(long, value_parser = capture::parse_timezone, allow_hyphen_values = true)]
+    pub timezone: Option<FixedOffset>,
+    /// Correction added to the back's clock, e.g. -1h30m or 2d
+    #[arg(long, value_parser = capture::parse_shift, allow_hyphen_values = true)]
+    pub time_shift: Option<Duration>,
+    /// How far the back was turned from a Hy6's upright mounting, clockwise seen from behind
+    #[arg(long, value_enum, default_value_t = Mount::Upright)]
+    pub mount: Mount,
+    /// Orientation of every frame, 1-8 or a name such as rotate90; overrides --mount
+    #[arg(long, value_parser = orientation::parse_orientation)]
+    pub orientation: Option<u16>,
+    /// Lens database: a YAML file mapping lens names to make, model, focal_length and max_aperture
+    #[arg(long)]
+    pub lens_db: Option<PathBuf>,
+    /// Lens from the lens database used for every frame
+    #[arg(long, requires = "lens_db")]
+    pub lens: Option<String>,
+    /// Aperture used for every frame, e.g. 22
+    #[arg(long, value_parser = lens::parse_f_stop)]
+    pub f_stop: Option<f32>,
+    /// CSV of lens and/or f_stop per shutter_count, overriding --lens and --f-stop
+    #[arg(long)]
+    pub lens_frames: Option<PathBuf>,
+    /// IA file shot through a diffuser (LCC frame) to correct the frames after it; repeat for more
+    #[arg(long = "lcc")]
+    pub lcc_frames: Vec<PathBuf>,
+    /// File listing LCC frames, one IA path per line relative to the list
+    #[arg(long)]
+    pub lcc_list: Option<PathBuf>,
+    /// How LCC corrections are applied: baked into the samples, or as DNG GainMap opcodes
+    #[arg(long, value_enum, default_value_t = LccApply::Bake)]
+    pub lcc_apply: LccApply,
+    /// Per-back calibration database: a directory of <serial>.yaml files
+    #[arg(long = "backs")]
+    pub backs_dir: Option<PathBuf>,
+    /// Source files to embed in each DNG, for recovery with dngtoia --extract
+    #[arg(long, value_enum, default_value_t = Embed::None)]
+    pub embed_original: Embed,
+    /// Client, written to the XMP packet
+    #[arg(long)]
+    pub client: Option<String>,
+    /// Job number, written to the XMP packet
+    #[arg(long)]
+    pub job: Option<String>,
+    /// Keyword for the XMP packet; repeat for more
+    #[arg(long = "keyword")]
+    pub keywords: Vec<String>,
+    /// Creator (photographer), written to the XMP packet
+    #[arg(long)]
+    pub creator: Option<String>,
+    /// Copyright notice, written to the XMP packet
+    #[arg(long)]
+    pub copyright: Option<String>,
+    /// Merge ratings, labels and keywords from .xmp sidecars next to the IA files
+    #[arg(long)]
+    pub xmp_sidecar: bool,
+    /// Stream frames in row bands to keep memory low (DNG output only)
+    #[arg(long)]
+    pub stream: bool,
+    /// Keep decoded BR/WR references across runs in this directory
+    #[arg(long)]
+    pub cache_dir: Option<PathBuf>,
+    /// Memory, in MiB, for decoded BR/WR references shared between frames
+    #[arg(long, default_value_t = refcache::DEFAULT_LIMIT / (1024 * 1024))]
+    pub cache_mem: usize,
+    /// Scan the input and print what would be converted, without writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Write only each frame's thumbnail, named like its output, for culling before conversion
+    #[arg(long, value_enum)]
+    pub thumbnails: Option<ThumbFormat>,
+    /// Convert only the frames named in this list, or left in this folder of culled thumbnails
+    #[arg(long)]
+    pub select: Option<PathBuf>,
+    /// Log more detail (-v for per-frame progress, -vv for calibration stats)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Manage the per-back calibration database
+    Backs {
+        #[command(subcommand)]
+        action: BacksAction,
+    },
+    /// Fit a back's colour matrices to a frame of an X-Rite ColorChecker
+    Profile {
+        /// IA file, or CFA DNG written by iatodng, of a ColorChecker Classic
+        frame: PathBuf,
+        /// Light the chart was shot under
+        #[arg(long, value_enum)]
+        illuminant: Illuminant,
+        /// Centres of the dark skin, bluish green, white and black patches in sensor sites, e.g. "812,604 3310,598 820,2260 3305,2250" [default: detected]
+        #[arg(long, value_parser = Chart::parse)]
+        chart: Option<Chart>,
+        #[command(flatten)]
+        db: BacksDir,
+        /// Report the fit without storing it
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Lay out every frame's thumbnail with its exposure on a contact sheet
+    ContactSheet {
+        /// The IA file or directory of IA files
+        dir: PathBuf,
+        /// Sheet to write: .jpg, one file per page, or .pdf
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Thumbnails across a page
+        #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..=20))]
+        columns: u32,
+        /// Thumbnails down a page
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..=20))]
+        rows: u32,
+        /// How far the back was turned from a Hy6's upright mounting, clockwise seen from behind
+        #[arg(long, value_enum, default_value_t = Mount::Upright)]
+        mount: Mount,
+        /// Orientation of every frame, 1-8 or a name such as rotate90; overrides --mount
+        #[arg(long, value_parser = orientation::parse_orientation)]
+        orientation: Option<u16>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum BacksAction {
+    /// List the backs in the database
+    List {
+        #[command(flatten)]
+        db: BacksDir,
+    },
+    /// Print a back's entry
+    Show {
+        serial: String,
+        #[command(flatten)]
+        db: BacksDir,
+    },
+    /// Add a back to the database
+    Create {
+        serial: String,
+        #[command(flatten)]
+        db: BacksDir,
+        #[command(flatten)]
+        fields: BackFields,
+    },
+    /// Change fields of a back's entry
+    Update {
+        serial: String,
+        #[command(flatten)]
+        db: BacksDir,
+        #[command(flatten)]
+        fields: BackFields,
+    },
+}
+
+#[derive(Args)]
+pub struct BacksDir {
+    /// Directory of the calibration database
+    #[arg(long = "backs")]
+    pub dir: PathBuf,
+}
+
+#[derive(Args)]
+pub struct BackFields {
+    /// Free text, e.g. where the back lives
+    #[arg(long)]
+    pub description: Option<String>,
+    /// Dark level of each position in the 2x2 CFA repeat, e.g. 1024,1020,1021,1025
+    #[arg(long, value_parser = backs::parse_black_level)]
+    pub black_level: Option<[f64; 4]>,
+    /// Counts from which the sensor clips
+    #[arg(long)]
+    pub white_level: Option<u16>,
+    /// XYZ to camera matrix for an illuminant, e.g. d65:1.02,-0.29,...; repeat for more
+    #[arg(long = "color-matrix", value_parser = backs::parse_matrix)]
+    pub color_matrices: Vec<(Illuminant, [f64; 9])>,
+    /// Camera to XYZ D50 matrix for an illuminant with a color matrix; repeat for more
+    #[arg(long = "forward-matrix", value_parser = backs::parse_matrix)]
+    pub forward_matrices: Vec<(Illuminant, [f64; 9])>,
+    /// Noise in sensor counts: shot,read for red, then green, then blue
+    #[arg(long, value_parser = backs::parse_noise)]
+    pub noise: Option<NoiseModel>,
+    /// File of defective sites, one column,row per line; replaces the list
+    #[arg(long)]
+    pub defects: Option<PathBuf>,
+    /// DNG camera profile (.dcp) to embed in the back's DNGs, copied into the database
+    #[arg(long)]
+    pub dcp: Option<PathBuf>,
+}
+
+impl BackFields {
+    /// Set the fields given on the command line in `back`.
+    fn apply(&self, back: &mut BackCalibration) -> Result<(), String> {
+        if let Some(description) = &self.description {
+            back.description = Some(description.clone());
+        }
+        back.black_level = self.black_level.or(back.black_level);
+        back.white_level = self.white_level.or(back.white_level);
+        back.noise = self.noise.or(back.noise);
+        for &(illuminant, color_matrix) in &self.color_matrices {
+            match back.colour.iter_mut().find(|c| c.illuminant == illuminant) {
+                Some(colour) => colour.color_matrix = color_matrix,
+                None => back.colour.push(ColourCalibration {
+                    illuminant,
+                    color_matrix,
+                    forward_matrix: None,
+                }),
+            }
+        }
+        for &(illuminant, forward_matrix) in &self.forward_matrices {
+            let colour = back
+                .colour
+                .iter_mut()
+                .find(|c| c.illuminant == illuminant)
+                .ok_or_else(|| format!("no color matrix for {:?}", illuminant))?;
+            colour.forward_matrix = Some(forward_matrix);
+        }
+        if let Some(path) = &self.defects {
+            back.defects =
+                backs::read_defects(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+
+    /// Copy the camera profile given on the command line into `db`.
+    fn save_profile(&self, db: &mut BackDb, serial: &str) -> Result<(), String> {
+        match &self.dcp {
+            Some(path) => db
+                .save_profile(serial, path)
+                .map_err(|e| format!("{}: {}", path.display(), e)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn backs_command(action: &BacksAction) -> Result<(), String> {
+    let (BacksAction::List { db }
+    | BacksAction::Show { db, .. }
+    | BacksAction::Create { db, .. }
+    | BacksAction::Update { db, .. }) = action;
+    let mut db = BackDb::open(&db.dir).map_err(|e| e.to_string())?;
+    match action {
+        BacksAction::List { .. } => {
+            for (serial, back) in db.iter() {
+                let mut summary = back.summary();
+                if let Some(profile) = db.profile(serial) {
+                    let name = profile.name.as_deref().unwrap_or("unnamed");
+                    summary = match summary.is_empty() {
+                        true => format!("profile '{}'", name),
+                        false => format!("{}, profile '{}'", summary, name),
+                    };
+                }
+                println!("{}: {}", serial, summary);
+            }
+        }
+        BacksAction::Show { serial, .. } => {
+            let back = db
+                .get(serial)
+                .ok_or_else(|| format!("no back '{}' in the database", serial))?;
+            print!(
+                "{}",
+                serde_yaml::to_string(&**back).map_err(|e| e.to_string())?
+            );
+            if let Some(profile) = db.profile(serial) {
+                println!(
+                    "# Camera profile {}for '{}'",
+                    profile
+                        .name
+                        .as_ref()
+                        .map(|name| format!("'{}' ", name))
+                        .unwrap_or_default(),
+                    profile.unique_camera_model
+                );
+            }
+        }
+        BacksAction::Create { serial, fields, .. } => {
+            if db.get(serial).is_some() {
+                return Err(format!("back '{}' is already in the database", serial));
+            }
+            let mut back = BackCalibration::default();
+            fields.apply(&mut back)?;
+            db.save(serial, back).map_err(|e| e.to_string())?;
+            fields.save_profile(&mut db, serial)?;
+        }
+        BacksAction::Update { serial, fields, .. } => {
+            let mut back = db
+                .get(serial)
+                .map(|back| (**back).clone())
+                .ok_or_else(|| format!("no back '{}' in the database", serial))?;
+            fields.apply(&mut back)?;
+            db.save(serial, back).map_err(|e| e.to_string())?;
+            fields.save_profile(&mut db, serial)?;
+        }
+    }
+    Ok(())
+}
+
+fn contact_sheet_command(
+    dir: &Path,
+    output: &Path,
+    layout: Layout,
+    orientation: u16,
+) -> Result<(), String> {
+    SheetFormat::of(output)?;
+    let start = Instant::now();
+    let files = plan::find_ia_files(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let written = sheet::write_sheets(&files, output, layout, orientation)
+        .map_err(|e| format!("{}: {}", output.display(), e))?;
+    for path in &written {
+        println!("Wrote {}", path.display());
+    }
+    info!(
+        "{} frames on {} in {:.1?}",
+        files.len(),
+        output.display(),
+        start.elapsed()
+    );
+    Ok(())
+}
+
+fn profile_command(
+    frame: &Path,
+    illuminant: Illuminant,
+    chart: Option<Chart>,
+    dir: &Path,
+    dry_run: bool,
+) -> Result<(), String> {
+    let cache = RefCache::new(refcache::DEFAULT_LIMIT, None);
+    let ChartFrame { meta, image } =
+        profile::read_frame(frame, &cache).map_err(|e| format!("{}: {}", frame.display(), e))?;
+    let (width, height, cfa) = (
+        meta.width as usize,
+        meta.height as usize,
+        meta.cfa_pattern(),
+    );
+    let chart = match chart {
+        Some(chart) => chart,
+        None => {
+            let chart = profile::detect(&image, width, height, cfa)
+                .ok_or("no ColorChecker found, give its corners with --chart")?;
+            let points: Vec<String> = chart
+                .corners
+                .iter()
+                .map(|[x, y]| format!("{:.0},{:.0}", x, y))
+                .collect();
+            println!("Chart found at {}", points.join(" "));
+            chart
+        }
+    };
+    let patches = chart.sample(&image, width, height, cfa)?;
+    let fitted = Profile::fit(&patches, illuminant)?;
+    for (name, delta_e) in profile::PATCH_NAMES.iter().zip(fitted.delta_e) {
+        info!("{:>14}: ΔE00 {:.2}", name, delta_e);
+    }
+    println!(
+        "{:?} fit for {}: mean ΔE00 {:.2}, max {:.2}",
+        illuminant,
+        meta.serial,
+        fitted.mean_delta_e(),
+        fitted.max_delta_e()
+    );
+    if dry_run {
+        return Ok(());
+    }
+    let mut db = BackDb::open(dir).map_err(|e| e.to_string())?;
+    let mut back = db
+        .get(&meta.serial)
+        .map(|back| (**back).clone())
+        .unwrap_or_default();
+    back.set_colour(fitted.calibration());
+    db.save(&meta.serial, back).map_err(|e| e.to_string())?;
+    println!("Stored in {}", dir.display());
+    Ok(())
+}
+
+/// A progress bar over `len` frames.
+fn frame_bar(progress: &MultiProgress, len: usize) -> ProgressBar {
+    let bar = progress.add(ProgressBar::new(len as u64));
+    bar.set_style(
+        ProgressStyle::with_template(
+            "{spinner} [{elapsed_precise}] {bar:40} {pos}/{len} frames, ETA {eta} {msg}",
+        )
+        .unwrap(),
+    );
+    bar
+}
+
+/// Write the thumbnail of every planned frame next to where its output
+/// would go, in parallel.
+fn write_thumbnails(
+    frames: &[PlannedFrame],
+    output_dir: &Path,
+    format: ThumbFormat,
+    options: &ConvertOptions,
+    progress: &MultiProgress,
+) {
+    let bar = frame_bar(
+        progress,
+        frames.iter().filter(|f| f.output.is_some()).count(),
+    );
+    let start = Instant::now();
+    let outcomes: Vec<ThumbOutcome> = frames
+        .par_iter()
+        .map(|frame| {
+            for problem in &frame.problems {
+                warn!("{}: {}", frame.ia.display(), problem);
+            }
+            let name = match &frame.output {
+                Some(name) => name,
+                None => return ThumbOutcome::Failed("no output name".to_string()),
+            };
+            let path = output_dir.join(name).with_extension(format.extension());
+            let outcome = cull::write_thumbnail(
+                &frame.ia,
+                &path,
+                format,
+                options.orientation,
+                options.on_exist,
+            );
+            if let ThumbOutcome::Failed(e) = &outcome {
+                error!("{}: {}", frame.ia.display(), e);
+            }
+            bar.inc(1);
+            outcome
+        })
+        .collect();
+    bar.finish_and_clear();
+    let count = |wanted: fn(&ThumbOutcome) -> bool| outcomes.iter().filter(|o| wanted(o)).count();
+    println!(
+        "{} thumbnails written, {} skipped, {} failed in {:.1?}",
+        count(|o| *o == ThumbOutcome::Written),
+        count(|o| *o == ThumbOutcome::Skipped),
+        count(|o| matches!(o, ThumbOutcome::Failed(_))),
+        start.elapsed()
+    );
+}
+
+/// The lens overrides asked for, checked against the lens database.
+fn lens_options(args: &Cli) -> Result<LensOptions, String> {
+    let db = match &args.lens_db {
+        Some(path) => LensDb::read(path).map_err(|e| e.to_string())?,
+        None => LensDb::default(),
+    };
+    let mut options = LensOptions::default();
+    if let Some(name) = &args.lens {
+        options.session.lens = Some(db.get(name)?.clone());
+    }
+    options.session.f_stop = args.f_stop;
+    if let Some(path) = &args.lens_frames {
+        options.frames = lens::read_frames(path, &db).map_err(|e| e.to_string())?;
+    }
+    Ok(options)
+}
+
+/// The LCC frames marked with --lcc and --lcc-list.
+fn lcc_options(args: &Cli) -> Result<LccOptions, String> {
+    let mut paths = args.lcc_frames.clone();
+    if let Some(list) = &args.lcc_list {
+        paths.extend(lcc::read_list(list).map_err(|e| format!("{}: {}", list.display(), e))?);
+    }
+    LccOptions::mark(&paths, args.lcc_apply).map_err(|e| e.to_string())
 }
 
 fn main() {
     let args = Cli::parse();
+    let level = match args.verbose {
+        0 => "warn",
+        1 => "info",
+        _ => "debug",
+    };
+    let logger =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).build();
+    let progress = MultiProgress::new();
+    LogWrapper::new(progress.clone(), logger)
+        .try_init()
+        .unwrap();
+
+    if let Some(command) = &args.command {
+        let result = match command {
+            Command::Backs { action } => backs_command(action),
+            Command::Profile {
+                frame,
+                illuminant,
+                chart,
+                db,
+                dry_run,
+            } => profile_command(frame, *illuminant, *chart, &db.dir, *dry_run),
+            Command::ContactSheet {
+                dir,
+                output,
+                columns,
+                rows,
+                mount,
+                orientation,
+            } => contact_sheet_command(
+                dir,
+                output,
+                Layout {
+                    columns: *columns as usize,
+                    rows: *rows as usize,
+                },
+                orientation::for_session(*orientation, *mount),
+            ),
+        };
+        if let Err(e) = result {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let sinar_ai_dir = args.sinar_ai_dir.clone().unwrap();
+    let output_dir = args.output_dir.clone().unwrap();
+
+    if args.stream && args.format != OutputFormat::Dng {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--stream only supports --format dng",
+            )
+            .exit();
+    }
+
+    if args.calibration == Calibration::None && args.format != OutputFormat::Dng {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--calibration none only supports --format dng",
+            )
+            .exit();
+    }
+
+    if args.embed_original != Embed::None && args.format.extension() != "dng" {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--embed-original needs DNG output",
+            )
+            .exit();
+    }
+
+    let lcc = match lcc_options(&args) {
+        Ok(lcc) => lcc,
+        Err(e) => Cli::command()
+            .error(clap::error::ErrorKind::ValueValidation, e)
+            .exit(),
+    };
+    if !lcc.frames.is_empty() {
+        if lcc.apply == LccApply::GainMap && args.format != OutputFormat::Dng {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "--lcc-apply gainmap only supports --format dng",
+                )
+                .exit();
+        }
+        if lcc.apply == LccApply::Bake && args.calibration == Calibration::None {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "--calibration none needs --lcc-apply gainmap",
+                )
+                .exit();
+        }
+    }
+
+    let lenses = match lens_options(&args) {
+        Ok(lenses) => lenses,
+        Err(e) => Cli::command()
+            .error(clap::error::ErrorKind::ValueValidation, e)
+            .exit(),
+    };
+
+    let backs = match &args.backs_dir {
+        Some(dir) if !dir.is_dir() => Cli::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                format!("--backs: {} is not a directory", dir.display()),
+            )
+            .exit(),
+        Some(dir) => match BackDb::open(dir) {
+            Ok(backs) => backs,
+            Err(e) => Cli::command()
+                .error(clap::error::ErrorKind::ValueValidation, e)
+                .exit(),
+        },
+        None => BackDb::default(),
+    };
+
+    let selection = args
+        .select
+        .as_ref()
+        .map(|path| match Selection::read(path) {
+            Ok(selection) => selection,
+            Err(e) => Cli::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!("--select: {}: {}", path.display(), e),
+                )
+                .exit(),
+        });
+
+    let files = plan::find_ia_files(&sinar_ai_dir).unwrap();
+    let options = ConvertOptions {
+        on_exist: args.on_exist,
+        format: args.format,
+        demosaic: args.demosaic,
+        calibration: args.calibration,
+        precision: args.precision,
+        embed: args.embed_original,
+        time: TimeCorrection {
+            timezone: args.timezone,
+            shift: args.time_shift.unwrap_or_else(Duration::zero),
+        },
+        orientation: orientation::for_session(args.orientation, args.mount),
+        lens: lenses,
+        xmp: XmpOptions {
+            annotations: Annotations {
+                client: args.client.clone(),
+                job: args.job.clone(),
+                keywords: args.keywords.clone(),
+                creator: args.creator.clone(),
+                copyright: args.copyright.clone(),
+                ..Annotations::default()
+            },
+            sidecar: args.xmp_sidecar,
+        },
+        lcc,
+        backs,
+        stream: args.stream,
+    };
+    let mut frames = plan::plan_batch(&files, &output_dir, &args.name, args.on_collision, &options);
+    if let Some(selection) = &selection {
+        let outputs: Vec<&Path> = frames.iter().filter_map(|f| f.output.as_deref()).collect();
+        for entry in selection.unmatched(&outputs) {
+            warn!("--select: {} matches no frame", entry);
+        }
+        let planned = frames.len();
+        frames.retain(|frame| {
+            frame
+                .output
+                .as_deref()
+                .is_some_and(|output| selection.contains(output))
+        });
+        info!("{} of {} frames selected", frames.len(), planned);
+    }
+    if args.dry_run {
+        plan::print_plan(&frames);
+        return;
+    }
+    if let Some(format) = args.thumbnails {
+        write_thumbnails(&frames, &output_dir, format, &options, &progress);
+        return;
+    }
     // make output directory if it doesn't exist
-    if !args.output_dir.exists() {
-        std::fs::create_dir(&args.output_dir).unwrap();
-    }
-    for entry in std::fs::read_dir(&args.sinar_ai_dir).unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        if path.is_file() {
-            if let Some(ext) = path.extension() {
-                if ext == "IA" {
-                    iatodng::sinar_ia::process_ia(&path, &args.output_dir);
-                }
+    if !output_dir.exists() {
+        std::fs::create_dir(&output_dir).unwrap();
+    }
+    let cache = RefCache::new(args.cache_mem * 1024 * 1024, args.cache_dir.clone());
+    let bar = frame_bar(
+        &progress,
+        frames.iter().filter(|f| f.output.is_some()).count(),
+    );
+    let start = Instant::now();
+    let (mut converted, mut skipped, mut failed) = (0, 0, 0);
+    for frame in &frames {
+        for problem in &frame.problems {
+            warn!("{}: {}", frame.ia.display(), problem);
+        }
+        let name = match &frame.output {
+            Some(name) => name,
+            None => {
+                failed += 1;
+                continue;
             }
+        };
+        bar.set_message(frame.ia.display().to_string());
+        match iatodng::sinar_ia::process_ia(&frame.ia, &output_dir.join(name), &options, &cache) {
+            FrameOutcome::Converted(_) => converted += 1,
+            FrameOutcome::Skipped => skipped += 1,
+            FrameOutcome::Failed(_) => failed += 1,
         }
+        bar.inc(1);
     }
+    bar.finish_and_clear();
+    let stats = cache.stats();
+    debug!(
+        "Calibration cache: {} hits, {} from disk, {} decoded",
+        stats.hits, stats.sidecar_hits, stats.misses
+    );
+    println!(
+        "{} converted, {} skipped, {} failed in {:.1?}",
+        converted,
+        skipped,
+        failed,
+        start.elapsed()
+    );
 }
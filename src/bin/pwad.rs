@@ -16,13 +16,13 @@ fn main() {
     //Parse CLI args
     let args = Cli::parse();
     //Open file
-    let pwad = iatodng::pwad::Pwad::from_file(&args.file.to_str().unwrap()).unwrap();
+    let pwad = iatodng::pwad::Pwad::from_file(&args.file).unwrap();
     //Print pwad struct data
     println!("{:?}", pwad);
     //Read meta lump
     let meta = pwad.read_lump_by_tag(iatodng::sinar_ia::META_KEY).unwrap();
     //Print meta lump
-    let metadata = iatodng::sinar_ia::SinarIAMeta::process_meta(&meta);
+    let metadata = iatodng::sinar_ia::SinarIAMeta::process_meta(&meta).unwrap();
     println!("{:?}", &metadata);
     let parent_dir = &args.file.parent().unwrap();
     println!(
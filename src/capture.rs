@@ -0,0 +1,260 @@
+/*
+Capture time of a frame.
+
+The capture time is the IA file's modification time, which the back sets
+when it writes the frame; META is not known to hold one.
+Card readers report it in the converting machine's zone, which is taken to
+be the zone the back's clock was set to unless a timezone is given. Copies
+that don't keep modification times lose it; if the EMO folder is named by
+date (`YYYYMMDD.EMO`) and the modification time falls on a different day,
+the date is corrected to the folder's and the time of day kept, as a copy
+usually keeps at least that much; with no modification time at all the
+frame is taken at midnight.
+*/
+
+use std::fs;
+use std::path::Path;
+
+use chrono::{
+    DateTime, Duration, FixedOffset, Local, NaiveDate, NaiveDateTime, NaiveTime, Offset, TimeZone,
+    Timelike,
+};
+use log::warn;
+
+/// Where a capture time came from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeSource {
+    /// The IA file's modification time
+    Modified,
+    /// The date in the EMO folder's name, at the modification time's time
+    /// of day, or midnight without one
+    Folder,
+}
+
+/// Corrections for a back's clock.
+#[derive(Debug, Clone, Copy)]
+pub struct TimeCorrection {
+    /// Zone the back's clock was set to; the converting machine's if `None`.
+    pub timezone: Option<FixedOffset>,
+    /// Added to the back's clock.
+    pub shift: Duration,
+}
+
+impl Default for TimeCorrection {
+    fn default() -> Self {
+        TimeCorrection {
+            timezone: None,
+            shift: Duration::zero(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CaptureTime {
+    /// The back's wall-clock time, corrected.
+    pub local: NaiveDateTime,
+    pub offset: FixedOffset,
+    pub source: TimeSource,
+}
+
+impl CaptureTime {
+    /// Capture time of the IA file at `ia`, or `None` if neither its
+    /// modification time nor its folder name give one.
+    pub fn of_ia(ia: &Path, correction: &TimeCorrection) -> Option<Self> {
+        let modified = fs::metadata(ia)
+            .and_then(|m| m.modified())
+            .ok()
+            .map(|t| DateTime::<Local>::from(t).naive_local());
+        let folder = ia.parent().and_then(folder_date);
+        let (local, source) = match (modified, folder) {
+            (Some(modified), Some(date)) if modified.date() != date => {
+                warn!(
+                    "{}: modified on {}, not the folder's date {}; using the folder's date",
+                    ia.display(),
+                    modified.date(),
+                    date
+                );
+                let time = modified.time().with_nanosecond(0)?;
+                (date.and_time(time), TimeSource::Folder)
+            }
+            (Some(modified), _) => (modified, TimeSource::Modified),
+            (None, Some(date)) => (date.and_time(NaiveTime::MIN), TimeSource::Folder),
+            (None, None) => return None,
+        };
+        let local = local + correction.shift;
+        let offset = correction.timezone.unwrap_or_else(|| local_offset(&local));
+        Some(CaptureTime {
+            local,
+            offset,
+            source,
+        })
+    }
+
+    /// EXIF date and time, `YYYY:MM:DD HH:MM:SS`.
+    pub fn exif_datetime(&self) -> String {
+        self.local.format("%Y:%m:%d %H:%M:%S").to_string()
+    }
+
+    /// EXIF subsecond field, in milliseconds. Only modification times on
+    /// the folder's date have subseconds.
+    pub fn exif_subsec(&self) -> Option<String> {
+        match self.source {
+            TimeSource::Modified => Some(format!("{:03}", self.local.nanosecond() / 1_000_000)),
+            TimeSource::Folder => None,
+        }
+    }
+
+    /// EXIF offset field, `+HH:MM`.
+    pub fn exif_offset(&self) -> String {
+        self.offset.to_string()
+    }
+
+    /// XMP date, ISO 8601 with the offset.
+    pub fn xmp_datetime(&self) -> String {
+        let format = match self.source {
+            TimeSource::Modified => "%Y-%m-%dT%H:%M:%S%.3f",
+            TimeSource::Folder => "%Y-%m-%dT%H:%M:%S",
+        };
+        format!("{}{}", self.local.format(format), self.offset)
+    }
+}
+
+// The converting machine's offset at `local`, or its current one if
+// `local` falls in a DST gap.
+fn local_offset(local: &NaiveDateTime) -> FixedOffset {
+    Local
+        .offset_from_local_datetime(local)
+        .earliest()
+        .map(|o| o.fix())
+        .unwrap_or_else(|| Local::now().offset().fix())
+}
+
+// The date in a `YYYYMMDD` or `YYYY-MM-DD` folder name, extension ignored.
+fn folder_date(folder: &Path) -> Option<NaiveDate> {
+    let stem = folder.file_stem()?.to_str()?;
+    ["%Y%m%d", "%Y-%m-%d"]
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(stem, format).ok())
+}
+
+/// Parse a UTC offset: `UTC`, `Z`, `+HH`, `+HHMM` or `+HH:MM`.
+pub fn parse_timezone(value: &str) -> Result<FixedOffset, String> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("utc") || value == "Z" {
+        return Ok(FixedOffset::east_opt(0).unwrap());
+    }
+    let error = || format!("'{}' is not a UTC offset like +02:00", value);
+    let (sign, digits) = match value.chars().next() {
+        Some('+') => (1, &value[1..]),
+        Some('-') => (-1, &value[1..]),
+        _ => return Err(error()),
+    };
+    let digits = digits.replace(':', "");
+    if !matches!(digits.len(), 2 | 4) || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(error());
+    }
+    let hours: i32 = digits[..2].parse().map_err(|_| error())?;
+    let minutes: i32 = if digits.len() == 4 {
+        digits[2..].parse().map_err(|_| error())?
+    } else {
+        0
+    };
+    if minutes >= 60 {
+        return Err(error());
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).ok_or_else(error)
+}
+
+/// Parse a clock correction such as `+1h30m`, `-45s` or `2d`.
+pub fn parse_shift(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let error = || format!("'{}' is not a time shift like -1h30m", value);
+    let (sign, mut rest) = match value.chars().next() {
+        Some('+') => (1, &value[1..]),
+        Some('-') => (-1, &value[1..]),
+        _ => (1, value),
+    };
+    if rest.is_empty() {
+        return Err(error());
+    }
+    let mut seconds: i64 = 0;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).ok_or_else(error)?;
+        let count: i64 = rest[..digits].parse().map_err(|_| error())?;
+        let unit = match rest[digits..].chars().next() {
+            Some('d') => 86_400,
+            Some('h') => 3_600,
+            Some('m') => 60,
+            Some('s') => 1,
+            _ => return Err(error()),
+        };
+        seconds = count
+            .checked_mul(unit)
+            .and_then(|s| seconds.checked_add(s))
+            .ok_or_else(error)?;
+        rest = &rest[digits + 1..];
+    }
+    // Anything longer is a typo, and would overflow the date.
+    if seconds > 100 * 366 * 86_400 {
+        return Err(error());
+    }
+    Ok(Duration::seconds(sign * seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_time() {
+        use chrono::{Duration, Local, NaiveDate, TimeZone};
+        use std::time::SystemTime;
+
+        assert_eq!(parse_timezone("+02:00").unwrap().local_minus_utc(), 7200);
+        assert_eq!(parse_timezone("-0530").unwrap().local_minus_utc(), -19800);
+        assert_eq!(parse_timezone("UTC").unwrap().local_minus_utc(), 0);
+        assert!(parse_timezone("2").is_err());
+        assert!(parse_timezone("+02:75").is_err());
+        assert_eq!(parse_shift("-1h30m").unwrap(), Duration::seconds(-5400));
+        assert_eq!(parse_shift("2d").unwrap(), Duration::days(2));
+        assert!(parse_shift("1x").is_err());
+        assert!(parse_shift("-").is_err());
+
+        let dir = crate::test_dir("capture");
+        let folder = dir.join("20080612.EMO");
+        std::fs::create_dir_all(&folder).unwrap();
+        let ia = folder.join("6C486AFC.IA");
+        std::fs::write(&ia, b"").unwrap();
+        // Taken on the folder's date in the converting machine's zone.
+        let taken = NaiveDate::from_ymd_opt(2008, 6, 12)
+            .unwrap()
+            .and_hms_milli_opt(11, 30, 45, 250)
+            .unwrap();
+        let file = std::fs::File::options().write(true).open(&ia).unwrap();
+        let modified = Local.from_local_datetime(&taken).earliest().unwrap();
+        file.set_modified(SystemTime::from(modified)).unwrap();
+
+        let correction = TimeCorrection {
+            timezone: Some(parse_timezone("+02:00").unwrap()),
+            shift: Duration::hours(1),
+        };
+        let captured = CaptureTime::of_ia(&ia, &correction).unwrap();
+        assert_eq!(captured.source, TimeSource::Modified);
+        assert_eq!(captured.local, taken + Duration::hours(1));
+        assert_eq!(captured.exif_subsec().as_deref(), Some("250"));
+        assert_eq!(captured.exif_offset(), "+02:00");
+
+        // A modification time from a later copy keeps its time of day on the folder's date.
+        let copied = NaiveDate::from_ymd_opt(2024, 3, 5)
+            .unwrap()
+            .and_hms_milli_opt(14, 37, 21, 500)
+            .unwrap();
+        let copied = Local.from_local_datetime(&copied).earliest().unwrap();
+        file.set_modified(SystemTime::from(copied)).unwrap();
+        let captured = CaptureTime::of_ia(&ia, &TimeCorrection::default()).unwrap();
+        assert_eq!(captured.source, TimeSource::Folder);
+        assert_eq!(captured.exif_datetime(), "2008:06:12 14:37:21");
+        assert_eq!(captured.exif_subsec(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
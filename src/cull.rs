@@ -0,0 +1,295 @@
+/*
+Culling before conversion: thumbnails out, a selection back in.
+
+`write_thumbnail` writes a frame's THUMB lump, turned to the session's
+orientation, as a JPEG or PNG. Only META and THUMB are read, so a card's
+worth takes seconds. Each thumbnail is named like the output the frame
+will be converted to, with the image format's extension, so the culled
+set maps straight back onto the frames.
+
+A `Selection` reads the cull back in, either as a list of names, one per
+line, or as the folder of thumbnails left once the rejects are deleted.
+An entry picks out the frame whose output has the same name, image or DNG
+extension aside (a dot in a name such as `1201_f5.6` is kept):
+
+* `CF0042/1201.jpg` matches the output `CF0042/1201.dng`.
+* A path that ends in an output's name matches it too, so lists of
+  absolute paths or paths under the thumbnail folder work.
+* A bare file name such as `1201` matches that name in any folder.
+*/
+
+use std::fs;
+use std::io;
+use std::path::{Component, Path};
+
+use crate::export;
+use crate::iadng::{self, OnExist};
+use crate::pwad::Pwad;
+use crate::sheet::{self, Thumbnail};
+
+#[derive(Debug, PartialEq, Clone, Copy, clap::ValueEnum)]
+pub enum ThumbFormat {
+    /// Baseline JPEG
+    Jpeg,
+    /// 8-bit PNG
+    Png,
+}
+
+impl ThumbFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ThumbFormat::Jpeg => "jpg",
+            ThumbFormat::Png => "png",
+        }
+    }
+}
+
+/// What became of a frame's thumbnail.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ThumbOutcome {
+    Written,
+    Skipped,
+    Failed(String),
+}
+
+/// Write the THUMB lump of `ia`, turned to a TIFF/EXIF `orientation`, to
+/// `path`. Thumbnails are written atomically, so one already there is
+/// complete: it is kept unless `on_exist` is Overwrite or Rename, which
+/// replace it, as a renamed thumbnail would no longer match its frame.
+pub fn write_thumbnail(
+    ia: &Path,
+    path: &Path,
+    format: ThumbFormat,
+    orientation: u16,
+    on_exist: OnExist,
+) -> ThumbOutcome {
+    if path.exists() && matches!(on_exist, OnExist::Skip | OnExist::Verify) {
+        return ThumbOutcome::Skipped;
+    }
+    let write = || -> io::Result<()> {
+        let pwad = Pwad::from_file(ia)?;
+        let thumbnail = Thumbnail::read(&pwad, orientation)?;
+        iadng::write_atomically(path, |tmp| match format {
+            ThumbFormat::Jpeg => {
+                sheet::write_jpeg(&thumbnail.rgb, thumbnail.width, thumbnail.height, tmp)
+            }
+            ThumbFormat::Png => export::write_png(
+                &thumbnail.rgb,
+                png::BitDepth::Eight,
+                thumbnail.width,
+                thumbnail.height,
+                tmp,
+            ),
+        })
+    };
+    match write() {
+        Ok(()) => ThumbOutcome::Written,
+        Err(e) => ThumbOutcome::Failed(e.to_string()),
+    }
+}
+
+/// The frames kept in a cull.
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    entries: Vec<String>,
+}
+
+// Extensions of outputs and thumbnails, dropped from names before matching.
+const EXTENSIONS: [&str; 6] = ["dng", "tif", "tiff", "png", "jpg", "jpeg"];
+
+// A path without an image extension, folders joined with `/`. Other
+// extensions are part of the name, as in `1201_f5.6`.
+fn key(path: &Path) -> String {
+    let known = path
+        .extension()
+        .is_some_and(|e| EXTENSIONS.contains(&e.to_string_lossy().to_lowercase().as_str()));
+    let path = if known {
+        path.with_extension("")
+    } else {
+        path.to_path_buf()
+    };
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+impl Selection {
+    /// Read a selection from `path`: a folder of thumbnails, searched
+    /// recursively, or a list of names. Blank lines, lines starting with
+    /// `#` and hidden files are skipped.
+    pub fn read(path: &Path) -> io::Result<Selection> {
+        let mut entries = Vec::new();
+        if path.is_dir() {
+            let mut folders = vec![path.to_path_buf()];
+            while let Some(folder) = folders.pop() {
+                for entry in fs::read_dir(&folder)? {
+                    let found = entry?.path();
+                    if found
+                        .file_name()
+                        .is_none_or(|name| name.to_string_lossy().starts_with('.'))
+                    {
+                        continue;
+                    }
+                    if found.is_dir() {
+                        folders.push(found);
+                    } else if let Ok(relative) = found.strip_prefix(path) {
+                        entries.push(key(relative));
+                    }
+                }
+            }
+        } else {
+            entries = fs::read_to_string(path)?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| key(Path::new(&line.replace('\\', "/"))))
+                .collect();
+        }
+        entries.sort();
+        entries.dedup();
+        Ok(Selection { entries })
+    }
+
+    /// Number of distinct entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Whether the frame written to `output`, relative to the output
+    /// directory, is selected.
+    pub fn contains(&self, output: &Path) -> bool {
+        let output = key(output);
+        self.entries.iter().any(|entry| matches(entry, &output))
+    }
+
+    /// Entries that pick out none of `outputs`, such as typing mistakes.
+    pub fn unmatched<'a>(&'a self, outputs: &[&Path]) -> Vec<&'a str> {
+        let outputs: Vec<String> = outputs.iter().map(|output| key(output)).collect();
+        self.entries
+            .iter()
+            .filter(|entry| !outputs.iter().any(|output| matches(entry, output)))
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+fn matches(entry: &str, output: &str) -> bool {
+    let name = output.rsplit('/').next().unwrap_or(output);
+    entry == output
+        || entry
+            .strip_suffix(output)
+            .is_some_and(|prefix| prefix.ends_with('/'))
+        || (!entry.contains('/') && entry == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orientation;
+    use crate::pwad;
+    use crate::sinar_ia::{META_KEY, THUMB_HT, THUMB_WD};
+
+    #[test]
+    fn test_cull() {
+        let dir = crate::test_dir("cull");
+        let (width, height) = (THUMB_WD as usize, THUMB_HT as usize);
+        let mut meta = vec![0u8; 360];
+        meta[272..280].copy_from_slice(b"e75-0042");
+        let ia = dir.join("0000001.IA");
+        pwad::write_pwad(
+            &ia,
+            &[(META_KEY, meta), ("THUMB", vec![90; width * height * 3])],
+        )
+        .unwrap();
+
+        // Thumbnails come out turned, and are kept once written.
+        let thumbs = dir.join("thumbs");
+        let png = thumbs.join("e75-0042/1201.png");
+        let write = |path: &Path, format, on_exist| {
+            write_thumbnail(&ia, path, format, orientation::HY6, on_exist)
+        };
+        assert_eq!(
+            write(&png, ThumbFormat::Png, OnExist::Skip),
+            ThumbOutcome::Written
+        );
+        let decoder = png::Decoder::new(std::fs::File::open(&png).unwrap());
+        let info = decoder.read_info().unwrap().info().clone();
+        assert_eq!((info.width, info.height), (height as u32, width as u32));
+        assert_eq!(
+            write(&png, ThumbFormat::Png, OnExist::Skip),
+            ThumbOutcome::Skipped
+        );
+        assert_eq!(
+            write(&png, ThumbFormat::Png, OnExist::Verify),
+            ThumbOutcome::Skipped
+        );
+        let jpeg = thumbs.join("e75-0042/1202.jpg");
+        assert_eq!(
+            write(&jpeg, ThumbFormat::Jpeg, OnExist::Overwrite),
+            ThumbOutcome::Written
+        );
+        assert_eq!(std::fs::read(&jpeg).unwrap()[..2], [0xff, 0xd8]);
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            // IA names need not be UTF-8.
+            let name = dir.join(std::ffi::OsStr::from_bytes(b"\xff.IA"));
+            std::fs::copy(&ia, &name).unwrap();
+            assert_eq!(
+                write_thumbnail(&name, &jpeg, ThumbFormat::Jpeg, 1, OnExist::Overwrite),
+                ThumbOutcome::Written
+            );
+        }
+        assert!(matches!(
+            write_thumbnail(
+                &dir.join("MISSING.IA"),
+                &jpeg,
+                ThumbFormat::Jpeg,
+                1,
+                OnExist::Overwrite
+            ),
+            ThumbOutcome::Failed(_)
+        ));
+
+        // The folder left after culling selects its frames, whatever the extension.
+        std::fs::write(thumbs.join(".DS_Store"), b"").unwrap();
+        let kept = Selection::read(&thumbs).unwrap();
+        assert_eq!(kept.len(), 2);
+        assert!(kept.contains(Path::new("e75-0042/1201.dng")));
+        assert!(kept.contains(Path::new("e75-0042/1202.tif")));
+        assert!(!kept.contains(Path::new("e75-0042/1203.dng")));
+
+        // A list may give bare names, paths under the thumbnail folder or absolute paths.
+        let list = dir.join("picks.txt");
+        std::fs::write(
+            &list,
+            "# picks\n1201.jpg\n\nthumbs/e75-0042/1305.jpg\n/Volumes/Cull/1400.png\n7777\n",
+        )
+        .unwrap();
+        let picks = Selection::read(&list).unwrap();
+        assert_eq!(picks.len(), 4);
+        assert!(picks.contains(Path::new("1201.dng")));
+        assert!(picks.contains(Path::new("e75-0042/1201.dng")));
+        assert!(picks.contains(Path::new("e75-0042/1305.dng")));
+        assert!(!picks.contains(Path::new("e75-0099/1305.dng")));
+        assert!(picks.contains(Path::new("1400.dng")));
+        assert!(!picks.contains(Path::new("11201.dng")));
+        // Only image extensions are dropped, so a dot in a name is kept.
+        std::fs::write(&list, "1201_f5.6\n1202_f8.0.JPG\n").unwrap();
+        let dotted = Selection::read(&list).unwrap();
+        assert!(dotted.contains(Path::new("e75-0042/1201_f5.6.dng")));
+        assert!(dotted.contains(Path::new("1202_f8.0.tif")));
+        assert!(!dotted.contains(Path::new("1201_f5.dng")));
+        let outputs = [Path::new("1201.dng"), Path::new("1400.dng")];
+        assert_eq!(picks.unmatched(&outputs), ["7777", "thumbs/e75-0042/1305"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
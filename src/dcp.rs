@@ -0,0 +1,283 @@
+/*
+DNG camera profiles (DCP) embedded in DNG output.
+
+A `.dcp` file is a TIFF-like file ("IIRC" rather than "II*") whose IFD0
+holds the tags of one camera profile: the colour matrices and illuminants,
+and optionally hue/saturation maps, a look table and a tone curve. Raw
+converters apply an embedded profile as they would the same file installed
+alongside them, so a colourist's profile travels with the frames.
+
+Profiles are kept per back in the calibration database, as `<serial>.dcp`
+next to the back's YAML entry; see `backs`. A frame from such a back gets
+the profile's tags in IFD0 in place of the back's colour matrices. A
+profile is made for one UniqueCameraModel, and a frame of any other model
+fails rather than being rendered through the wrong profile. Profiles whose
+ProfileEmbedPolicy forbids embedding are refused when read.
+*/
+
+use std::io::{self, Cursor};
+
+use rawler::{
+    formats::tiff::{DirectoryWriter, Rational, SRational, TiffError, Value},
+    tags::DngTag,
+};
+
+use crate::tiffread::{IfdEntry, TiffReader};
+
+const TAG_UNIQUE_CAMERA_MODEL: u16 = 50708;
+const TAG_COLOR_MATRIX_1: u16 = 50721;
+const TAG_PROFILE_NAME: u16 = 50936;
+const TAG_PROFILE_EMBED_POLICY: u16 = 50941;
+
+/// The ProfileEmbedPolicy value "embed never".
+const EMBED_NEVER: u32 = 2;
+
+// The text tags of a profile copied into a DNG.
+const TEXT_TAGS: [(u16, DngTag); 3] = [
+    (TAG_PROFILE_NAME, DngTag::ProfileName),
+    (50932, DngTag::ProfileCalibrationSignature),
+    (50942, DngTag::ProfileCopyright),
+];
+
+// The numeric tags of a profile copied into a DNG. UniqueCameraModel is
+// the DNG's own.
+const VALUE_TAGS: [(u16, DngTag); 23] = [
+    (50778, DngTag::CalibrationIlluminant1),
+    (50779, DngTag::CalibrationIlluminant2),
+    (52529, DngTag::CalibrationIlluminant3),
+    (TAG_COLOR_MATRIX_1, DngTag::ColorMatrix1),
+    (50722, DngTag::ColorMatrix2),
+    (52531, DngTag::ColorMatrix3),
+    (50964, DngTag::ForwardMatrix1),
+    (50965, DngTag::ForwardMatrix2),
+    (52532, DngTag::ForwardMatrix3),
+    (52533, DngTag::IlluminantData1),
+    (52534, DngTag::IlluminantData2),
+    (52535, DngTag::IlluminantData3),
+    (50937, DngTag::ProfileHueSatMapDims),
+    (50938, DngTag::ProfileHueSatMapData1),
+    (50939, DngTag::ProfileHueSatMapData2),
+    (52537, DngTag::ProfileHueSatMapData3),
+    (51107, DngTag::ProfileHueSatMapEncoding),
+    (50981, DngTag::ProfileLookTableDims),
+    (50982, DngTag::ProfileLookTableData),
+    (51108, DngTag::ProfileLookTableEncoding),
+    (50940, DngTag::ProfileToneCurve),
+    (TAG_PROFILE_EMBED_POLICY, DngTag::ProfileEmbedPolicy),
+    (51109, DngTag::BaselineExposureOffset),
+];
+
+/// A camera profile read from a `.dcp` file.
+#[derive(Debug, Clone)]
+pub struct Dcp {
+    /// ProfileName, if the profile has one
+    pub name: Option<String>,
+    /// The camera model the profile was made for
+    pub unique_camera_model: String,
+    text: Vec<(DngTag, String)>,
+    values: Vec<(DngTag, Value)>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// The entry's values in its own TIFF type.
+fn value(entry: &IfdEntry) -> Option<Value> {
+    let words = || entry.as_u32s();
+    Some(match entry.typ {
+        1 => Value::Byte(entry.data.clone()),
+        3 => Value::Short(words().into_iter().map(|v| v as u16).collect()),
+        4 => Value::Long(words()),
+        5 => Value::Rational(
+            entry
+                .as_u32_pairs()
+                .into_iter()
+                .map(|[n, d]| Rational::new(n, d))
+                .collect(),
+        ),
+        7 => Value::Undefined(entry.data.clone()),
+        8 => Value::SShort(words().into_iter().map(|v| v as u16 as i16).collect()),
+        9 => Value::SLong(words().into_iter().map(|v| v as i32).collect()),
+        10 => Value::SRational(
+            entry
+                .as_u32_pairs()
+                .into_iter()
+                .map(|[n, d]| SRational::new(n as i32, d as i32))
+                .collect(),
+        ),
+        11 => Value::Float(entry.as_f64s().into_iter().map(|v| v as f32).collect()),
+        12 => Value::Double(entry.as_f64s()),
+        _ => return None,
+    })
+}
+
+impl Dcp {
+    /// Read a profile from the contents of a `.dcp` file.
+    pub fn parse(bytes: &[u8]) -> io::Result<Dcp> {
+        let mut tiff = TiffReader::new(Cursor::new(bytes))?;
+        if tiff.magic != 0x4352 && tiff.magic != 42 {
+            return Err(invalid("not a DNG camera profile".to_string()));
+        }
+        let ifd = tiff.read_ifd(tiff.first_ifd)?;
+        let unique_camera_model = ifd
+            .get(TAG_UNIQUE_CAMERA_MODEL)
+            .map(|e| e.as_string())
+            .filter(|model| !model.is_empty())
+            .ok_or_else(|| invalid("profile has no UniqueCameraModel".to_string()))?;
+        if ifd.get(TAG_COLOR_MATRIX_1).map(|e| e.count) != Some(9) {
+            return Err(invalid("profile has no 3x3 ColorMatrix1".to_string()));
+        }
+        let policy = ifd
+            .get(TAG_PROFILE_EMBED_POLICY)
+            .and_then(|e| e.as_u32s().first().copied());
+        if policy == Some(EMBED_NEVER) {
+            return Err(invalid(
+                "profile's ProfileEmbedPolicy forbids embedding it".to_string(),
+            ));
+        }
+
+        let text = TEXT_TAGS
+            .iter()
+            .filter_map(|&(id, tag)| Some((tag, ifd.get(id)?.as_string())))
+            .filter(|(_, text)| !text.is_empty())
+            .collect();
+        let mut values = Vec::new();
+        for &(id, tag) in &VALUE_TAGS {
+            if let Some(entry) = ifd.get(id) {
+                let value = value(entry).ok_or_else(|| {
+                    invalid(format!("tag {} has unsupported type {}", id, entry.typ))
+                })?;
+                values.push((tag, value));
+            }
+        }
+        Ok(Dcp {
+            name: ifd
+                .get(TAG_PROFILE_NAME)
+                .map(|e| e.as_string())
+                .filter(|name| !name.is_empty()),
+            unique_camera_model,
+            text,
+            values,
+        })
+    }
+
+    /// Check the profile was made for the camera a DNG names as `model`.
+    pub fn check_camera(&self, model: &str) -> Result<(), String> {
+        if self.unique_camera_model != model {
+            return Err(format!(
+                "camera profile {}is for '{}', not '{}'",
+                self.name
+                    .as_ref()
+                    .map(|name| format!("'{}' ", name))
+                    .unwrap_or_default(),
+                self.unique_camera_model,
+                model
+            ));
+        }
+        Ok(())
+    }
+
+    /// Write the profile's tags to a DNG's IFD0.
+    pub fn write(&self, root_ifd: &mut DirectoryWriter) -> Result<(), TiffError> {
+        for (tag, text) in &self.text {
+            root_ifd.add_tag(*tag, text.as_str())?;
+        }
+        for (tag, value) in &self.values {
+            root_ifd.add_tag(*tag, value.clone())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backs::{BackCalibration, BackDb, ColourCalibration, Illuminant};
+    use crate::iadng;
+    use rawler::formats::tiff::TiffWriter;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_dcp() {
+        let profile = |model: &str, policy: u32| {
+            let mut bytes = Cursor::new(Vec::new());
+            let mut dcp = TiffWriter::new(&mut bytes).unwrap();
+            let mut ifd = dcp.new_directory();
+            ifd.add_tag(DngTag::UniqueCameraModel, model).unwrap();
+            ifd.add_tag(DngTag::ProfileName, "Studio 2 neutral")
+                .unwrap();
+            ifd.add_tag(DngTag::CalibrationIlluminant1, 21_u16).unwrap();
+            let matrix: Vec<SRational> = (0..9).map(|i| SRational::new(i - 4, 8)).collect();
+            ifd.add_tag(DngTag::ColorMatrix1, &matrix[..]).unwrap();
+            ifd.add_tag(DngTag::ProfileHueSatMapDims, [2_u32, 1, 1])
+                .unwrap();
+            ifd.add_tag(
+                DngTag::ProfileHueSatMapData1,
+                [0.0_f32, 1.0, 1.0, 5.0, 0.9, 1.1],
+            )
+            .unwrap();
+            ifd.add_tag(DngTag::ProfileToneCurve, [0.0_f32, 0.0, 1.0, 1.0])
+                .unwrap();
+            ifd.add_tag(DngTag::ProfileEmbedPolicy, Value::Long(vec![policy]))
+                .unwrap();
+            let offset = ifd.build().unwrap();
+            dcp.build(offset).unwrap();
+            bytes.into_inner()
+        };
+        let meta = iadng::test_meta();
+        let model = iadng::unique_camera_model(&meta);
+        assert!(Dcp::parse(&profile(&model, 2)).is_err());
+        assert!(Dcp::parse(&profile("Emotion 22 on Sinar Hy6", 0))
+            .unwrap()
+            .check_camera(&model)
+            .is_err());
+
+        // Copied into the database, which makes an entry for the back.
+        let dir = crate::test_dir("dcp");
+        let source = dir.join("studio.dcp");
+        std::fs::write(&source, profile(&model, 1)).unwrap();
+        let mut db = BackDb::open(&dir).unwrap();
+        db.save_profile("e75-0042", &source).unwrap();
+        std::fs::remove_file(&source).unwrap();
+        let db = BackDb::open(&dir).unwrap();
+        assert!(db.get("e75-0042").is_some());
+        let dcp = db.profile("e75-0042").unwrap();
+        assert_eq!(dcp.name.as_deref(), Some("Studio 2 neutral"));
+        dcp.check_camera(&model).unwrap();
+
+        // Its tags replace the back's colour matrices.
+        let mut meta = iadng::test_meta();
+        meta.back = Some(Arc::new(BackCalibration {
+            colour: vec![
+                ColourCalibration {
+                    illuminant: Illuminant::A,
+                    color_matrix: [1.0; 9],
+                    forward_matrix: None,
+                },
+                ColourCalibration {
+                    illuminant: Illuminant::D65,
+                    color_matrix: [1.0; 9],
+                    forward_matrix: None,
+                },
+            ],
+            ..BackCalibration::default()
+        }));
+        meta.profile = Some(Arc::clone(dcp));
+        let root = iadng::write_test_dng(&meta, &dir.join("e75-0042.dng")).root;
+        assert_eq!(root.get(50708).unwrap().as_string(), model);
+        assert_eq!(root.get(50936).unwrap().as_string(), "Studio 2 neutral");
+        assert_eq!(root.get(50778).unwrap().as_u32s(), [21]);
+        let matrix: Vec<f64> = (0..9).map(|i| (i as f64 - 4.0) / 8.0).collect();
+        assert_eq!(root.get(50721).unwrap().as_f64s(), matrix);
+        assert!(root.get(50722).is_none());
+        assert_eq!(root.get(50937).unwrap().as_u32s(), [2, 1, 1]);
+        assert_eq!(
+            root.get(50938).unwrap().as_f64s(),
+            [0.0, 1.0, 1.0, 5.0, 0.9f32 as f64, 1.1f32 as f64]
+        );
+        assert_eq!(root.get(50940).unwrap().as_f64s(), [0.0, 0.0, 1.0, 1.0]);
+        assert_eq!(root.get(50941).unwrap().as_u32s(), [1]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,411 @@
+/*
+Output writers. The CFA DNG is written by `iadng`; everything else is
+demosaiced from the same calibrated buffer first.
+*/
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::mem::size_of;
+use std::path::Path;
+
+use log::{debug, info};
+use ndarray::Array1;
+use rawler::{
+    formats::tiff::{CompressionMethod, PhotometricInterpretation, TiffError, TiffWriter, Value},
+    tags::{ExifTag, TiffCommonTag},
+};
+
+use crate::backs::{self, Illuminant};
+use crate::calibrate::Sample;
+use crate::demosaic::{self, Algorithm};
+use crate::iadng::{self, WriteStats};
+use crate::original::Originals;
+use crate::profile::{self, Matrix};
+use crate::sinar_ia::{ConvertOptions, SinarIAMeta};
+use crate::tiffread::TiffReader;
+
+#[derive(Debug, PartialEq, Clone, Copy, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// CFA DNG with the calibrated sensor data
+    Dng,
+    /// Demosaiced LinearRaw DNG
+    LinearDng,
+    /// Demosaiced, white balanced 16-bit linear TIFF
+    Tiff,
+    /// Demosaiced, white balanced 16-bit sRGB TIFF, through the back's colour matrix
+    TiffSrgb,
+    /// Demosaiced, white balanced 16-bit sRGB PNG, through the back's colour matrix
+    Png,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Dng | OutputFormat::LinearDng => "dng",
+            OutputFormat::Tiff | OutputFormat::TiffSrgb => "tif",
+            OutputFormat::Png => "png",
+        }
+    }
+}
+
+fn tiff_error(e: TiffError) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+/// Write the calibrated CFA buffer `raw` to `path` in `options.format`.
+/// Only DNGs embed `originals`.
+pub fn write_output<S: Sample>(
+    raw: &Array1<S>,
+    thumb: &[u8],
+    path: &Path,
+    meta: &SinarIAMeta,
+    options: &ConvertOptions,
+    originals: Originals,
+) -> io::Result<WriteStats> {
+    let format = options.format;
+    if format == OutputFormat::Dng {
+        return iadng::write_1d_array_to_dng(raw, thumb, path, meta, originals).map_err(tiff_error);
+    }
+    if format == OutputFormat::LinearDng && options.demosaic == Algorithm::HalfSize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "LinearRaw DNGs need a full-size demosaic",
+        ));
+    }
+    // The demosaic works in f64 whatever precision the frame was calibrated in.
+    let mut raw = raw.mapv(|v| v.to_f64());
+    if let Some(back) = &meta.back {
+        // Unlike a DNG reader, the demosaic doesn't know of defects.
+        let (width, height) = (meta.width as usize, meta.height as usize);
+        backs::fix_defects(raw.as_slice_mut().unwrap(), width, height, &back.defects);
+    }
+    let rgb = demosaic::demosaic(
+        &raw,
+        meta.width as usize,
+        meta.height as usize,
+        meta.cfa_pattern(),
+        options.demosaic,
+    );
+    let (height, width, _) = rgb.dim();
+    let rgb = rgb.into_shape(width * height * 3).unwrap();
+    match format {
+        OutputFormat::LinearDng => {
+            iadng::write_linear_dng(&rgb, thumb, path, meta, originals).map_err(tiff_error)
+        }
+        OutputFormat::Tiff | OutputFormat::TiffSrgb => {
+            let srgb = (format == OutputFormat::TiffSrgb).then(|| camera_to_srgb(meta));
+            let (data, stats) = render_rgb(&rgb, srgb.as_ref());
+            info!("Writing TIFF to {}", path.display());
+            iadng::write_atomically(path, |tmp| write_tiff(&data, width, height, tmp, meta))
+                .map_err(tiff_error)?;
+            Ok(stats)
+        }
+        OutputFormat::Png => {
+            let (data, stats) = render_rgb(&rgb, Some(&camera_to_srgb(meta)));
+            let (data, out_width, out_height) = orient(&data, width, height, 3, meta.orientation);
+            let bytes: Vec<u8> = data.iter().flat_map(|v| v.to_be_bytes()).collect();
+            info!("Writing PNG to {}", path.display());
+            iadng::write_atomically(path, |tmp| {
+                write_png(&bytes, png::BitDepth::Sixteen, out_width, out_height, tmp)
+            })?;
+            Ok(stats)
+        }
+        OutputFormat::Dng => unreachable!(),
+    }
+}
+
+fn srgb_encode(v: f64) -> f64 {
+    if v <= 0.0031308 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Linear sRGB to XYZ under D65.
+const SRGB_TO_XYZ: Matrix = [
+    0.4124564, 0.3575761, 0.1804375, 0.2126729, 0.7151522, 0.0721750, 0.0193339, 0.1191920,
+    0.9503041,
+];
+
+/// White balanced camera RGB to linear sRGB, from the back's ColorMatrix
+/// nearest D65, or the placeholder DNGs get without one. Rows are scaled so
+/// that camera white stays white.
+pub fn camera_to_srgb(meta: &SinarIAMeta) -> Matrix {
+    let color_matrix = meta
+        .back
+        .as_ref()
+        .and_then(|back| {
+            back.colour
+                .iter()
+                .min_by_key(|c| (c.illuminant as i32 - Illuminant::D65 as i32).abs())
+        })
+        .map_or(iadng::PLACEHOLDER_COLOR_MATRIX, |c| c.color_matrix);
+    let mut srgb_to_camera = profile::mul(&color_matrix, &SRGB_TO_XYZ);
+    for row in srgb_to_camera.chunks_exact_mut(3) {
+        let sum: f64 = row.iter().sum();
+        row.iter_mut().for_each(|v| *v /= sum);
+    }
+    profile::inverse(&srgb_to_camera).unwrap_or(profile::diagonal([1.0; 3]))
+}
+
+/// White balance interleaved camera RGB, normalise to the brightest sample
+/// and quantise to 16 bits. With `to_srgb`, a camera to linear sRGB matrix,
+/// the samples are converted to sRGB and through its transfer curve.
+pub fn render_rgb(rgb: &Array1<f64>, to_srgb: Option<&Matrix>) -> (Vec<u16>, WriteStats) {
+    let wb = iadng::estimate_white_balance_rgb(rgb);
+    let gains = [1.0 / wb.0, 1.0 / wb.1, 1.0 / wb.2];
+    let balanced = |pixel: &[f64]| -> [f64; 3] {
+        let camera = std::array::from_fn(|c| pixel[c] * gains[c]);
+        match to_srgb {
+            Some(matrix) => profile::apply(matrix, camera),
+            None => camera,
+        }
+    };
+    let max = rgb
+        .as_slice()
+        .unwrap()
+        .chunks_exact(3)
+        .flat_map(balanced)
+        .fold(0.0_f64, f64::max);
+    let scale = if max > 0.0 { 1.0 / max } else { 0.0 };
+    debug!(
+        "White balance gains: {:?}, max: {}, scale: {}",
+        gains, max, scale
+    );
+    let data = rgb
+        .as_slice()
+        .unwrap()
+        .chunks_exact(3)
+        .flat_map(balanced)
+        .map(|v| {
+            let v = (v * scale).clamp(0.0, 1.0);
+            let v = if to_srgb.is_some() { srgb_encode(v) } else { v };
+            (v * u16::MAX as f64).round() as u16
+        })
+        .collect();
+    (
+        data,
+        WriteStats {
+            min: 0.0,
+            max,
+            scale,
+            white_balance: wb,
+        },
+    )
+}
+
+/// Apply a TIFF/EXIF orientation to interleaved pixel data, returning the
+/// displayed pixels and their width and height.
+pub fn orient<T: Copy>(
+    data: &[T],
+    width: usize,
+    height: usize,
+    channels: usize,
+    orientation: u16,
+) -> (Vec<T>, usize, usize) {
+    let (out_width, out_height) = if orientation >= 5 {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    let mut out = Vec::with_capacity(data.len());
+    for y in 0..out_height {
+        for x in 0..out_width {
+            let (row, col) = match orientation {
+                2 => (y, width - 1 - x),
+                3 => (height - 1 - y, width - 1 - x),
+                4 => (height - 1 - y, x),
+                5 => (x, y),
+                6 => (height - 1 - x, y),
+                7 => (height - 1 - x, width - 1 - y),
+                8 => (x, width - 1 - y),
+                _ => (y, x),
+            };
+            let i = (row * width + col) * channels;
+            out.extend_from_slice(&data[i..i + channels]);
+        }
+    }
+    (out, out_width, out_height)
+}
+
+fn write_tiff(
+    data: &[u16],
+    width: usize,
+    height: usize,
+    path: &Path,
+    meta: &SinarIAMeta,
+) -> Result<(), TiffError> {
+    let file = File::create(path)?;
+    let mut output = BufWriter::new(file);
+    let mut tiff = TiffWriter::new(&mut output)?;
+    let mut ifd = tiff.new_directory();
+    ifd.add_tag(TiffCommonTag::NewSubFileType, Value::Long(vec![0]))?;
+    ifd.add_tag(TiffCommonTag::ImageWidth, width as u32)?;
+    ifd.add_tag(TiffCommonTag::ImageLength, height as u32)?;
+    ifd.add_tag(
+        TiffCommonTag::PhotometricInt,
+        PhotometricInterpretation::RGB,
+    )?;
+    ifd.add_tag(TiffCommonTag::SamplesPerPixel, 3_u16)?;
+    ifd.add_tag(TiffCommonTag::BitsPerSample, [16_u16, 16, 16])?;
+    ifd.add_tag(TiffCommonTag::SampleFormat, [1_u16, 1, 1])?;
+    ifd.add_tag(ExifTag::PlanarConfiguration, 1_u16)?;
+    ifd.add_tag(TiffCommonTag::Compression, CompressionMethod::None)?;
+    ifd.add_tag(TiffCommonTag::Orientation, meta.orientation)?;
+    ifd.add_tag(TiffCommonTag::Software, "iatodng_rs v1.0")?;
+    ifd.add_tag(TiffCommonTag::Model, meta.model.as_str())?;
+    ifd.add_tag(TiffCommonTag::Make, meta.camera.as_str())?;
+    if let Some(captured) = &meta.captured {
+        ifd.add_tag(ExifTag::ModifyDate, captured.exif_datetime())?;
+    }
+    let offset = ifd.write_data_u16_be(data)?;
+    ifd.add_tag(TiffCommonTag::StripOffsets, offset)?;
+    ifd.add_tag(
+        TiffCommonTag::StripByteCounts,
+        std::mem::size_of_val(data) as u32,
+    )?;
+    ifd.add_tag(TiffCommonTag::RowsPerStrip, height as u32)?;
+    let ifd_off = ifd.build()?;
+    tiff.build(ifd_off)?;
+    output.flush()?;
+    output.get_ref().sync_all()?;
+    Ok(())
+}
+
+/// Write interleaved RGB as an sRGB PNG; 16-bit samples are big endian.
+pub(crate) fn write_png(
+    bytes: &[u8],
+    depth: png::BitDepth,
+    width: usize,
+    height: usize,
+    path: &Path,
+) -> io::Result<()> {
+    let png_error = |e: png::EncodingError| io::Error::other(e.to_string());
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(depth);
+    encoder.set_source_srgb(png::SrgbRenderingIntent::Perceptual);
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    writer.write_image_data(bytes).map_err(png_error)?;
+    writer.finish().map_err(png_error)
+}
+
+/// Format-aware check that an existing output file is complete.
+pub fn verify_output(path: &Path, meta: &SinarIAMeta, options: &ConvertOptions) -> io::Result<()> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let (width, height) =
+        demosaic::output_size(meta.width as usize, meta.height as usize, options.demosaic);
+    let (width, height) = (width as u32, height as u32);
+    match options.format {
+        OutputFormat::Dng => iadng::verify_dng(path, meta, 1),
+        OutputFormat::LinearDng => iadng::verify_dng(path, meta, 3),
+        OutputFormat::Tiff | OutputFormat::TiffSrgb => {
+            let mut tiff = TiffReader::open(path)?;
+            let ifd = tiff.read_ifd(tiff.first_ifd)?;
+            let get = |tag: u16| ifd.get(tag).map(|e| e.as_u32s()).unwrap_or_default();
+            if get(256) != vec![width] || get(257) != vec![height] {
+                return Err(invalid("image size does not match frame".to_string()));
+            }
+            let (offsets, sizes) = (get(273), get(279));
+            let total: u64 = sizes.iter().map(|&s| s as u64).sum();
+            if total != width as u64 * height as u64 * 3 * size_of::<u16>() as u64 {
+                return Err(invalid(format!("strips hold {} bytes", total)));
+            }
+            if offsets.len() != sizes.len()
+                || offsets
+                    .iter()
+                    .zip(sizes.iter())
+                    .any(|(&o, &s)| o as u64 + s as u64 > tiff.len)
+            {
+                return Err(invalid("strips run past end of file".to_string()));
+            }
+            Ok(())
+        }
+        OutputFormat::Png => {
+            let png_error = |e: png::DecodingError| invalid(e.to_string());
+            let decoder = png::Decoder::new(File::open(path)?);
+            let mut reader = decoder.read_info().map_err(png_error)?;
+            // PNGs are stored rotated to display orientation.
+            let (width, height) = if meta.orientation >= 5 {
+                (height, width)
+            } else {
+                (width, height)
+            };
+            if (reader.info().width, reader.info().height) != (width, height) {
+                return Err(invalid("image size does not match frame".to_string()));
+            }
+            let mut buffer = vec![0; reader.output_buffer_size()];
+            reader.next_frame(&mut buffer).map_err(png_error)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::backs::{BackCalibration, ColourCalibration, Illuminant};
+    use crate::profile;
+
+    #[test]
+    fn test_orient() {
+        // 3x2 image, one channel:
+        // 1 2 3
+        // 4 5 6
+        let data = [1, 2, 3, 4, 5, 6];
+        assert_eq!(orient(&data, 3, 2, 1, 1), (data.to_vec(), 3, 2));
+        assert_eq!(orient(&data, 3, 2, 1, 3), (vec![6, 5, 4, 3, 2, 1], 3, 2));
+        assert_eq!(orient(&data, 3, 2, 1, 6), (vec![4, 1, 5, 2, 6, 3], 2, 3));
+        assert_eq!(orient(&data, 3, 2, 1, 7), (vec![6, 3, 5, 2, 4, 1], 2, 3));
+        assert_eq!(orient(&data, 3, 2, 1, 8), (vec![3, 6, 2, 5, 1, 4], 2, 3));
+    }
+
+    #[test]
+    fn test_render_srgb() {
+        // Without a calibration the camera is taken to have Adobe RGB's
+        // primaries, so its pure green lies outside sRGB, while white stays
+        // white.
+        let matrix = camera_to_srgb(&SinarIAMeta::default());
+        for white in profile::apply(&matrix, [1.0; 3]) {
+            assert!((white - 1.0).abs() < 1e-9);
+        }
+        let green = profile::apply(&matrix, [0.0, 1.0, 0.0]);
+        assert!(green[0] < 0.0 && green[1] > 1.0 && green[2] < 0.0);
+
+        // A back calibrated as having sRGB's primaries passes straight through.
+        let back = BackCalibration {
+            colour: vec![ColourCalibration {
+                illuminant: Illuminant::D65,
+                color_matrix: [
+                    3.2404542, -1.5371385, -0.4985314, -0.9692660, 1.8760108, 0.0415560, 0.0556434,
+                    -0.2040259, 1.0572252,
+                ],
+                forward_matrix: None,
+            }],
+            ..BackCalibration::default()
+        };
+        let meta = SinarIAMeta {
+            back: Some(Arc::new(back)),
+            ..SinarIAMeta::default()
+        };
+        for (v, identity) in camera_to_srgb(&meta)
+            .iter()
+            .zip(profile::diagonal([1.0; 3]))
+        {
+            assert!((v - identity).abs() < 1e-4);
+        }
+
+        // Grey, green and magenta: grey stays neutral either way, and the
+        // green is clipped to the sRGB gamut.
+        let rgb = Array1::from_vec(vec![0.25, 0.25, 0.25, 0.0, 1.0, 0.0, 1.0, 0.0, 1.0]);
+        let (linear, _) = render_rgb(&rgb, None);
+        assert_eq!(linear[3..6], [0, u16::MAX, 0]);
+        let (srgb, _) = render_rgb(&rgb, Some(&matrix));
+        assert!(srgb[0] == srgb[1] && srgb[1] == srgb[2]);
+        assert_eq!([srgb[3], srgb[5]], [0, 0]);
+    }
+}
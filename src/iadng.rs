@@ -1,6 +1,6 @@
 extern crate rawler;
 use ndarray::parallel::prelude::{IntoParallelRefIterator, ParallelIterator};
-use ndarray::{Array1, Dim, OwnedRepr};
+use ndarray::Array1;
 use rawler::{
     dng::{rect_to_dng_area, DNG_VERSION_V1_1, DNG_VERSION_V1_6},
     formats::tiff::{
@@ -11,39 +11,297 @@ use rawler::{
     tags::{DngTag, ExifTag, TiffCommonTag},
 };
 
-use std::{fs::File, io::BufWriter, mem::size_of, path::PathBuf};
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    mem::size_of,
+    path::{Path, PathBuf},
+};
+
+use crate::backs;
+use crate::calibrate::{Sample, SensorLevels};
+use crate::demosaic::cfa_color;
+use crate::export::verify_output;
+use crate::noise;
+use crate::original::{EmbeddedFile, Originals};
+use crate::sinar_ia::{ConvertOptions, SinarIAMeta, THUMB_HT, THUMB_WD};
+use crate::tiffread::TiffReader;
+use crate::xmp;
+use log::{debug, info, warn};
+
+pub(crate) const TAG_SUBIFDS: u16 = 330;
+pub(crate) const TAG_IMAGE_WIDTH: u16 = 256;
+pub(crate) const TAG_IMAGE_LENGTH: u16 = 257;
+pub(crate) const TAG_STRIP_OFFSETS: u16 = 273;
+pub(crate) const TAG_SAMPLES_PER_PIXEL: u16 = 277;
+pub(crate) const TAG_STRIP_BYTE_COUNTS: u16 = 279;
+pub(crate) const TAG_BLACK_LEVEL: u16 = 50714;
+pub(crate) const TAG_RAW_IMAGE_DIGEST: u16 = 50972;
+pub(crate) const TAG_DNG_PRIVATE_DATA: u16 = 50740;
+pub(crate) const TAG_ORIGINAL_RAW_FILE_NAME: u16 = 50827;
+pub(crate) const TAG_ORIGINAL_RAW_FILE_DATA: u16 = 50828;
+pub(crate) const TAG_ORIGINAL_RAW_FILE_DIGEST: u16 = 50973;
+
+// DNGPrivateData starts with a NUL terminated maker name; ours is followed
+// by records of a 4-byte name, a big-endian u32 length and the data.
+const PRIVATE_MAKER: &[u8] = b"iatodng\0";
+/// The original IA META lump.
+pub const PRIVATE_META: [u8; 4] = *b"META";
+/// Present if the raw data was calibrated and scaled: min and max as f64.
+pub const PRIVATE_SCALED: [u8; 4] = *b"SCAL";
+/// An embedded BR/WR reference, one record per file.
+pub const PRIVATE_REFERENCE: [u8; 4] = *b"OREF";
+
+#[derive(Debug, PartialEq, Clone, Copy, clap::ValueEnum)]
+pub enum OnExist {
+    /// Leave the existing file alone
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Skip only if the existing file checks out, otherwise replace it
+    Verify,
+    /// Write to the next free `_1`, `_2`, ... name
+    Rename,
+}
+
+/// Decide where to write `new_dng` given what is already on disk. `None` means skip.
+pub fn resolve_output(
+    new_dng: &Path,
+    meta: &SinarIAMeta,
+    options: &ConvertOptions,
+) -> Option<PathBuf> {
+    if !new_dng.exists() {
+        return Some(new_dng.to_path_buf());
+    }
+    match options.on_exist {
+        OnExist::Skip => {
+            info!("Output already exists, skipping");
+            None
+        }
+        OnExist::Overwrite => {
+            info!("Output already exists, overwriting");
+            Some(new_dng.to_path_buf())
+        }
+        OnExist::Verify => match verify_output(new_dng, meta, options) {
+            Ok(()) => {
+                info!("Output already exists and verified, skipping");
+                None
+            }
+            Err(e) => {
+                warn!(
+                    "Output already exists but failed verification ({}), overwriting",
+                    e
+                );
+                Some(new_dng.to_path_buf())
+            }
+        },
+        OnExist::Rename => {
+            let stem = new_dng
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let ext = new_dng
+                .extension()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let renamed = (1..)
+                .map(|n| new_dng.with_file_name(format!("{}_{}.{}", stem, n, ext)))
+                .find(|p| !p.exists())
+                .unwrap();
+            info!("Output already exists, writing {}", renamed.display());
+            Some(renamed)
+        }
+    }
+}
+
+/// Check that an existing DNG is complete: the raw IFD matches the frame
+/// size with `samples` per pixel, its strips lie inside the file, and
+/// `RawImageDigest` (if present) matches the decoded samples.
+pub fn verify_dng(path: &Path, meta: &SinarIAMeta, samples: u16) -> io::Result<()> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut tiff = TiffReader::open(path)?;
+    let root = tiff.read_ifd(tiff.first_ifd)?;
+    let raw_offset = root
+        .get(TAG_SUBIFDS)
+        .and_then(|e| e.as_u32s().first().copied())
+        .ok_or_else(|| invalid("no raw SubIFD".to_string()))?;
+    let raw = tiff.read_ifd(raw_offset)?;
+    let dims = (
+        raw.get(TAG_IMAGE_WIDTH).map(|e| e.as_u32s()),
+        raw.get(TAG_IMAGE_LENGTH).map(|e| e.as_u32s()),
+    );
+    if dims != (Some(vec![meta.width]), Some(vec![meta.height])) {
+        return Err(invalid(format!("raw size {:?} does not match frame", dims)));
+    }
+    let offsets = raw
+        .get(TAG_STRIP_OFFSETS)
+        .map(|e| e.as_u32s())
+        .unwrap_or_default();
+    let sizes = raw
+        .get(TAG_STRIP_BYTE_COUNTS)
+        .map(|e| e.as_u32s())
+        .unwrap_or_default();
+    let total: u64 = sizes.iter().map(|&s| s as u64).sum();
+    if offsets.is_empty() || offsets.len() != sizes.len() {
+        return Err(invalid("missing or mismatched strips".to_string()));
+    }
+    if total != meta.width as u64 * meta.height as u64 * samples as u64 * size_of::<u16>() as u64 {
+        return Err(invalid(format!("strips hold {} bytes", total)));
+    }
+    let mut digest = md5::Context::new();
+    for (&offset, &size) in offsets.iter().zip(sizes.iter()) {
+        // Samples are written big-endian and digested little-endian.
+        let mut strip = tiff.read_at(offset as u64, size as usize)?;
+        for sample in strip.chunks_exact_mut(2) {
+            sample.swap(0, 1);
+        }
+        digest.consume(&strip);
+    }
+    if let Some(expected) = root.get(TAG_RAW_IMAGE_DIGEST) {
+        if expected.data != digest.compute().0 {
+            return Err(invalid("RawImageDigest mismatch".to_string()));
+        }
+    }
+    Ok(())
+}
 
-use crate::sinar_ia::{SinarIAMeta, E75_CFA, THUMB_HT, THUMB_WD};
+/// The DNGPrivateData block: a maker tag followed by named records, each
+/// with a big-endian length. META is the original IA metadata lump; SCAL
+/// holds the range the raw data was scaled from, if it was, and each OREF an
+/// embedded reference file.
+pub(crate) fn private_data(
+    meta: &SinarIAMeta,
+    stats: Option<&WriteStats>,
+    refs: &[EmbeddedFile],
+) -> Vec<u8> {
+    let mut records = vec![(PRIVATE_META, meta.meta_lump.clone())];
+    if let Some(stats) = stats {
+        let mut scaled = stats.min.to_be_bytes().to_vec();
+        scaled.extend_from_slice(&stats.max.to_be_bytes());
+        records.push((PRIVATE_SCALED, scaled));
+    }
+    for file in refs {
+        records.push((PRIVATE_REFERENCE, file.to_record()));
+    }
+    let mut data = PRIVATE_MAKER.to_vec();
+    for (name, record) in records {
+        data.extend_from_slice(&name);
+        data.extend_from_slice(&(record.len() as u32).to_be_bytes());
+        data.extend_from_slice(&record);
+    }
+    data
+}
 
-fn scale_1d_f64_u16(image: &Array1<f64>) -> Vec<u16> {
+/// Split the DNGPrivateData written by `iatodng` into its records, or `None`
+/// if it was written by something else.
+pub fn parse_private_data(data: &[u8]) -> Option<Vec<([u8; 4], &[u8])>> {
+    let mut rest = data.strip_prefix(PRIVATE_MAKER)?;
+    let mut records = Vec::new();
+    while !rest.is_empty() {
+        if rest.len() < 8 {
+            return None;
+        }
+        let name = rest[..4].try_into().unwrap();
+        let len = u32::from_be_bytes(rest[4..8].try_into().unwrap()) as usize;
+        let record = rest.get(8..8 + len)?;
+        records.push((name, record));
+        rest = &rest[8 + len..];
+    }
+    Some(records)
+}
+
+/// The META lump kept in the DNGPrivateData of `path`, or `None` if it is
+/// not a DNG written by `iatodng`.
+pub fn read_private_meta(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let mut tiff = TiffReader::open(path)?;
+    let root = tiff.read_ifd(tiff.first_ifd)?;
+    Ok(root
+        .get(TAG_DNG_PRIVATE_DATA)
+        .and_then(|e| parse_private_data(&e.data))
+        .and_then(|records| {
+            records
+                .into_iter()
+                .find(|(name, _)| *name == PRIVATE_META)
+                .map(|(_, data)| data.to_vec())
+        })
+        .filter(|data| !data.is_empty()))
+}
+
+const OPCODE_VERSION: u32 = 0x0103_0000;
+// Opcodes may be skipped by readers that don't know them.
+const OPTIONAL: u32 = 1;
+
+/// An OpcodeList of `(id, params)` opcodes: their count, then each one's
+/// ID, version, flags and parameter length ahead of its parameters, all
+/// big endian.
+pub(crate) fn opcode_list(opcodes: &[(u32, Vec<u8>)]) -> Vec<u8> {
+    let mut list = Vec::new();
+    list.extend_from_slice(&(opcodes.len() as u32).to_be_bytes());
+    for (id, params) in opcodes {
+        for v in [*id, OPCODE_VERSION, OPTIONAL, params.len() as u32] {
+            list.extend_from_slice(&v.to_be_bytes());
+        }
+        list.extend_from_slice(params);
+    }
+    list
+}
+
+// Sibling temp file, so the final rename stays on one filesystem.
+fn temp_path(new_dng: &Path) -> PathBuf {
+    let name = new_dng
+        .file_name()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    new_dng.with_file_name(format!(".{}.tmp", name))
+}
+
+/// Calibration statistics of a written frame.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteStats {
+    pub min: f64,
+    pub max: f64,
+    pub scale: f64,
+    pub white_balance: (f64, f64, f64),
+}
+
+pub(crate) fn scale_to_u16<S: Sample>(image: &Array1<S>) -> (Vec<u16>, WriteStats) {
     let mut min = 0.0;
     let mut max = 0.0;
-    for i in image.iter() {
-        if i < &min {
-            min = *i;
+    for i in image.iter().map(|i| i.to_f64()) {
+        if i < min {
+            min = i;
         }
-        if i > &max {
-            max = *i;
+        if i > max {
+            max = i;
         }
     }
     let scale = u16::MAX as f64 / (max - min);
-    println!("\tmin: {}, max: {}, scale: {}", min, max, scale);
-    image
+    debug!("min: {}, max: {}, scale: {}", min, max, scale);
+    let scaled = image
         .par_iter()
-        .map(|i| i - min)
+        .map(|i| i.to_f64() - min)
         .map(|i| (i * scale).round() as u16)
-        .collect::<Vec<u16>>()
+        .collect::<Vec<u16>>();
+    (
+        scaled,
+        WriteStats {
+            min,
+            max,
+            scale,
+            ..Default::default()
+        },
+    )
 }
 
-fn matrix_to_tiff_value(xyz_to_cam: &Vec<f64>, d: i32) -> Vec<SRational> {
+fn matrix_to_tiff_value(xyz_to_cam: &[f64], d: i32) -> Vec<SRational> {
     xyz_to_cam
         .iter()
         .map(|a| SRational::new((a * d as f64) as i32, d))
         .collect()
 }
 
-fn estimate_white_balance(
-    raw_bayer_data: &Array1<f64>,
+fn estimate_white_balance<S: Sample>(
+    raw_bayer_data: &Array1<S>,
     width: usize,
     cfa_layout: [u8; 4],
 ) -> (f64, f64, f64) {
@@ -55,7 +313,7 @@ fn estimate_white_balance(
     let mut g_count = 0;
     let mut b_count = 0;
 
-    for (i, &value) in raw_bayer_data.iter().enumerate() {
+    for (i, value) in raw_bayer_data.iter().map(|v| v.to_f64()).enumerate() {
         let x = i % width;
         let y = i / width;
         let color = cfa_layout[((y & 1) << 1) + (x & 1)];
@@ -86,35 +344,212 @@ fn estimate_white_balance(
     (r_avg / max_avg, g_avg / max_avg, b_avg / max_avg)
 }
 
-pub(crate) fn write_1d_array_to_dng(
-    image: &Array1<f64>,
+// Same as `estimate_white_balance`, for interleaved RGB.
+pub(crate) fn estimate_white_balance_rgb<S: Sample>(rgb: &Array1<S>) -> (f64, f64, f64) {
+    let mut sums = [0.0; 3];
+    for (i, value) in rgb.iter().map(|v| v.to_f64()).enumerate() {
+        sums[i % 3] += value;
+    }
+    let max_sum = sums[0].max(sums[1]).max(sums[2]);
+    (sums[0] / max_sum, sums[1] / max_sum, sums[2] / max_sum)
+}
+
+/// Raw data stored in the main DNG IFD.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum DngRaw<'a, S: Sample> {
+    /// Calibrated CFA samples, one per site
+    Cfa(&'a Array1<S>),
+    /// Demosaiced camera RGB, interleaved
+    LinearRaw(&'a Array1<S>),
+}
+
+/// Source of the raw IFD's data, scaled to 16 bits and handed over a strip
+/// at a time so it never has to exist as one buffer.
+pub(crate) trait RawStrips {
+    /// 1 for CFA data, 3 for interleaved LinearRaw.
+    fn samples(&self) -> u16;
+    /// Gray-world white balance of the frame.
+    fn white_balance(&mut self, meta: &SinarIAMeta) -> Result<(f64, f64, f64), TiffError>;
+    /// Pass every strip, in order, to `write`.
+    fn write_strips(
+        &mut self,
+        write: &mut dyn FnMut(&[u16]) -> Result<(), TiffError>,
+    ) -> Result<WriteStats, TiffError>;
+    /// Levels of data written unscaled, or `None` for calibrated data
+    /// stretched to the full 16-bit range.
+    fn levels(&self) -> Option<SensorLevels> {
+        None
+    }
+}
+
+impl<'a, S: Sample> RawStrips for DngRaw<'a, S> {
+    fn samples(&self) -> u16 {
+        match self {
+            DngRaw::Cfa(_) => 1,
+            DngRaw::LinearRaw(_) => 3,
+        }
+    }
+
+    fn white_balance(&mut self, meta: &SinarIAMeta) -> Result<(f64, f64, f64), TiffError> {
+        Ok(match self {
+            DngRaw::Cfa(image) => {
+                estimate_white_balance(image, meta.width as usize, meta.cfa_pattern())
+            }
+            DngRaw::LinearRaw(rgb) => estimate_white_balance_rgb(rgb),
+        })
+    }
+
+    // The whole frame as a single strip.
+    fn write_strips(
+        &mut self,
+        write: &mut dyn FnMut(&[u16]) -> Result<(), TiffError>,
+    ) -> Result<WriteStats, TiffError> {
+        let (DngRaw::Cfa(image) | DngRaw::LinearRaw(image)) = self;
+        let (u16_image, stats) = scale_to_u16(image);
+        write(&u16_image)?;
+        Ok(stats)
+    }
+}
+
+/// Uncalibrated RAW0 counts, written as they are.
+pub(crate) struct SensorRaw<'a> {
+    pub data: &'a [u16],
+    pub levels: SensorLevels,
+}
+
+impl<'a> RawStrips for SensorRaw<'a> {
+    fn samples(&self) -> u16 {
+        1
+    }
+
+    // Gray world over the counts above the dark level of each CFA position.
+    fn white_balance(&mut self, meta: &SinarIAMeta) -> Result<(f64, f64, f64), TiffError> {
+        let width = meta.width as usize;
+        let cfa = meta.cfa_pattern();
+        let mut sums = [0.0; 3];
+        let mut counts = [0u64; 3];
+        for (i, &v) in self.data.iter().enumerate() {
+            let (x, y) = (i % width, i / width);
+            let c = cfa_color(cfa, x, y);
+            sums[c] += v as f64 - self.levels.black[(y & 1) << 1 | (x & 1)];
+            counts[c] += 1;
+        }
+        let avg: Vec<f64> = (0..3).map(|c| sums[c] / counts[c] as f64).collect();
+        let max_avg = avg[0].max(avg[1]).max(avg[2]);
+        Ok((avg[0] / max_avg, avg[1] / max_avg, avg[2] / max_avg))
+    }
+
+    fn write_strips(
+        &mut self,
+        write: &mut dyn FnMut(&[u16]) -> Result<(), TiffError>,
+    ) -> Result<WriteStats, TiffError> {
+        write(self.data)?;
+        Ok(WriteStats {
+            min: self.data.iter().copied().min().unwrap_or(0) as f64,
+            max: self.data.iter().copied().max().unwrap_or(0) as f64,
+            scale: 1.0,
+            ..Default::default()
+        })
+    }
+
+    fn levels(&self) -> Option<SensorLevels> {
+        Some(self.levels)
+    }
+}
+
+/// Write uncalibrated sensor counts as a CFA DNG.
+pub(crate) fn write_sensor_dng(
+    raw: SensorRaw,
     thumb: &[u8],
-    path: &PathBuf,
+    new_dng: &Path,
     meta: &SinarIAMeta,
-) -> Result<(), TiffError> {
-    let new_dng = path.join(format!("{}.dng", meta.shutter_count));
-    println!("\tWriting DNG to {}", new_dng.display());
-    if new_dng.exists() {
-        println!("\tDNG already exists, skipping");
-        return Ok(());
+    originals: Originals,
+) -> Result<WriteStats, TiffError> {
+    info!("Writing uncalibrated DNG to {}", new_dng.display());
+    write_atomically(new_dng, |tmp| {
+        write_dng_file(raw, thumb, tmp, meta, originals)
+    })
+}
+
+/// Run `write` against a sibling temp file and rename it over `new_path` on
+/// success, so a crash never leaves a truncated file under the final name.
+pub(crate) fn write_atomically<T, E: From<io::Error>>(
+    new_path: &Path,
+    write: impl FnOnce(&Path) -> Result<T, E>,
+) -> Result<T, E> {
+    if let Some(parent) = new_path.parent() {
+        fs::create_dir_all(parent)?;
     }
-    let file = File::create(new_dng).unwrap();
+    let tmp_path = temp_path(new_path);
+    match write(&tmp_path) {
+        Ok(result) => {
+            fs::rename(&tmp_path, new_path)?;
+            Ok(result)
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path);
+            Err(e)
+        }
+    }
+}
+
+/// Write calibrated CFA samples as a DNG, embedding `originals`.
+pub(crate) fn write_1d_array_to_dng<S: Sample>(
+    image: &Array1<S>,
+    thumb: &[u8],
+    new_dng: &Path,
+    meta: &SinarIAMeta,
+    originals: Originals,
+) -> Result<WriteStats, TiffError> {
+    info!("Writing DNG to {}", new_dng.display());
+    write_atomically(new_dng, |tmp| {
+        write_dng_file(DngRaw::Cfa(image), thumb, tmp, meta, originals)
+    })
+}
+
+/// Write demosaiced, interleaved camera RGB as a LinearRaw DNG.
+pub(crate) fn write_linear_dng(
+    rgb: &Array1<f64>,
+    thumb: &[u8],
+    new_dng: &Path,
+    meta: &SinarIAMeta,
+    originals: Originals,
+) -> Result<WriteStats, TiffError> {
+    info!("Writing LinearRaw DNG to {}", new_dng.display());
+    write_atomically(new_dng, |tmp| {
+        write_dng_file(DngRaw::LinearRaw(rgb), thumb, tmp, meta, originals)
+    })
+}
+
+/// The UniqueCameraModel of a frame's DNG, which camera profiles name.
+pub fn unique_camera_model(meta: &SinarIAMeta) -> String {
+    format!("{} on {}", meta.model, meta.camera)
+}
+
+pub(crate) fn write_dng_file<R: RawStrips>(
+    mut raw: R,
+    thumb: &[u8],
+    path: &Path,
+    meta: &SinarIAMeta,
+    originals: Originals,
+) -> Result<WriteStats, TiffError> {
+    let file = File::create(path)?;
     let mut output = BufWriter::new(file);
     let mut dng = TiffWriter::new(&mut output).unwrap();
     let mut root_ifd = dng.new_directory();
-    let wb_coeff_tup = estimate_white_balance(&image, meta.width as usize, E75_CFA);
-    println!(
-        "\tWhite balance: R: {}, G: {}, B: {}",
+    let wb_coeff_tup = raw.white_balance(meta)?;
+    debug!(
+        "White balance: R: {}, G: {}, B: {}",
         wb_coeff_tup.0, wb_coeff_tup.1, wb_coeff_tup.2
     );
-    let wb_coeff = vec![wb_coeff_tup.0, wb_coeff_tup.1, wb_coeff_tup.2];
+    let wb_coeff = [wb_coeff_tup.0, wb_coeff_tup.1, wb_coeff_tup.2];
     root_ifd.add_tag(
         TiffCommonTag::PhotometricInt,
         PhotometricInterpretation::RGB,
     )?;
     root_ifd.add_tag(TiffCommonTag::NewSubFileType, Value::Long(vec![1]))?;
 
-    root_ifd.add_tag(TiffCommonTag::Orientation, 7_u16)?;
+    root_ifd.add_tag(TiffCommonTag::Orientation, meta.orientation)?;
     //356x476 thumbnail
     root_ifd.add_tag(TiffCommonTag::ImageWidth, THUMB_WD)?;
     root_ifd.add_tag(TiffCommonTag::ImageLength, THUMB_HT)?;
@@ -123,11 +558,11 @@ pub(crate) fn write_1d_array_to_dng(
     root_ifd.add_tag(TiffCommonTag::SampleFormat, [1_u16, 1, 1])?;
     root_ifd.add_tag(TiffCommonTag::SamplesPerPixel, 3_u16)?;
 
-    let offset = root_ifd.write_data(&thumb)?;
+    let offset = root_ifd.write_data(thumb)?;
 
     root_ifd.add_tag(TiffCommonTag::StripOffsets, offset)?;
     root_ifd.add_tag(TiffCommonTag::StripByteCounts, thumb.len() as u32)?;
-    root_ifd.add_tag(TiffCommonTag::RowsPerStrip, 476 as u16)?;
+    root_ifd.add_tag(TiffCommonTag::RowsPerStrip, 476_u16)?;
 
     root_ifd.add_tag(DngTag::AsShotNeutral, &wb_coeff[..])?;
 
@@ -136,43 +571,114 @@ pub(crate) fn write_1d_array_to_dng(
     root_ifd.add_tag(DngTag::DNGBackwardVersion, &DNG_VERSION_V1_1[..])?;
     root_ifd.add_tag(TiffCommonTag::Model, meta.model.as_str())?;
     root_ifd.add_tag(TiffCommonTag::Make, meta.camera.as_str())?;
-    let uq_model = format!("{} on {}", meta.model, meta.camera);
-    root_ifd.add_tag(DngTag::UniqueCameraModel, uq_model.as_str())?;
     root_ifd.add_tag(
-        ExifTag::ModifyDate,
-        chrono::Local::now().format("%Y:%m:%d %H:%M:%S").to_string(),
-    )?;
-    root_ifd.add_tag(DngTag::CalibrationIlluminant1, u16::from(Illuminant::D65))?;
-    root_ifd.add_tag(
-        DngTag::ColorMatrix1,
-        matrix_to_tiff_value(
-            &vec![
-                2.0413690, -0.5649464, -0.3446944, -0.9692660, 1.8760108, 0.0415560, 0.0134474,
-                -0.1183897, 1.0154096,
-            ],
-            10_000,
-        )
-        .as_slice(),
+        DngTag::UniqueCameraModel,
+        unique_camera_model(meta).as_str(),
     )?;
+    root_ifd.add_tag(DngTag::CameraSerialNumber, meta.serial.as_str())?;
+    let modified = match &meta.captured {
+        Some(captured) => captured.exif_datetime(),
+        None => chrono::Local::now().format("%Y:%m:%d %H:%M:%S").to_string(),
+    };
+    root_ifd.add_tag(ExifTag::ModifyDate, modified)?;
+    match &meta.profile {
+        Some(profile) => profile.write(&mut root_ifd)?,
+        None => write_colour(&mut root_ifd, meta)?,
+    }
 
     let mut r_ifd = root_ifd.new_directory();
-    write_dng_data(&mut r_ifd, meta, image)?;
+    let (raw_digest, mut stats) = write_dng_data(&mut r_ifd, meta, &mut raw)?;
+    stats.white_balance = wb_coeff_tup;
     let r_off = r_ifd.build()?;
-    let mut sub_ifds = Vec::new();
-    sub_ifds.push(r_off);
+    if let Some(profile) = noise_profile(meta, raw.levels(), &stats) {
+        let baseline = noise::baseline_noise(&profile, meta.iso);
+        root_ifd.add_tag(DngTag::BaselineNoise, Rational::new_f64(baseline, 10_000))?;
+        // Nothing is known of how sharp the optics and sensor render, so
+        // ask for the reference camera's sharpening.
+        root_ifd.add_tag(DngTag::BaselineSharpness, Rational::new(1, 1))?;
+    }
+    root_ifd.add_tag(DngTag::RawImageDigest, &raw_digest[..])?;
+    // Unscaled data needs no SCAL record to be restored.
+    let scaled = raw.levels().is_none().then_some(&stats);
+    root_ifd.add_tag(
+        DngTag::DNGPrivateData,
+        &private_data(meta, scaled, &originals.refs)[..],
+    )?;
+    root_ifd.add_tag(DngTag::MakerNoteSafety, 1_u16)?;
+    root_ifd.add_tag(TiffCommonTag::Xmp, xmp::packet(meta).as_bytes())?;
+    if let Some(ia) = originals.ia {
+        // Handed over rather than copied: the compressed IA can be large.
+        root_ifd.add_tag(DngTag::OriginalRawFileName, ia.name.as_str())?;
+        root_ifd.add_tag(DngTag::OriginalRawFileDigest, &ia.digest()[..])?;
+        root_ifd.add_tag_undefined(DngTag::OriginalRawFileData, ia.data)?;
+    }
+    let sub_ifds = vec![r_off];
     write_exif_data(&mut root_ifd, meta)?;
     root_ifd.add_tag(TiffCommonTag::SubIFDs, &sub_ifds)?;
     let dng_off = root_ifd.build()?;
     dng.build(dng_off)?;
+    output.flush()?;
+    output.get_ref().sync_all()?;
+
+    Ok(stats)
+}
+
+/// ColorMatrix for backs without a calibration: XYZ to Adobe RGB (1998)
+/// under D65, as if the camera's primaries were those.
+pub(crate) const PLACEHOLDER_COLOR_MATRIX: [f64; 9] = [
+    2.0413690, -0.5649464, -0.3446944, -0.9692660, 1.8760108, 0.0415560, 0.0134474, -0.1183897,
+    1.0154096,
+];
 
+// The back's colour matrices from the calibration database, or the
+// placeholder.
+fn write_colour(root_ifd: &mut DirectoryWriter, meta: &SinarIAMeta) -> Result<(), TiffError> {
+    let colour = meta
+        .back
+        .as_ref()
+        .map(|back| back.dng_colour())
+        .unwrap_or_default();
+    if colour.is_empty() {
+        root_ifd.add_tag(DngTag::CalibrationIlluminant1, u16::from(Illuminant::D65))?;
+        root_ifd.add_tag(
+            DngTag::ColorMatrix1,
+            matrix_to_tiff_value(&PLACEHOLDER_COLOR_MATRIX, 10_000).as_slice(),
+        )?;
+        return Ok(());
+    }
+    let tags = [
+        (
+            DngTag::CalibrationIlluminant1,
+            DngTag::ColorMatrix1,
+            DngTag::ForwardMatrix1,
+        ),
+        (
+            DngTag::CalibrationIlluminant2,
+            DngTag::ColorMatrix2,
+            DngTag::ForwardMatrix2,
+        ),
+    ];
+    for (calibration, (illuminant, color_matrix, forward_matrix)) in colour.iter().zip(tags) {
+        root_ifd.add_tag(illuminant, calibration.illuminant.light_source())?;
+        root_ifd.add_tag(
+            color_matrix,
+            matrix_to_tiff_value(&calibration.color_matrix, 10_000).as_slice(),
+        )?;
+        if let Some(forward) = &calibration.forward_matrix {
+            root_ifd.add_tag(
+                forward_matrix,
+                matrix_to_tiff_value(forward, 10_000).as_slice(),
+            )?;
+        }
+    }
     Ok(())
 }
 
 pub(crate) fn write_dng_data(
     r_ifd: &mut DirectoryWriter,
     meta: &SinarIAMeta,
-    image: &ndarray::ArrayBase<OwnedRepr<f64>, Dim<[usize; 1]>>,
-) -> Result<(), TiffError> {
+    raw: &mut dyn RawStrips,
+) -> Result<([u8; 16], WriteStats), TiffError> {
     let full_size = Rect::new(
         Point::new(0, 0),
         Dim2::new(meta.width as usize, meta.height as usize),
@@ -180,37 +686,113 @@ pub(crate) fn write_dng_data(
     r_ifd.add_tag(TiffCommonTag::NewSubFileType, Value::Long(vec![0]))?;
     r_ifd.add_tag(DngTag::ActiveArea, rect_to_dng_area(&full_size))?;
     r_ifd.add_tag(ExifTag::PlanarConfiguration, 1_u16)?;
-    r_ifd.add_tag(
-        TiffCommonTag::PhotometricInt,
-        PhotometricInterpretation::CFA,
-    )?;
-    r_ifd.add_tag(TiffCommonTag::ImageWidth, meta.width as u32)?;
-    r_ifd.add_tag(TiffCommonTag::ImageLength, meta.height as u32)?;
-    r_ifd.add_tag(TiffCommonTag::SamplesPerPixel, 1_u16)?;
-    r_ifd.add_tag(TiffCommonTag::BitsPerSample, [16_u16])?;
-    r_ifd.add_tag(DngTag::CFALayout, 1_u16)?;
-    r_ifd.add_tag(TiffCommonTag::CFAPattern, E75_CFA)?;
-    r_ifd.add_tag(TiffCommonTag::CFARepeatPatternDim, [2u16, 2u16])?;
-    r_ifd.add_tag(DngTag::CFAPlaneColor, [0u8, 1u8, 2u8])?;
+    r_ifd.add_tag(TiffCommonTag::ImageWidth, meta.width)?;
+    r_ifd.add_tag(TiffCommonTag::ImageLength, meta.height)?;
+    r_ifd.add_tag(TiffCommonTag::Orientation, meta.orientation)?;
+    match raw.samples() {
+        1 => {
+            r_ifd.add_tag(
+                TiffCommonTag::PhotometricInt,
+                PhotometricInterpretation::CFA,
+            )?;
+            r_ifd.add_tag(TiffCommonTag::SamplesPerPixel, 1_u16)?;
+            r_ifd.add_tag(TiffCommonTag::BitsPerSample, [16_u16])?;
+            r_ifd.add_tag(DngTag::CFALayout, 1_u16)?;
+            r_ifd.add_tag(TiffCommonTag::CFAPattern, meta.cfa_pattern())?;
+            r_ifd.add_tag(TiffCommonTag::CFARepeatPatternDim, [2u16, 2u16])?;
+            r_ifd.add_tag(DngTag::CFAPlaneColor, [0u8, 1u8, 2u8])?;
+            if let Some(gain_map) = &meta.gain_map {
+                r_ifd.add_tag_undefined(DngTag::OpcodeList2, gain_map.opcode_list())?;
+            }
+            if let Some(back) = meta.back.as_ref().filter(|b| !b.defects.is_empty()) {
+                r_ifd.add_tag_undefined(
+                    DngTag::OpcodeList1,
+                    backs::fix_bad_pixels_opcode(&back.defects, meta.cfa_pattern()),
+                )?;
+            }
+        }
+        _ => {
+            r_ifd.add_tag(
+                TiffCommonTag::PhotometricInt,
+                PhotometricInterpretation::LinearRaw,
+            )?;
+            r_ifd.add_tag(TiffCommonTag::SamplesPerPixel, 3_u16)?;
+            r_ifd.add_tag(TiffCommonTag::BitsPerSample, [16_u16, 16, 16])?;
+        }
+    }
+    if let Some(levels) = raw.levels() {
+        r_ifd.add_tag(DngTag::BlackLevelRepeatDim, [2u16, 2u16])?;
+        let black: Vec<Rational> = levels
+            .black
+            .iter()
+            .map(|b| Rational::new((b * 100.0).round() as u32, 100))
+            .collect();
+        r_ifd.add_tag(DngTag::BlackLevel, &black[..])?;
+        r_ifd.add_tag(DngTag::WhiteLevel, levels.white as u32)?;
+    }
     r_ifd.add_tag(TiffCommonTag::Compression, CompressionMethod::None)?;
     let mut strip_offsets: Vec<u32> = Vec::new();
     let mut strip_sizes: Vec<u32> = Vec::new();
     let mut strip_rows: Vec<u32> = Vec::new();
-    let rows_per_strip = meta.height / 1;
-    let u16_image = scale_1d_f64_u16(image);
-    for strip in u16_image
-        .chunks((rows_per_strip * meta.width) as usize)
-        .into_iter()
-    {
+    let row_len = meta.width as usize * raw.samples() as usize;
+    let mut digest = md5::Context::new();
+    let stats = raw.write_strips(&mut |strip| {
         let offset = r_ifd.write_data_u16_be(strip)?;
+        digest_samples(&mut digest, strip);
         strip_offsets.push(offset);
-        strip_sizes.push((strip.len() * size_of::<u16>()) as u32);
-        strip_rows.push((strip.len() / meta.width as usize) as u32);
+        strip_sizes.push(std::mem::size_of_val(strip) as u32);
+        strip_rows.push((strip.len() / row_len) as u32);
+        Ok(())
+    })?;
+    if let Some(profile) = noise_profile(meta, raw.levels(), &stats) {
+        r_ifd.add_tag(DngTag::NoiseProfile, &profile[..])?;
     }
     r_ifd.add_tag(TiffCommonTag::StripOffsets, &strip_offsets)?;
     r_ifd.add_tag(TiffCommonTag::StripByteCounts, &strip_sizes)?;
-    r_ifd.add_tag(TiffCommonTag::RowsPerStrip, &strip_rows)?;
-    Ok(())
+    // Every strip but the last is full.
+    r_ifd.add_tag(
+        TiffCommonTag::RowsPerStrip,
+        strip_rows.first().copied().unwrap_or(meta.height),
+    )?;
+    Ok((digest.compute().0, stats))
+}
+
+// RawImageDigest is the MD5 of the samples in little-endian order, whatever
+// order the file stores them in.
+fn digest_samples(digest: &mut md5::Context, samples: &[u16]) {
+    let mut bytes = [0u8; 8192];
+    for chunk in samples.chunks(bytes.len() / 2) {
+        for (le, sample) in bytes.chunks_exact_mut(2).zip(chunk) {
+            le.copy_from_slice(&sample.to_le_bytes());
+        }
+        digest.consume(&bytes[..chunk.len() * 2]);
+    }
+}
+
+// The frame's noise profile for the data written: unscaled counts above
+// `levels`, or calibrated samples scaled by `stats`.
+fn noise_profile(
+    meta: &SinarIAMeta,
+    levels: Option<SensorLevels>,
+    stats: &WriteStats,
+) -> Option<[f64; 6]> {
+    let noise = meta.noise?;
+    Some(match levels {
+        Some(levels) => {
+            let cfa = meta.cfa_pattern();
+            let mut black = [(0.0, 0); 3];
+            for (position, &level) in levels.black.iter().enumerate() {
+                let colour = &mut black[cfa[position] as usize];
+                *colour = (colour.0 + level, colour.1 + 1);
+            }
+            let k = black.map(|(sum, n)| {
+                let black = if n > 0 { sum / n as f64 } else { 0.0 };
+                1.0 / (levels.white as f64 - black)
+            });
+            noise.profile(k, [0.0; 3])
+        }
+        None => noise.profile([stats.scale / u16::MAX as f64; 3], [stats.min; 3]),
+    })
 }
 
 pub(crate) fn write_exif_data(
@@ -219,8 +801,8 @@ pub(crate) fn write_exif_data(
 ) -> Result<(), TiffError> {
     let exif_offset = {
         let mut exif_ifd = root_ifd.new_directory();
-        // Add EXIF version 0220
-        exif_ifd.add_tag_undefined(ExifTag::ExifVersion, vec![48, 50, 50, 48])?;
+        // EXIF version 0230
+        exif_ifd.add_tag_undefined(ExifTag::ExifVersion, b"0230".to_vec())?;
         fill_exif_ifd(&mut exif_ifd, meta)?;
         exif_ifd.build()?
     };
@@ -228,26 +810,375 @@ pub(crate) fn write_exif_data(
     Ok(())
 }
 
+/// EXIF ImageUniqueID: stable for a frame, since a back never repeats a
+/// shutter count.
+pub(crate) fn image_unique_id(meta: &SinarIAMeta) -> String {
+    format!(
+        "{:X}",
+        md5::compute(format!("{}:{}", meta.serial, meta.shutter_count))
+    )
+}
+
 pub(crate) fn fill_exif_ifd(
     exif_ifd: &mut rawler::formats::tiff::DirectoryWriter,
     meta: &SinarIAMeta,
 ) -> Result<(), TiffError> {
-    exif_ifd.add_tag(ExifTag::FNumber, Rational::new_f32(meta.f_stop, 10_000))?;
-    //exif_ifd.add_tag(ExifTag::ApertureValue, Rational::new_f32(meta.f_stop, 10_000))?;
-    exif_ifd.add_tag(
-        ExifTag::ISOSpeedRatings,
-        Value::Short(vec![meta.iso as u16]),
-    )?;
-    exif_ifd.add_tag(
-        ExifTag::FocalLength,
-        Rational::new_f32(meta.focal_length, 10_00),
-    )?;
-    //add serial number
+    // The back records the aperture set on the lens.
+    if meta.f_stop > 0.0 {
+        exif_ifd.add_tag(ExifTag::FNumber, Rational::new_f32(meta.f_stop, 10_000))?;
+        let apex = 2.0 * (meta.f_stop as f64).log2();
+        exif_ifd.add_tag(ExifTag::ApertureValue, Rational::new_f64(apex, 10_000))?;
+    }
+    if meta.iso > 0 {
+        exif_ifd.add_tag(
+            ExifTag::ISOSpeedRatings,
+            Value::Short(vec![meta.iso as u16]),
+        )?;
+        // The back's ISO setting is an exposure index, not a measured speed.
+        exif_ifd.add_tag(ExifTag::SensitivityType, 2_u16)?;
+        exif_ifd.add_tag(ExifTag::RecommendedExposureIndex, meta.iso)?;
+    }
+    if meta.focal_length > 0.0 {
+        exif_ifd.add_tag(
+            ExifTag::FocalLength,
+            Rational::new_f32(meta.focal_length, 10_00),
+        )?;
+    }
+    // Without a known lens, LensModel is left out rather than made up from
+    // the focal length.
+    if let Some(lens) = &meta.lens {
+        if let Some(make) = &lens.make {
+            exif_ifd.add_tag(ExifTag::LensMake, make.as_str())?;
+        }
+        exif_ifd.add_tag(ExifTag::LensModel, lens.model.as_str())?;
+        // Unknown values are written as 0/0.
+        let specification: Vec<Rational> = lens
+            .specification()
+            .iter()
+            .map(|&v| match v {
+                v if v > 0.0 => Rational::new_f32(v, 10_000),
+                _ => Rational::new(0, 0),
+            })
+            .collect();
+        exif_ifd.add_tag(ExifTag::LensSpecification, &specification[..])?;
+    }
+    // BodySerialNumber (0xA431)
     exif_ifd.add_tag(ExifTag::SerialNumber, meta.serial.clone())?;
-    //add shutter time
-    exif_ifd.add_tag(
-        ExifTag::ExposureTime,
-        Rational::new(meta.measured_shutter_us, 1_000_000),
-    )?;
+    exif_ifd.add_tag(ExifTag::ImageUniqueID, image_unique_id(meta))?;
+    exif_ifd.add_tag(ExifTag::ImageNumber, meta.shutter_count)?;
+    // ExposureTime is the shutter time the back measured, ShutterSpeedValue
+    // the one that was asked for.
+    if meta.measured_shutter_us > 0 {
+        exif_ifd.add_tag(
+            ExifTag::ExposureTime,
+            Rational::new(meta.measured_shutter_us, 1_000_000),
+        )?;
+    }
+    if meta.req_shutter_us > 0 {
+        let apex = -(meta.req_shutter_us as f64 / 1_000_000.0).log2();
+        exif_ifd.add_tag(ExifTag::ShutterSpeedValue, SRational::new_f64(apex, 10_000))?;
+    }
+    exif_ifd.add_tag(ExifTag::LightSource, meta.white_balance_name.light_source())?;
+    exif_ifd.add_tag(ExifTag::WhiteBalance, meta.white_balance_name.exif_mode())?;
+    // Raw sensor data is in no particular colour space.
+    exif_ifd.add_tag(ExifTag::ColorSpace, 0xFFFF_u16)?;
+    if let Some(captured) = &meta.captured {
+        let datetime = captured.exif_datetime();
+        exif_ifd.add_tag(ExifTag::DateTimeOriginal, datetime.as_str())?;
+        exif_ifd.add_tag(ExifTag::CreateDate, datetime.as_str())?;
+        if let Some(subsec) = captured.exif_subsec() {
+            exif_ifd.add_tag(ExifTag::SubSecTime, subsec.as_str())?;
+            exif_ifd.add_tag(ExifTag::SubSecTimeOriginal, subsec.as_str())?;
+            exif_ifd.add_tag(ExifTag::SubSecTimeDigitized, subsec.as_str())?;
+        }
+        let offset = captured.exif_offset();
+        exif_ifd.add_tag(ExifTag::OffsetTime, offset.as_str())?;
+        exif_ifd.add_tag(ExifTag::OffsetTimeOriginal, offset.as_str())?;
+        exif_ifd.add_tag(ExifTag::OffsetTimeDigitized, offset.as_str())?;
+    }
     Ok(())
 }
+
+/// A small Hy6 frame with the exposure recorded in META, for tests.
+#[cfg(test)]
+pub(crate) fn test_meta() -> SinarIAMeta {
+    use crate::orientation;
+    use crate::sinar_ia::{WhiteBalance, HY6_CAMERA};
+    SinarIAMeta {
+        shutter_count: 1234,
+        camera: HY6_CAMERA.to_string(),
+        measured_shutter_us: 8000,
+        req_shutter_us: 8000,
+        f_stop: 5.6,
+        iso: 50,
+        serial: "e75-0042".to_string(),
+        model: "Emotion 75".to_string(),
+        width: 4,
+        height: 2,
+        white_balance_name: WhiteBalance::Flash,
+        focal_length: 80.0,
+        orientation: orientation::HY6,
+        ..SinarIAMeta::default()
+    }
+}
+
+/// IFD0, the raw IFD and the EXIF IFD of a DNG written by `write_test_dng`.
+#[cfg(test)]
+pub(crate) struct TestDng {
+    pub root: crate::tiffread::Ifd,
+    pub raw: crate::tiffread::Ifd,
+    pub exif: crate::tiffread::Ifd,
+}
+
+/// Write a 4x2 frame of the samples 0 to 7 with `meta`, which must be 4x2
+/// too, as a CFA DNG at `path`, and read it back.
+#[cfg(test)]
+pub(crate) fn write_test_dng(meta: &SinarIAMeta, path: &Path) -> TestDng {
+    let image = Array1::from_vec((0..8).map(|v| v as f64).collect());
+    write_1d_array_to_dng(&image, &[0; 12], path, meta, Originals::default()).unwrap();
+    let mut tiff = TiffReader::open(path).unwrap();
+    let root = tiff.read_ifd(tiff.first_ifd).unwrap();
+    let raw = tiff
+        .read_ifd(root.get(TAG_SUBIFDS).unwrap().as_u32s()[0])
+        .unwrap();
+    let exif = tiff
+        .read_ifd(root.get(34665).unwrap().as_u32s()[0])
+        .unwrap();
+    TestDng { root, raw, exif }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{self, CaptureTime, TimeSource};
+    use crate::orientation;
+    use crate::pwad::{self, Pwad};
+    use crate::sinar_ia::META_KEY;
+    use crate::xmp::Annotations;
+
+    #[test]
+    fn test_private_data_round_trip() {
+        let meta = SinarIAMeta {
+            meta_lump: (0..=255).collect(),
+            ..SinarIAMeta::default()
+        };
+        let stats = WriteStats {
+            min: -12.5,
+            max: 4000.0,
+            ..Default::default()
+        };
+        let data = private_data(&meta, Some(&stats), &[]);
+        let records = parse_private_data(&data).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0], (PRIVATE_META, &meta.meta_lump[..]));
+        assert_eq!(records[1].0, PRIVATE_SCALED);
+        assert_eq!(records[1].1[..8], (-12.5f64).to_be_bytes());
+        assert!(parse_private_data(&data[..data.len() - 1]).is_none());
+        assert!(parse_private_data(b"Adobe\0MakN").is_none());
+
+        // The restored META lump survives a PWAD round trip.
+        let path = crate::test_dir("restore").join("FRAME.IA");
+        pwad::write_pwad(
+            &path,
+            &[(META_KEY, meta.meta_lump.clone()), ("THUMB", vec![7; 5])],
+        )
+        .unwrap();
+        let pwad = Pwad::from_file(&path).unwrap();
+        assert_eq!(pwad.read_lump_by_tag(META_KEY).unwrap(), meta.meta_lump);
+        assert_eq!(pwad.read_lump_by_tag("THUMB").unwrap(), vec![7; 5]);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_uncalibrated_passthrough() {
+        let (width, height) = (6, 4);
+        // Dark levels differ by CFA position.
+        let dark: Vec<u16> = (0..width * height)
+            .map(|i| 100 + 10 * (((i / width) & 1) << 1 | (i % width) & 1) as u16)
+            .collect();
+        let levels = SensorLevels::from_dark(&dark, width);
+        assert_eq!(levels.black, [100.0, 110.0, 120.0, 130.0]);
+        assert_eq!(levels.white, u16::MAX);
+
+        let raw: Vec<u16> = (0..(width * height) as u16).map(|v| v * 997).collect();
+        let mut sensor = SensorRaw { data: &raw, levels };
+        let mut written = Vec::new();
+        let stats = sensor
+            .write_strips(&mut |strip| {
+                written.extend_from_slice(strip);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(written, raw);
+        assert_eq!(stats.scale, 1.0);
+        assert_eq!(sensor.levels(), Some(levels));
+
+        // Nothing was rescaled, so only the META record is kept.
+        let data = private_data(&SinarIAMeta::default(), None, &[]);
+        let records = parse_private_data(&data).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].0, PRIVATE_META);
+    }
+
+    #[test]
+    fn test_raw_image_digest() {
+        let meta = test_meta();
+        let raw: Vec<u16> = (0..8).map(|v| v * 997).collect();
+        let levels = SensorLevels {
+            black: [0.0; 4],
+            white: u16::MAX,
+        };
+        let path = crate::test_dir("digest").join("FRAME.dng");
+        write_sensor_dng(
+            SensorRaw { data: &raw, levels },
+            &[0; 12],
+            &path,
+            &meta,
+            Originals::default(),
+        )
+        .unwrap();
+        // The MD5 of the samples as little-endian bytes, as the DNG SDK
+        // computes it, although they are stored big-endian.
+        let mut tiff = TiffReader::open(&path).unwrap();
+        let root = tiff.read_ifd(tiff.first_ifd).unwrap();
+        let expected = [
+            0xf2, 0x01, 0xdc, 0x0c, 0x40, 0x13, 0xd5, 0xc9, 0x29, 0xe1, 0x2d, 0xf8, 0x95, 0x33,
+            0x08, 0x6b,
+        ];
+        assert_eq!(root.get(TAG_RAW_IMAGE_DIGEST).unwrap().data, expected);
+        verify_dng(&path, &meta, 1).unwrap();
+
+        // A changed sample no longer matches.
+        let raw_ifd = tiff
+            .read_ifd(root.get(TAG_SUBIFDS).unwrap().as_u32s()[0])
+            .unwrap();
+        let strip = raw_ifd.get(TAG_STRIP_OFFSETS).unwrap().as_u32s()[0] as usize;
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[strip + 3] ^= 1;
+        fs::write(&path, bytes).unwrap();
+        assert!(verify_dng(&path, &meta, 1).is_err());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_on_exist() {
+        let meta = test_meta();
+        let dir = crate::test_dir("on-exist");
+        let path = dir.join("FRAME.dng");
+        let resolve = |on_exist| {
+            let options = ConvertOptions {
+                on_exist,
+                ..ConvertOptions::default()
+            };
+            resolve_output(&path, &meta, &options)
+        };
+        let policies = [
+            OnExist::Skip,
+            OnExist::Overwrite,
+            OnExist::Verify,
+            OnExist::Rename,
+        ];
+        for on_exist in policies {
+            assert_eq!(resolve(on_exist), Some(path.clone()));
+        }
+
+        // A truncated DNG is only kept by Skip.
+        fs::write(&path, b"II*\0").unwrap();
+        assert_eq!(resolve(OnExist::Skip), None);
+        assert_eq!(resolve(OnExist::Overwrite), Some(path.clone()));
+        assert_eq!(resolve(OnExist::Verify), Some(path.clone()));
+        assert_eq!(resolve(OnExist::Rename), Some(dir.join("FRAME_1.dng")));
+        fs::write(dir.join("FRAME_1.dng"), b"").unwrap();
+        assert_eq!(resolve(OnExist::Rename), Some(dir.join("FRAME_2.dng")));
+
+        // A complete one passes verification.
+        let raw: Vec<u16> = (0..8).collect();
+        let levels = SensorLevels {
+            black: [0.0; 4],
+            white: u16::MAX,
+        };
+        write_sensor_dng(
+            SensorRaw { data: &raw, levels },
+            &[0; 12],
+            &path,
+            &meta,
+            Originals::default(),
+        )
+        .unwrap();
+        assert_eq!(resolve(OnExist::Verify), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let dir = crate::test_dir("atomic");
+        let path = dir.join("nested/FRAME.dng");
+        let tmp = temp_path(&path);
+
+        // A failed write removes its temp file and leaves any old output as it was.
+        let failed: io::Result<()> = write_atomically(&path, |tmp| {
+            fs::write(tmp, b"partial")?;
+            Err(io::Error::other("disk full"))
+        });
+        assert!(failed.is_err());
+        assert!(!tmp.exists() && !path.exists());
+        fs::write(&path, b"old").unwrap();
+        let failed: io::Result<()> = write_atomically(&path, |tmp| {
+            fs::write(tmp, b"partial")?;
+            Err(io::Error::other("disk full"))
+        });
+        assert!(failed.is_err());
+        assert!(!tmp.exists());
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+
+        let written = write_atomically(&path, |tmp| fs::write(tmp, b"new").map(|()| 3));
+        assert_eq!(written.unwrap(), 3);
+        assert!(!tmp.exists());
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_exif_readback() {
+        let mut meta = test_meta();
+        meta.captured = Some(CaptureTime {
+            local: chrono::NaiveDate::from_ymd_opt(2008, 6, 12)
+                .unwrap()
+                .and_hms_milli_opt(11, 30, 45, 250)
+                .unwrap(),
+            offset: capture::parse_timezone("+02:00").unwrap(),
+            source: TimeSource::Modified,
+        });
+        let dir = crate::test_dir("exif");
+        let TestDng { root, exif, .. } = write_test_dng(&meta, &dir.join("frame.dng"));
+        assert_eq!(root.get(306).unwrap().as_string(), "2008:06:12 11:30:45");
+        assert_eq!(root.get(274).unwrap().as_u32s(), [orientation::HY6 as u32]);
+        assert_eq!(root.get(50735).unwrap().as_string(), "e75-0042");
+        let packet = String::from_utf8_lossy(&root.get(700).unwrap().data).to_string();
+        assert_eq!(xmp::parse_sidecar(&packet).unwrap(), Annotations::default());
+        assert!(packet.contains("<xmp:CreateDate>2008-06-12T11:30:45.250+02:00<"));
+        let number = |tag: u16| exif.get(tag).unwrap().as_f64s()[0];
+        let text = |tag: u16| exif.get(tag).unwrap().as_string();
+        assert_eq!(text(36864), "0230");
+        assert!((number(33437) - 5.6).abs() < 1e-3);
+        assert!((number(37378) - 2.0 * 5.6f64.log2()).abs() < 1e-3);
+        assert!((number(33434) - 0.008).abs() < 1e-9);
+        assert!((number(37377) + 0.008f64.log2()).abs() < 1e-3);
+        assert_eq!(number(34855), 50.0);
+        assert_eq!(number(34866), 50.0);
+        assert_eq!(number(37386), 80.0);
+        assert!(exif.get(42036).is_none());
+        assert!(exif.get(34850).is_none());
+        assert_eq!(text(42033), "e75-0042");
+        assert_eq!(text(42016), image_unique_id(&meta));
+        assert_eq!(text(42016).len(), 32);
+        assert_eq!(number(37393), 1234.0);
+        assert_eq!(number(37384), 4.0);
+        assert_eq!(number(41987), 1.0);
+        assert_eq!(text(36867), "2008:06:12 11:30:45");
+        assert_eq!(text(36868), "2008:06:12 11:30:45");
+        assert_eq!(text(37521), "250");
+        assert_eq!(text(36881), "+02:00");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
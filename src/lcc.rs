@@ -0,0 +1,425 @@
+/*
+Lens cast calibration (LCC).
+
+A WR reference corrects the sensor's own non-uniformity, but not the
+vignetting and colour cast that a wide or shifted lens adds. An LCC frame is
+shot through a diffuser with the same lens and movements as the frames that
+follow it. It is calibrated like any other frame and reduced, per CFA
+position, to a coarse grid of gains that flatten it relative to its centre.
+
+Frames get the correction of the last LCC frame before them from the same
+back, by shutter count. It is either baked into the flat-field gains, or
+written to CFA DNGs as one GainMap opcode per CFA position (OpcodeList2,
+DNG 1.3) for the raw converter to apply, which leaves the samples untouched.
+*/
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::warn;
+use rayon::prelude::{IndexedParallelIterator, ParallelIterator, ParallelSliceMut};
+
+use crate::calibrate::{self, Sample};
+use crate::iadng;
+use crate::pwad;
+use crate::refcache::RefCache;
+use crate::sinar_ia::{SinarIAMeta, META_KEY, RAW_KEY};
+
+/// Grid spacing, in samples of one CFA position.
+pub const STEP: usize = 64;
+/// Gains are kept within [1/MAX_GAIN, MAX_GAIN].
+pub const MAX_GAIN: f64 = 16.0;
+
+const GAIN_MAP_ID: u32 = 9;
+
+/// How the correction reaches the output.
+#[derive(Debug, PartialEq, Clone, Copy, Default, clap::ValueEnum)]
+pub enum LccApply {
+    /// Scale the calibrated samples
+    #[default]
+    Bake,
+    /// Write DNG GainMap opcodes; CFA DNG output only
+    #[value(name = "gainmap")]
+    GainMap,
+}
+
+/// A frame marked as an LCC frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LccFrame {
+    pub path: PathBuf,
+    pub serial: String,
+    pub shutter_count: u32,
+}
+
+/// LCC frames for a run.
+#[derive(Debug, Clone, Default)]
+pub struct LccOptions {
+    pub frames: Vec<LccFrame>,
+    pub apply: LccApply,
+}
+
+impl LccOptions {
+    /// Mark the IA files at `paths` as LCC frames.
+    pub fn mark(paths: &[PathBuf], apply: LccApply) -> io::Result<Self> {
+        let mut frames = Vec::with_capacity(paths.len());
+        for path in paths {
+            let meta = SinarIAMeta::from_ia(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+            frames.push(LccFrame {
+                path: path.clone(),
+                serial: meta.serial,
+                shutter_count: meta.shutter_count,
+            });
+        }
+        Ok(LccOptions { frames, apply })
+    }
+
+    /// The LCC frame for `meta`: the last one taken before it on the same
+    /// back.
+    pub fn for_frame(&self, meta: &SinarIAMeta) -> Option<&LccFrame> {
+        self.frames
+            .iter()
+            .filter(|l| l.serial == meta.serial && l.shutter_count < meta.shutter_count)
+            .max_by_key(|l| l.shutter_count)
+    }
+}
+
+/// Read a list of LCC frames: one IA path per line, relative to the list's
+/// folder. Blank lines and lines starting with `#` are skipped.
+pub fn read_list(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let base = path.parent().unwrap_or(Path::new(""));
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| base.join(line))
+        .collect())
+}
+
+// Grid points along `extent` samples of one CFA position.
+fn points(extent: usize) -> usize {
+    extent.saturating_sub(1).div_ceil(STEP).max(1) + 1
+}
+
+// Bilinear interpolation of `value` on a `rows` x `cols` grid at fractional
+// grid coordinates.
+fn interpolate(value: impl Fn(usize) -> f64, rows: usize, cols: usize, v: f64, h: f64) -> f64 {
+    let (v0, h0) = (
+        (v.floor() as usize).min(rows - 2),
+        (h.floor() as usize).min(cols - 2),
+    );
+    let (fv, fh) = (v - v0 as f64, h - h0 as f64);
+    let at = |v, h| value(v * cols + h);
+    (at(v0, h0) * (1.0 - fh) + at(v0, h0 + 1) * fh) * (1.0 - fv)
+        + (at(v0 + 1, h0) * (1.0 - fh) + at(v0 + 1, h0 + 1) * fh) * fv
+}
+
+/// Gains that flatten an LCC frame: a `rows` x `cols` grid for each of the
+/// four CFA positions, row by row, spanning that position's samples.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LccMap {
+    pub width: usize,
+    pub height: usize,
+    pub rows: usize,
+    pub cols: usize,
+    pub gains: Arc<Vec<f32>>,
+}
+
+impl LccMap {
+    fn plane_size(width: usize, height: usize) -> (usize, usize) {
+        ((height / 2).max(1), (width / 2).max(1))
+    }
+
+    /// Grid rows and columns for a `width` x `height` frame.
+    pub fn grid(width: usize, height: usize) -> (usize, usize) {
+        let (hp, wp) = LccMap::plane_size(width, height);
+        (points(hp), points(wp))
+    }
+
+    /// Wrap gains measured for a `width` x `height` frame.
+    pub fn from_gains(gains: Arc<Vec<f32>>, width: usize, height: usize) -> io::Result<Self> {
+        let (rows, cols) = LccMap::grid(width, height);
+        if gains.len() != 4 * rows * cols {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "LCC map holds {} gains, expected {}",
+                    gains.len(),
+                    4 * rows * cols
+                ),
+            ));
+        }
+        Ok(LccMap {
+            width,
+            height,
+            rows,
+            cols,
+            gains,
+        })
+    }
+
+    /// Measure a calibrated LCC frame, `width` x `height` CFA sites. Each
+    /// grid point fits a plane to the samples within STEP/2 of it, so that
+    /// points on the frame's edges, whose windows are cut off, aren't biased
+    /// towards its centre.
+    pub fn measure(image: &[f64], width: usize, height: usize) -> Self {
+        assert_eq!(image.len(), width * height);
+        let (hp, wp) = LccMap::plane_size(width, height);
+        let (rows, cols) = LccMap::grid(width, height);
+        let half = STEP / 2;
+        let mut gains = vec![1.0f32; 4 * rows * cols];
+        gains
+            .par_chunks_mut(rows * cols)
+            .enumerate()
+            .for_each(|(plane, gains)| {
+                let (r, c) = (plane / 2, plane % 2);
+                let value = |py: usize, px: usize| {
+                    image[(2 * py + r).min(height - 1) * width + (2 * px + c).min(width - 1)]
+                };
+                let means: Vec<f64> = (0..rows * cols)
+                    .map(|i| {
+                        let y = ((i / cols) * (hp - 1)) as f64 / (rows - 1) as f64;
+                        let x = ((i % cols) * (wp - 1)) as f64 / (cols - 1) as f64;
+                        let (y, x) = (y.round() as usize, x.round() as usize);
+                        let ys = y.saturating_sub(half)..(y + half + 1).min(hp);
+                        let xs = x.saturating_sub(half)..(x + half + 1).min(wp);
+                        // The window is a rectangle, so the slopes along
+                        // each axis can be fitted separately.
+                        let centre = |range: &std::ops::Range<usize>| {
+                            (range.start + range.end - 1) as f64 / 2.0
+                        };
+                        let (cy, cx) = (centre(&ys), centre(&xs));
+                        let (mut sum, mut sum_y, mut sum_x) = (0.0, 0.0, 0.0);
+                        let (mut var_y, mut var_x) = (0.0, 0.0);
+                        for py in ys.clone() {
+                            for px in xs.clone() {
+                                let (dy, dx) = (py as f64 - cy, px as f64 - cx);
+                                let v = value(py, px);
+                                sum += v;
+                                sum_y += v * dy;
+                                sum_x += v * dx;
+                                var_y += dy * dy;
+                                var_x += dx * dx;
+                            }
+                        }
+                        let slope = |s: f64, var: f64| if var > 0.0 { s / var } else { 0.0 };
+                        let count = ys.len() * xs.len();
+                        sum / count as f64
+                            + slope(sum_y, var_y) * (y as f64 - cy)
+                            + slope(sum_x, var_x) * (x as f64 - cx)
+                    })
+                    .collect();
+                let centre = interpolate(
+                    |i| means[i],
+                    rows,
+                    cols,
+                    (rows - 1) as f64 / 2.0,
+                    (cols - 1) as f64 / 2.0,
+                );
+                for (g, &mean) in gains.iter_mut().zip(&means) {
+                    if mean > 0.0 && centre > 0.0 {
+                        *g = (centre / mean).clamp(1.0 / MAX_GAIN, MAX_GAIN) as f32;
+                    }
+                }
+            });
+        LccMap {
+            width,
+            height,
+            rows,
+            cols,
+            gains: Arc::new(gains),
+        }
+    }
+
+    /// The gain at CFA site (`row`, `col`).
+    pub fn gain(&self, row: usize, col: usize) -> f64 {
+        let (hp, wp) = LccMap::plane_size(self.width, self.height);
+        let plane = (row & 1) * 2 + (col & 1);
+        let grid = &self.gains[plane * self.rows * self.cols..][..self.rows * self.cols];
+        let v = (row / 2) as f64 * (self.rows - 1) as f64 / (hp - 1).max(1) as f64;
+        let h = (col / 2) as f64 * (self.cols - 1) as f64 / (wp - 1).max(1) as f64;
+        interpolate(|i| grid[i] as f64, self.rows, self.cols, v, h)
+    }
+
+    /// The largest gain, which baked corrections are scaled by so the
+    /// frame's samples only ever get smaller.
+    pub fn max_gain(&self) -> f64 {
+        self.gains.iter().fold(1.0f32, |m, &g| m.max(g)) as f64
+    }
+
+    /// `gains`, the flat field of a whole frame or of the rows from `row0`,
+    /// with the correction baked in.
+    pub fn bake<S: Sample>(&self, gains: &mut [S::Gain], row0: usize) {
+        let (width, scale) = (self.width, 1.0 / self.max_gain());
+        gains
+            .par_chunks_mut(width)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for (x, g) in row.iter_mut().enumerate() {
+                    *g = S::scale_gain(*g, self.gain(row0 + y, x) * scale);
+                }
+            });
+    }
+
+    /// An OpcodeList2 with a GainMap for each CFA position.
+    pub fn opcode_list(&self) -> Vec<u8> {
+        let (hp, wp) = LccMap::plane_size(self.width, self.height);
+        let mut opcodes = Vec::new();
+        for plane in 0..4 {
+            let (r, c) = (plane / 2, plane % 2);
+            let mut params = Vec::new();
+            // Area: top, left, bottom, right; plane, planes, row and column pitch.
+            for v in [r, c, self.height, self.width, 0, 1, 2, 2] {
+                params.extend_from_slice(&(v as u32).to_be_bytes());
+            }
+            params.extend_from_slice(&(self.rows as u32).to_be_bytes());
+            params.extend_from_slice(&(self.cols as u32).to_be_bytes());
+            // Spacing and origin are relative to the area.
+            let spacing_v = 2.0 * (hp - 1) as f64 / ((self.rows - 1) * (self.height - r)) as f64;
+            let spacing_h = 2.0 * (wp - 1) as f64 / ((self.cols - 1) * (self.width - c)) as f64;
+            for v in [spacing_v, spacing_h, 0.0, 0.0] {
+                params.extend_from_slice(&v.to_be_bytes());
+            }
+            params.extend_from_slice(&1u32.to_be_bytes());
+            let grid = &self.gains[plane * self.rows * self.cols..][..self.rows * self.cols];
+            for g in grid {
+                params.extend_from_slice(&g.to_be_bytes());
+            }
+            opcodes.push((GAIN_MAP_ID, params));
+        }
+        iadng::opcode_list(&opcodes)
+    }
+}
+
+/// Calibrate the LCC frame at `path` against its own BR and WR references
+/// and measure it.
+pub fn measure_frame(path: &Path, cache: &RefCache) -> io::Result<LccMap> {
+    let pwad = pwad::Pwad::from_file(path)?;
+    let meta = SinarIAMeta::process_meta(&pwad.read_lump_by_tag(META_KEY)?)?;
+    let (width, height) = (meta.width as usize, meta.height as usize);
+    if width == 0 || height == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unknown model for serial '{}'", meta.serial),
+        ));
+    }
+    let folder = path.parent().unwrap();
+    let raw = calibrate::decode_u16_le(&pwad.read_lump_by_tag(RAW_KEY)?, width, height);
+    let black = cache.dark(&folder.join(&meta.black_ref), width, height)?;
+    let white_ref = folder.join(&meta.white_ref);
+    let gains = cache
+        .gains::<f64>(&white_ref, width, height)
+        .map_err(|e| {
+            warn!(
+                "{}: LCC frame measured without a flat field: {}",
+                white_ref.display(),
+                e
+            )
+        })
+        .ok();
+    let image = calibrate::calibrate::<f64>(&raw, &black, gains.as_ref().map(|g| g.as_slice()));
+    Ok(LccMap::measure(image.as_slice().unwrap(), width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lcc() {
+        // A diffuser frame with vignetting and a colour cast towards the corners.
+        let (width, height) = (1024, 768);
+        let cast = [1.0, 0.9, 0.95, 0.8];
+        let lcc_frame = |x: usize, y: usize| {
+            let (dx, dy) = (x as f64 - 512.0, y as f64 - 384.0);
+            let r2 = (dx * dx + dy * dy) / (640.0 * 640.0);
+            let plane = (y & 1) * 2 + (x & 1);
+            1000.0 * (1.0 - 0.4 * r2) * (1.0 - (1.0 - cast[plane]) * r2)
+        };
+        let image: Vec<f64> = (0..width * height)
+            .map(|i| lcc_frame(i % width, i / width))
+            .collect();
+        let map = LccMap::measure(&image, width, height);
+        assert_eq!((map.rows, map.cols), (7, 9));
+        for y in (0..height).step_by(13) {
+            for x in (0..width).step_by(11) {
+                let plane = (y & 1) * 2 + (x & 1);
+                let centre = lcc_frame(512 + (x & 1), 384 + (y & 1));
+                let flat = image[y * width + x] * map.gain(y, x) / centre;
+                assert!(
+                    (flat - 1.0).abs() < 0.03,
+                    "{} at {},{} plane {}",
+                    flat,
+                    x,
+                    y,
+                    plane
+                );
+            }
+        }
+
+        // Baked into unit gains, the correction never brightens a sample.
+        let mut gains = vec![f64::unit_gain(); width * height];
+        map.bake::<f64>(&mut gains, 0);
+        assert!(gains.iter().all(|&g| g <= f64::unit_gain()));
+        let mut band = vec![f64::unit_gain(); width * 2];
+        map.bake::<f64>(&mut band, 100);
+        assert_eq!(band[..], gains[100 * width..102 * width]);
+        let mut fixed = vec![u16::unit_gain(); width];
+        map.bake::<u16>(&mut fixed, 0);
+        for (&f, &g) in fixed.iter().zip(&gains) {
+            assert!((f as f64 / u16::unit_gain() as f64 - g / f64::unit_gain()).abs() < 1e-4);
+        }
+
+        // The latest LCC frame from the same back applies.
+        let lcc = |serial: &str, shutter_count| LccFrame {
+            path: PathBuf::from(format!("{}-{}.IA", serial, shutter_count)),
+            serial: serial.to_string(),
+            shutter_count,
+        };
+        let options = LccOptions {
+            frames: vec![
+                lcc("e75-0042", 1200),
+                lcc("e75-0042", 1230),
+                lcc("e75-0042", 1300),
+                lcc("e22-0001", 1233),
+            ],
+            ..LccOptions::default()
+        };
+        let mut meta = iadng::test_meta();
+        assert_eq!(options.for_frame(&meta), Some(&options.frames[1]));
+        meta.shutter_count = 1230;
+        assert_eq!(options.for_frame(&meta), Some(&options.frames[0]));
+        meta.shutter_count = 1100;
+        assert_eq!(options.for_frame(&meta), None);
+
+        // GainMap opcodes, one per CFA position.
+        let opcodes = map.opcode_list();
+        let word = |at: usize| u32::from_be_bytes(opcodes[at..at + 4].try_into().unwrap());
+        let params = 4 * 8 + 4 * 2 + 8 * 4 + 4 + 4 * 7 * 9;
+        assert_eq!(opcodes.len(), 4 + 4 * (16 + params));
+        assert_eq!(word(0), 4);
+        assert_eq!(
+            [word(4), word(8), word(12), word(16)],
+            [9, 0x0103_0000, 1, params as u32]
+        );
+        let plane3 = 4 + 3 * (16 + params) + 16;
+        assert_eq!(
+            [word(plane3), word(plane3 + 4), word(plane3 + 8)],
+            [1, 1, 768]
+        );
+        let spacing = f64::from_be_bytes(opcodes[plane3 + 40..plane3 + 48].try_into().unwrap());
+        // The last grid row is the position's last row, 766 rows below the first.
+        assert!((spacing * 6.0 * 767.0 - 766.0).abs() < 1e-9);
+
+        let mut meta = iadng::test_meta();
+        let small = LccMap::measure(&[1.0, 2.0, 1.0, 2.0, 3.0, 4.0, 3.0, 4.0], 4, 2);
+        meta.gain_map = Some(Arc::new(small.clone()));
+        let dir = crate::test_dir("lcc");
+        let path = dir.join("FRAME.dng");
+        let raw = iadng::write_test_dng(&meta, &path).raw;
+        assert_eq!(raw.get(51009).unwrap().data, small.opcode_list());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,393 @@
+/*
+Lenses the back can't see.
+
+On a Hy6 the back records the lens's focal length and aperture; on a view
+camera there is nothing to record, and META holds zeros or leftovers. A lens
+database (YAML, names mapped to lenses) describes the lenses in use:
+
+    apo-sironar-150:
+      make: Rodenstock
+      model: Apo-Sironar-S 150mm f/5.6
+      focal_length: 150
+      max_aperture: 5.6
+
+and frames are assigned a lens and aperture for the whole session, or frame
+by frame from a CSV keyed by shutter count:
+
+    shutter_count,lens,f_stop
+    1234,apo-sironar-150,22
+
+Per-frame entries win over the session's, which win over META.
+
+On a Hy6 body, with no lens given, the lens is guessed from META's focal
+length: it is named only when exactly one lens in `HY6_LENSES` covers that
+length, so at 80 and 120 mm, where a prime and the zoom overlap, none is.
+This stands in for the AF lens identifier until it is decoded (see
+Limitations in the README).
+*/
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::sinar_ia::SinarIAMeta;
+
+/// A lens from the database.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Lens {
+    pub make: Option<String>,
+    pub model: String,
+    /// In mm; the shortest, for a zoom
+    pub focal_length: f32,
+    /// Widest aperture, as an f-number, at `focal_length`
+    pub max_aperture: Option<f32>,
+    /// Zooms: the longest focal length
+    pub focal_length_max: Option<f32>,
+    /// Zooms: widest aperture at `focal_length_max`
+    pub max_aperture_tele: Option<f32>,
+}
+
+impl Lens {
+    /// EXIF LensSpecification: shortest and longest focal length, then the
+    /// widest aperture at each, 0 where unknown.
+    pub fn specification(&self) -> [f32; 4] {
+        let max_aperture = self.max_aperture.unwrap_or(0.0);
+        [
+            self.focal_length,
+            self.focal_length_max.unwrap_or(self.focal_length),
+            max_aperture,
+            self.max_aperture_tele.unwrap_or(max_aperture),
+        ]
+    }
+
+    pub fn is_zoom(&self) -> bool {
+        self.focal_length_max.is_some()
+    }
+
+    fn covers(&self, focal_length: f32) -> bool {
+        // META holds the focal length in µm; allow for rounding in the lens.
+        let tolerance = 0.5;
+        focal_length >= self.focal_length - tolerance
+            && focal_length <= self.focal_length_max.unwrap_or(self.focal_length) + tolerance
+    }
+}
+
+/// A lens for the Hy6 mount.
+#[derive(Debug, Clone, Copy)]
+pub struct Hy6Lens {
+    pub model: &'static str,
+    pub focal_length: f32,
+    pub focal_length_max: Option<f32>,
+    pub max_aperture: f32,
+    pub max_aperture_tele: Option<f32>,
+}
+
+const fn prime(model: &'static str, focal_length: f32, max_aperture: f32) -> Hy6Lens {
+    Hy6Lens {
+        model,
+        focal_length,
+        focal_length_max: None,
+        max_aperture,
+        max_aperture_tele: None,
+    }
+}
+
+/// Schneider Kreuznach AFD lenses for the Rollei/Sinar Hy6 mount.
+pub static HY6_LENSES: [Hy6Lens; 5] = [
+    prime("Super-Angulon 50mm f/2.8 AFD", 50.0, 2.8),
+    prime("Xenotar 80mm f/2.8 AFD", 80.0, 2.8),
+    prime("Makro-Symmar 120mm f/4 AFD", 120.0, 4.0),
+    prime("Tele-Xenar 180mm f/4 AFD", 180.0, 4.0),
+    Hy6Lens {
+        model: "Variogon 60-140mm f/4-5.6 AFD",
+        focal_length: 60.0,
+        focal_length_max: Some(140.0),
+        max_aperture: 4.0,
+        max_aperture_tele: Some(5.6),
+    },
+];
+
+impl Hy6Lens {
+    pub fn to_lens(&self) -> Lens {
+        Lens {
+            make: Some("Schneider Kreuznach".to_string()),
+            model: self.model.to_string(),
+            focal_length: self.focal_length,
+            max_aperture: Some(self.max_aperture),
+            focal_length_max: self.focal_length_max,
+            max_aperture_tele: self.max_aperture_tele,
+        }
+    }
+}
+
+/// A guess at the Hy6 lens from its focal length: the one lens that covers
+/// `focal_length`, if there is only one.
+pub fn guess_hy6(focal_length: f32) -> Option<Lens> {
+    if focal_length <= 0.0 {
+        return None;
+    }
+    let mut matches = HY6_LENSES
+        .iter()
+        .map(Hy6Lens::to_lens)
+        .filter(|lens| lens.covers(focal_length));
+    match (matches.next(), matches.next()) {
+        (Some(lens), None) => Some(lens),
+        _ => None,
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Lenses by name.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct LensDb {
+    lenses: BTreeMap<String, Lens>,
+}
+
+impl LensDb {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let db: LensDb = serde_yaml::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+        for (name, lens) in &db.lenses {
+            if lens.focal_length <= 0.0 || lens.max_aperture.is_some_and(|f| f <= 0.0) {
+                return Err(invalid(format!(
+                    "{}: lens '{}' needs a positive focal length and aperture",
+                    path.display(),
+                    name
+                )));
+            }
+        }
+        Ok(db)
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Lens, String> {
+        if self.lenses.is_empty() {
+            return Err(format!("no lens '{}', the lens database is empty", name));
+        }
+        self.lenses.get(name).ok_or_else(|| {
+            let names: Vec<&str> = self.lenses.keys().map(|k| k.as_str()).collect();
+            format!(
+                "no lens '{}' in the lens database ({})",
+                name,
+                names.join(", ")
+            )
+        })
+    }
+}
+
+/// The lens and aperture for some frames; `None` leaves META's value.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LensChoice {
+    pub lens: Option<Lens>,
+    pub f_stop: Option<f32>,
+}
+
+/// Lens overrides for a run.
+#[derive(Debug, Clone, Default)]
+pub struct LensOptions {
+    pub session: LensChoice,
+    /// By shutter count
+    pub frames: HashMap<u32, LensChoice>,
+}
+
+impl LensOptions {
+    /// Replace META's lens and aperture where overridden for `meta`'s frame.
+    pub fn apply(&self, meta: &mut SinarIAMeta) {
+        let frame = self.frames.get(&meta.shutter_count);
+        let lens = frame
+            .and_then(|f| f.lens.as_ref())
+            .or(self.session.lens.as_ref());
+        if let Some(lens) = lens {
+            // Keep the focal length a zoom was set to, if META has it.
+            if !lens.is_zoom() || meta.focal_length <= 0.0 {
+                meta.focal_length = lens.focal_length;
+            }
+            meta.lens = Some(lens.clone());
+        }
+        if let Some(f_stop) = frame.and_then(|f| f.f_stop).or(self.session.f_stop) {
+            meta.f_stop = f_stop;
+        }
+    }
+}
+
+/// Parse an f-number such as `5.6`.
+pub fn parse_f_stop(value: &str) -> Result<f32, String> {
+    value
+        .trim()
+        .trim_start_matches("f/")
+        .parse::<f32>()
+        .ok()
+        .filter(|f| *f > 0.0)
+        .ok_or_else(|| format!("'{}' is not an f-number like 5.6", value))
+}
+
+/// Parse a per-frame CSV: a header naming a `shutter_count` column and
+/// `lens` and/or `f_stop` columns, then one row per frame. Empty cells
+/// leave the session's value. Fields can't be quoted.
+pub fn parse_frames(text: &str, db: &LensDb) -> Result<HashMap<u32, LensChoice>, String> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+    let header: Vec<&str> = match lines.next() {
+        Some((_, line)) => line.split(',').map(str::trim).collect(),
+        None => return Ok(HashMap::new()),
+    };
+    let column = |name: &str| header.iter().position(|&c| c == name);
+    let count_column =
+        column("shutter_count").ok_or_else(|| "no shutter_count column".to_string())?;
+    let (lens_column, f_stop_column) = (column("lens"), column("f_stop"));
+    if lens_column.is_none() && f_stop_column.is_none() {
+        return Err("no lens or f_stop column".to_string());
+    }
+    let mut frames = HashMap::new();
+    for (number, line) in lines {
+        let line_error = |msg: String| format!("line {}: {}", number + 1, msg);
+        let cells: Vec<&str> = line.split(',').map(str::trim).collect();
+        let cell = |column: Option<usize>| {
+            column
+                .and_then(|c| cells.get(c).copied())
+                .filter(|c| !c.is_empty())
+        };
+        let count: u32 = cell(Some(count_column))
+            .and_then(|c| c.parse().ok())
+            .ok_or_else(|| line_error("bad shutter count".to_string()))?;
+        let lens = match cell(lens_column) {
+            Some(name) => Some(db.get(name).map_err(line_error)?.clone()),
+            None => None,
+        };
+        let f_stop = match cell(f_stop_column) {
+            Some(f) => Some(parse_f_stop(f).map_err(line_error)?),
+            None => None,
+        };
+        if frames.insert(count, LensChoice { lens, f_stop }).is_some() {
+            return Err(line_error(format!("shutter count {} listed twice", count)));
+        }
+    }
+    Ok(frames)
+}
+
+/// Read a per-frame CSV, see `parse_frames`.
+pub fn read_frames(path: &Path, db: &LensDb) -> io::Result<HashMap<u32, LensChoice>> {
+    parse_frames(&fs::read_to_string(path)?, db)
+        .map_err(|e| invalid(format!("{}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iadng;
+
+    #[test]
+    fn test_lens_overrides() {
+        let dir = crate::test_dir("lens");
+        let db_path = dir.join("lenses.yaml");
+        std::fs::write(
+            &db_path,
+            "sironar-150:\n  make: Rodenstock\n  model: Apo-Sironar-S 150mm\n  \
+             focal_length: 150\n  max_aperture: 5.6\nnikkor-90:\n  model: Nikkor-SW 90mm\n  \
+             focal_length: 90\n",
+        )
+        .unwrap();
+        let db = LensDb::read(&db_path).unwrap();
+        let frames = parse_frames(
+            "shutter_count, f_stop, lens\n1234,22,\n1235,,nikkor-90\n",
+            &db,
+        )
+        .unwrap();
+        assert!(parse_frames("shutter_count,lens\n1,zeiss\n", &db).is_err());
+        assert!(parse_frames("shutter_count,f_stop\n1,8\n1,11\n", &db).is_err());
+        let options = LensOptions {
+            session: LensChoice {
+                lens: Some(db.get("sironar-150").unwrap().clone()),
+                f_stop: Some(16.0),
+            },
+            frames,
+        };
+
+        // View camera: the back recorded no lens.
+        let mut meta = SinarIAMeta {
+            shutter_count: 1234,
+            serial: "e75-0042".to_string(),
+            width: 4,
+            height: 2,
+            ..SinarIAMeta::default()
+        };
+        let path = dir.join("frame.dng");
+        let read_exif = |meta: &SinarIAMeta| iadng::write_test_dng(meta, &path).exif;
+        let exif = read_exif(&meta);
+        for tag in [33437, 37378, 37386, 42034, 42035, 42036] {
+            assert!(exif.get(tag).is_none(), "tag {} written for zero lens", tag);
+        }
+
+        options.apply(&mut meta);
+        assert_eq!((meta.f_stop, meta.focal_length), (22.0, 150.0));
+        let exif = read_exif(&meta);
+        assert_eq!(exif.get(33437).unwrap().as_f64s(), [22.0]);
+        assert_eq!(exif.get(37386).unwrap().as_f64s(), [150.0]);
+        assert_eq!(exif.get(42035).unwrap().as_string(), "Rodenstock");
+        assert_eq!(exif.get(42036).unwrap().as_string(), "Apo-Sironar-S 150mm");
+        let spec = exif.get(42034).unwrap().as_f64s();
+        assert_eq!(spec[..2], [150.0, 150.0]);
+        assert!((spec[2] - 5.6).abs() < 1e-3 && (spec[3] - 5.6).abs() < 1e-3);
+
+        let mut meta = SinarIAMeta {
+            shutter_count: 1235,
+            focal_length: 80.0,
+            ..SinarIAMeta::default()
+        };
+        options.apply(&mut meta);
+        assert_eq!((meta.f_stop, meta.focal_length), (16.0, 90.0));
+        assert_eq!(meta.lens.unwrap().make, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_hy6_lenses() {
+        let model = |focal: f32| guess_hy6(focal).map(|lens| lens.model);
+        assert_eq!(model(50.0).as_deref(), Some("Super-Angulon 50mm f/2.8 AFD"));
+        assert_eq!(model(180.2).as_deref(), Some("Tele-Xenar 180mm f/4 AFD"));
+        assert_eq!(
+            model(100.0).as_deref(),
+            Some("Variogon 60-140mm f/4-5.6 AFD")
+        );
+        // Both the Xenotar and the Variogon cover 80 mm.
+        assert_eq!(model(80.0), None);
+        assert_eq!(model(0.0), None);
+        assert_eq!(
+            guess_hy6(100.0).unwrap().specification(),
+            [60.0, 140.0, 4.0, 5.6]
+        );
+
+        let mut lump = vec![0u8; 360];
+        lump[20..29].copy_from_slice(b"Sinar Hy6");
+        lump[272..280].copy_from_slice(b"e75-0042");
+        lump[356..360].copy_from_slice(&50_000u32.to_le_bytes());
+        let meta = SinarIAMeta::process_meta(&lump).unwrap();
+        assert_eq!(meta.lens.unwrap().focal_length, 50.0);
+        lump[20..29].copy_from_slice(b"Sinar p3 ");
+        assert_eq!(SinarIAMeta::process_meta(&lump).unwrap().lens, None);
+
+        // A zoom from the lens database keeps the focal length it was set to.
+        let mut meta = SinarIAMeta {
+            focal_length: 100.0,
+            ..SinarIAMeta::default()
+        };
+        let options = LensOptions {
+            session: LensChoice {
+                lens: guess_hy6(100.0),
+                f_stop: None,
+            },
+            ..LensOptions::default()
+        };
+        options.apply(&mut meta);
+        assert_eq!(meta.focal_length, 100.0);
+    }
+}
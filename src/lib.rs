@@ -1,6 +1,44 @@
+pub mod backs;
+pub mod calibrate;
+pub mod capture;
+pub mod cull;
+pub mod dcp;
+pub mod demosaic;
+pub mod export;
 pub mod iadng;
+pub mod lcc;
+pub mod lens;
+pub mod naming;
+pub mod noise;
+pub mod orientation;
+pub mod original;
+pub mod plan;
+pub mod profile;
 pub mod pwad;
+pub mod refcache;
+pub mod restore;
+pub mod sheet;
 pub mod sinar_ia;
+pub mod stream;
+pub mod tiffread;
+pub mod xmp;
+
+/// A fresh, empty directory for one test's files. Every call gets its own,
+/// so tests running side by side never share one.
+#[cfg(test)]
+pub(crate) fn test_dir(name: &str) -> std::path::PathBuf {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "iatodng-{}-{}-{}",
+        name,
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
 
 #[cfg(test)]
 mod tests {
@@ -14,8 +52,8 @@ mod tests {
         let pwad = Pwad::from_file("002500E8.EMO/6C486AFC.IA").unwrap();
         println!("{:?}", pwad);
         let metab = pwad.read_lump_by_tag(META_KEY).unwrap();
-        assert!(metab.len() > 0);
-        let meta = sinar_ia::SinarIAMeta::process_meta(&metab);
+        assert!(!metab.is_empty());
+        let meta = sinar_ia::SinarIAMeta::process_meta(&metab).unwrap();
         assert!(meta.camera == "Sinar Hy6");
     }
 }
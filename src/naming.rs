@@ -0,0 +1,309 @@
+/*
+Output file name templates.
+
+A template is a relative path with `{field}` placeholders filled from the
+frame's META lump, e.g. `{serial}/{date}/{shutter_count}.dng`.
+*/
+
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use chrono::NaiveDateTime;
+
+use crate::capture::{CaptureTime, TimeCorrection};
+use crate::sinar_ia::SinarIAMeta;
+
+pub const DEFAULT_TEMPLATE: &str = "{shutter_count}.dng";
+
+pub const TEMPLATE_FIELDS: [&str; 10] = [
+    "serial",
+    "model",
+    "camera",
+    "shutter_count",
+    "date",
+    "time",
+    "ia_name",
+    "iso",
+    "f_stop",
+    "wb",
+];
+
+#[derive(Debug, Clone)]
+pub struct NameTemplate {
+    template: String,
+}
+
+impl NameTemplate {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        if template.trim().is_empty() {
+            return Err("Name template is empty".to_string());
+        }
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("Unclosed '{{' in name template '{}'", template))?;
+            let field = &rest[start + 1..start + end];
+            if !TEMPLATE_FIELDS.contains(&field) {
+                return Err(format!(
+                    "Unknown field '{{{}}}' in name template, expected one of: {}",
+                    field,
+                    TEMPLATE_FIELDS.join(", ")
+                ));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(format!("Unmatched '}}' in name template '{}'", template));
+        }
+        let path = Path::new(template);
+        if path
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(format!(
+                "Name template '{}' must be a relative path without '..'",
+                template
+            ));
+        }
+        Ok(NameTemplate {
+            template: template.to_string(),
+        })
+    }
+
+    /// Render the template for one frame, relative to the output directory.
+    /// The extension is forced to `ext` whether or not the template has one.
+    /// Empty, `.` and `..` field values render as `unknown`, so the name
+    /// always stays inside the output directory.
+    pub fn render(&self, meta: &SinarIAMeta, ia_path: &Path, ext: &str) -> PathBuf {
+        let captured = capture_time(meta, ia_path);
+        let mut name = self.template.clone();
+        for field in TEMPLATE_FIELDS {
+            let key = format!("{{{}}}", field);
+            if !name.contains(&key) {
+                continue;
+            }
+            let value = match field {
+                "serial" => meta.serial.clone(),
+                "model" => meta.model.clone(),
+                "camera" => meta.camera.clone(),
+                "shutter_count" => meta.shutter_count.to_string(),
+                "date" => format_captured(captured, "%Y-%m-%d"),
+                "time" => format_captured(captured, "%H%M%S"),
+                "ia_name" => ia_path
+                    .file_stem()
+                    .map(|s| s.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                "iso" => meta.iso.to_string(),
+                "f_stop" => format!("{:.1}", meta.f_stop),
+                "wb" => format!("{:?}", meta.white_balance_name),
+                _ => unreachable!(),
+            };
+            name = name.replace(&key, &sanitize(&value));
+        }
+        let dotted = format!(".{}", ext);
+        if name.to_lowercase().ends_with(".dng") {
+            name.truncate(name.len() - 4);
+        }
+        if !name.to_lowercase().ends_with(&dotted.to_lowercase()) {
+            name.push_str(&dotted);
+        }
+        // `parse` and `sanitize` already rule out anything but plain
+        // components; checked again as the name is joined to the output
+        // directory.
+        Path::new(&name)
+            .components()
+            .map(|c| match c {
+                Component::Normal(part) => part,
+                _ => "unknown".as_ref(),
+            })
+            .collect()
+    }
+}
+
+impl Default for NameTemplate {
+    fn default() -> Self {
+        NameTemplate {
+            template: DEFAULT_TEMPLATE.to_string(),
+        }
+    }
+}
+
+/// Capture time used for `{date}` and `{time}`: the frame's, uncorrected
+/// if it was never looked up, or `None` if there is none.
+pub fn capture_time(meta: &SinarIAMeta, ia_path: &Path) -> Option<NaiveDateTime> {
+    meta.captured
+        .or_else(|| CaptureTime::of_ia(ia_path, &TimeCorrection::default()))
+        .map(|captured| captured.local)
+}
+
+// A frame without a capture time renders as `unknown`, never as the time of
+// the conversion, so the same frame gets the same name on every run.
+fn format_captured(captured: Option<NaiveDateTime>, format: &str) -> String {
+    captured.map_or_else(
+        || "unknown".to_string(),
+        |time| time.format(format).to_string(),
+    )
+}
+
+// Keep field values from introducing extra path components.
+fn sanitize(value: &str) -> String {
+    let value = value.trim();
+    if matches!(value, "" | "." | "..") {
+        return "unknown".to_string();
+    }
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, clap::ValueEnum)]
+pub enum OnCollision {
+    /// Append `_1`, `_2`, ... to later frames that render to the same name
+    Suffix,
+    /// Report the collision and do not convert the later frame
+    Error,
+}
+
+/// Output names already handed out in this batch, mapped to their source IA.
+/// Names are compared ignoring case, as output folders are often on
+/// case-insensitive file systems where `A.dng` and `a.dng` are one file.
+#[derive(Debug, Default)]
+pub struct OutputNames {
+    claimed: HashMap<PathBuf, PathBuf>,
+}
+
+fn fold_case(name: &Path) -> PathBuf {
+    PathBuf::from(name.to_string_lossy().to_lowercase())
+}
+
+impl OutputNames {
+    pub fn new() -> Self {
+        OutputNames::default()
+    }
+
+    /// Claim `candidate` for `source`. On a collision the error carries the
+    /// IA file that already owns the name.
+    pub fn claim(
+        &mut self,
+        candidate: PathBuf,
+        source: &Path,
+        policy: OnCollision,
+    ) -> Result<PathBuf, PathBuf> {
+        let mut name = candidate.clone();
+        if let Some(owner) = self.claimed.get(&fold_case(&name)) {
+            if policy == OnCollision::Error {
+                return Err(owner.clone());
+            }
+            let stem = candidate
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let ext = candidate
+                .extension()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let mut n = 1;
+            while self.claimed.contains_key(&fold_case(&name)) {
+                name = candidate.with_file_name(format!("{}_{}.{}", stem, n, ext));
+                n += 1;
+            }
+        }
+        self.claimed.insert(fold_case(&name), source.to_path_buf());
+        Ok(name)
+    }
+
+    /// Mark `name` as taken by `owner`, such as an output already on disk
+    /// that was converted from another frame.
+    pub fn reserve(&mut self, name: &Path, owner: &Path) {
+        self.claimed.insert(fold_case(name), owner.to_path_buf());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame_meta() -> SinarIAMeta {
+        SinarIAMeta {
+            shutter_count: 1234,
+            f_stop: 5.6,
+            serial: "e75-0042".to_string(),
+            ..SinarIAMeta::default()
+        }
+    }
+
+    #[test]
+    fn test_name_template() {
+        let meta = frame_meta();
+        let ia = Path::new("6C486AFC.IA");
+        let default = NameTemplate::default();
+        assert_eq!(default.render(&meta, ia, "dng"), PathBuf::from("1234.dng"));
+        let nested = NameTemplate::parse("{serial}/{ia_name}_{shutter_count}_f{f_stop}").unwrap();
+        assert_eq!(
+            nested.render(&meta, ia, "dng"),
+            PathBuf::from("e75-0042/6C486AFC_1234_f5.6.dng")
+        );
+        // Without a capture time the date is unknown, not today's.
+        let dated = NameTemplate::parse("{date}/{shutter_count}").unwrap();
+        assert_eq!(
+            dated.render(&meta, ia, "dng"),
+            PathBuf::from("unknown/1234.dng")
+        );
+        // Empty or relative values can't leave the output directory.
+        let mut blank = frame_meta();
+        blank.serial = "..".to_string();
+        let camera = NameTemplate::parse("{camera}/{serial}/{shutter_count}").unwrap();
+        let rendered = camera.render(&blank, ia, "dng");
+        assert_eq!(rendered, PathBuf::from("unknown/unknown/1234.dng"));
+        blank.camera = " . ".to_string();
+        blank.serial = "a/../..".to_string();
+        let rendered = camera.render(&blank, ia, "dng");
+        assert_eq!(rendered, PathBuf::from("unknown/a_.._../1234.dng"));
+        assert!(rendered
+            .components()
+            .all(|c| matches!(c, Component::Normal(_))));
+        assert!(NameTemplate::parse("{bogus}.dng").is_err());
+        assert!(NameTemplate::parse("../{serial}.dng").is_err());
+        assert!(NameTemplate::parse("{serial.dng").is_err());
+    }
+
+    #[test]
+    fn test_name_collisions() {
+        let mut names = OutputNames::new();
+        let a = Path::new("A.IA");
+        let b = Path::new("B.IA");
+        let name = PathBuf::from("1234.dng");
+        assert_eq!(
+            names.claim(name.clone(), a, OnCollision::Suffix),
+            Ok(name.clone())
+        );
+        assert_eq!(
+            names.claim(name.clone(), b, OnCollision::Suffix),
+            Ok(PathBuf::from("1234_1.dng"))
+        );
+        assert_eq!(
+            names.claim(name, b, OnCollision::Error),
+            Err(a.to_path_buf())
+        );
+        // Names differing only in case are one file on many file systems.
+        assert_eq!(
+            names.claim(PathBuf::from("1234.DNG"), b, OnCollision::Error),
+            Err(a.to_path_buf())
+        );
+        assert_eq!(
+            names.claim(PathBuf::from("1234.DNG"), b, OnCollision::Suffix),
+            Ok(PathBuf::from("1234_2.DNG"))
+        );
+        names.reserve(Path::new("5678.dng"), Path::new("out/5678.dng"));
+        assert_eq!(
+            names.claim(PathBuf::from("5678.dng"), a, OnCollision::Error),
+            Err(PathBuf::from("out/5678.dng"))
+        );
+    }
+}
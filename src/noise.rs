@@ -0,0 +1,463 @@
+/*
+Noise model of a frame, for the DNG NoiseProfile and BaselineNoise tags.
+
+A sample `s` above black has variance `shot * s + read` for each CFA colour.
+
+* Read noise comes from the BR file. BLACK0 and BLACK1 are two dark frames,
+  so the variance of their difference is that of a frame minus its dark
+  reference, which is what calibration leaves. Outliers (hot pixels whose
+  dark current differs between the two) are clipped.
+* Shot noise is fitted to the frame itself. Each block of 16x16 samples of
+  one CFA position gives a signal (its mean) and a noise variance (from
+  differences of neighbouring samples, so smooth gradients don't count).
+  Textured blocks only ever add variance, so the fit follows the lower
+  quartile of the blocks at each signal level.
+
+Both are measured in sensor counts above the dark frame and converted to the
+units of the calibrated samples with each colour's mean calibration gain.
+Frames with too few flat blocks, such as dark frames, get no noise model.
+*/
+
+use rayon::prelude::{
+    IndexedParallelIterator, IntoParallelIterator, ParallelIterator, ParallelSlice,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::demosaic::cfa_color;
+
+/// Samples per block side, in samples of one CFA position.
+pub const BLOCK: usize = 16;
+/// Counts from which a sensor site is taken as clipped.
+const SATURATED: u16 = 0xFF00;
+/// Signal bins the blocks of each colour are sorted into.
+const BINS: usize = 32;
+/// Blocks a bin needs to be fitted.
+const MIN_BLOCKS: usize = 8;
+/// Half the range of the read noise histograms, in counts.
+const DARK_RANGE: i32 = 4096;
+/// Read noise differences further than this many standard deviations from
+/// the median are outliers.
+const CLIP_SIGMA: f64 = 6.0;
+/// Noise at 18% of full scale, at ISO 100, of the reference camera
+/// BaselineNoise is relative to: a signal-to-noise ratio of 100.
+const REFERENCE_NOISE: f64 = 0.0018;
+
+/// Noise per CFA colour (R, G, B): a sample `s` above black has variance
+/// `shot * s + read`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NoiseModel {
+    pub shot: [f64; 3],
+    pub read: [f64; 3],
+}
+
+impl NoiseModel {
+    /// DNG NoiseProfile: a scale and offset per colour for data normalised
+    /// to [0, 1], where sample `s` is written as `k * (s - zero)`.
+    pub fn profile(&self, k: [f64; 3], zero: [f64; 3]) -> [f64; 6] {
+        let mut profile = [0.0; 6];
+        for c in 0..3 {
+            profile[2 * c] = self.shot[c] * k[c];
+            profile[2 * c + 1] = (k[c] * k[c] * (self.read[c] + self.shot[c] * zero[c])).max(0.0);
+        }
+        profile
+    }
+}
+
+/// DNG BaselineNoise for a normalised `profile`: the green noise at 18%,
+/// taken back to ISO 100 as shot noise, relative to `REFERENCE_NOISE`.
+pub fn baseline_noise(profile: &[f64; 6], iso: u32) -> f64 {
+    let noise = (profile[2] * 0.18 + profile[3]).sqrt();
+    let to_iso_100 = if iso > 0 {
+        (100.0 / iso as f64).sqrt()
+    } else {
+        1.0
+    };
+    (noise * to_iso_100 / REFERENCE_NOISE).clamp(0.25, 8.0)
+}
+
+/// Histograms of BLACK1 - BLACK0 per colour, for the read noise.
+#[derive(Debug, Clone)]
+pub struct DarkStats {
+    hist: [Vec<u64>; 3],
+}
+
+impl Default for DarkStats {
+    fn default() -> Self {
+        DarkStats {
+            hist: std::array::from_fn(|_| vec![0; 2 * DARK_RANGE as usize]),
+        }
+    }
+}
+
+impl DarkStats {
+    /// Count rows of the two dark frames, `width` sites wide and starting at
+    /// sensor row `row0`.
+    pub fn measure(
+        black0: &[u16],
+        black1: &[u16],
+        width: usize,
+        cfa: [u8; 4],
+        row0: usize,
+    ) -> Self {
+        // A histogram per band of rows; one per row would be mostly empty.
+        let band = 64 * width;
+        black0
+            .par_chunks(band)
+            .zip(black1.par_chunks(band))
+            .enumerate()
+            .map(|(n, (black0, black1))| {
+                let mut stats = DarkStats::default();
+                let row0 = row0 + n * 64;
+                for (i, (&b0, &b1)) in black0.iter().zip(black1).enumerate() {
+                    let c = cfa_color(cfa, i % width, row0 + i / width);
+                    let bin = (b1 as i32 - b0 as i32 + DARK_RANGE).clamp(0, 2 * DARK_RANGE - 1);
+                    stats.hist[c][bin as usize] += 1;
+                }
+                stats
+            })
+            .reduce(DarkStats::default, DarkStats::merge)
+    }
+
+    pub fn merge(mut self, other: DarkStats) -> DarkStats {
+        for (hist, other) in self.hist.iter_mut().zip(&other.hist) {
+            for (h, o) in hist.iter_mut().zip(other) {
+                *h += o;
+            }
+        }
+        self
+    }
+
+    /// Read noise variance per colour, in counts.
+    pub fn read_noise(&self) -> [f64; 3] {
+        std::array::from_fn(|c| clipped_variance(&self.hist[c]))
+    }
+}
+
+// Variance of the histogrammed values within CLIP_SIGMA robust standard
+// deviations of their median.
+fn clipped_variance(hist: &[u64]) -> f64 {
+    let total: u64 = hist.iter().sum();
+    if total == 0 {
+        return 0.0;
+    }
+    let quantile = |hist: &[u64], q: f64| {
+        let target = (total as f64 * q).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        hist.iter()
+            .position(|&n| {
+                seen += n;
+                seen >= target
+            })
+            .unwrap_or(0)
+    };
+    let median = quantile(hist, 0.5);
+    let mut deviations = vec![0u64; hist.len()];
+    for (bin, &n) in hist.iter().enumerate() {
+        deviations[bin.abs_diff(median)] += n;
+    }
+    // 1.4826 MAD estimates the standard deviation of normal noise; keep at
+    // least a count either side for quantised, nearly noiseless data.
+    let sigma = (1.4826 * quantile(&deviations, 0.5) as f64).max(1.0);
+    let reach = (CLIP_SIGMA * sigma).ceil() as usize;
+    let range = median.saturating_sub(reach)..(median + reach + 1).min(hist.len());
+    let (mut n, mut sum, mut sum2) = (0.0, 0.0, 0.0);
+    for bin in range {
+        let (count, x) = (hist[bin] as f64, bin as f64);
+        n += count;
+        sum += count * x;
+        sum2 += count * x * x;
+    }
+    let mean = sum / n;
+    (sum2 / n - mean * mean).max(0.0)
+}
+
+// Signal and noise variance of one block, in counts.
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    colour: usize,
+    signal: f64,
+    variance: f64,
+}
+
+/// Block statistics and calibration gains of some rows of a frame, for the
+/// shot noise.
+#[derive(Debug, Clone, Default)]
+pub struct NoiseStats {
+    blocks: Vec<Block>,
+    // Sums of calibrated samples and of counts above the dark frame, per
+    // colour.
+    calibrated: [f64; 3],
+    counts: [f64; 3],
+}
+
+impl NoiseStats {
+    /// Measure rows of a frame `width` sites wide starting at sensor row
+    /// `row0`: sensor counts `raw`, the dark frame `black`, and the sample
+    /// each site was calibrated to, if it was.
+    pub fn measure(
+        raw: &[u16],
+        black: &[u16],
+        calibrated: Option<&(dyn Fn(usize) -> f64 + Sync)>,
+        width: usize,
+        cfa: [u8; 4],
+        row0: usize,
+    ) -> Self {
+        let rows = raw.len() / width;
+        let dark = |i: usize| raw[i] as f64 - black[i] as f64;
+        let (block_rows, block_cols) = (rows / (2 * BLOCK), width / (2 * BLOCK));
+        let blocks = (0..block_rows * block_cols * 4)
+            .into_par_iter()
+            .filter_map(|b| {
+                let (position, block) = (b % 4, b / 4);
+                let (r, c) = (position / 2, position % 2);
+                let (y0, x0) = (
+                    (block / block_cols) * 2 * BLOCK,
+                    (block % block_cols) * 2 * BLOCK,
+                );
+                let site = |py: usize, px: usize| (y0 + 2 * py + r) * width + x0 + 2 * px + c;
+                let (mut sum, mut diff2) = (0.0, 0.0);
+                for py in 0..BLOCK {
+                    for px in 0..BLOCK {
+                        let i = site(py, px);
+                        if raw[i] >= SATURATED {
+                            return None;
+                        }
+                        sum += dark(i);
+                        if px > 0 {
+                            let d = dark(i) - dark(site(py, px - 1));
+                            diff2 += d * d;
+                        }
+                    }
+                }
+                Some(Block {
+                    colour: cfa_color(cfa, x0 + c, row0 + y0 + r),
+                    signal: sum / (BLOCK * BLOCK) as f64,
+                    variance: diff2 / (2 * BLOCK * (BLOCK - 1)) as f64,
+                })
+            })
+            .collect();
+        let mut stats = NoiseStats {
+            blocks,
+            ..NoiseStats::default()
+        };
+        if let Some(calibrated) = calibrated {
+            let sums = (0..rows)
+                .into_par_iter()
+                .map(|y| {
+                    let mut sums = [[0.0; 3]; 2];
+                    for x in 0..width {
+                        let (i, colour) = (y * width + x, cfa_color(cfa, x, row0 + y));
+                        sums[0][colour] += calibrated(i);
+                        sums[1][colour] += dark(i);
+                    }
+                    sums
+                })
+                .reduce(
+                    || [[0.0; 3]; 2],
+                    |mut a, b| {
+                        for (a, b) in a.iter_mut().flatten().zip(b.iter().flatten()) {
+                            *a += b;
+                        }
+                        a
+                    },
+                );
+            (stats.calibrated, stats.counts) = (sums[0], sums[1]);
+        }
+        stats
+    }
+
+    pub fn merge(mut self, other: NoiseStats) -> NoiseStats {
+        self.blocks.extend(other.blocks);
+        for c in 0..3 {
+            self.calibrated[c] += other.calibrated[c];
+            self.counts[c] += other.counts[c];
+        }
+        self
+    }
+
+    /// The frame's noise, given the read noise variance in counts, in the
+    /// units of the calibrated samples, or of counts if none were measured.
+    pub fn model(&self, read: [f64; 3]) -> Option<NoiseModel> {
+        let mut shot = [0.0; 3];
+        for (c, (shot, read)) in shot.iter_mut().zip(read).enumerate() {
+            *shot = self.fit_shot(c, read)?;
+        }
+        self.scale(NoiseModel { shot, read })
+    }
+
+    /// A noise model in counts, such as a back's calibrated one, in the
+    /// units of the calibrated samples, or unchanged if none were measured.
+    pub fn scale(&self, counts: NoiseModel) -> Option<NoiseModel> {
+        let mut model = counts;
+        for (c, (shot, read)) in model.shot.iter_mut().zip(&mut model.read).enumerate() {
+            if self.counts[c] != 0.0 {
+                let gain = self.calibrated[c] / self.counts[c];
+                if gain <= 0.0 {
+                    return None;
+                }
+                *shot *= gain;
+                *read *= gain * gain;
+            }
+        }
+        Some(model)
+    }
+
+    // Least squares fit of `shot` in `variance = shot * signal + read` to
+    // the lower quartile of each signal bin, weighted by relative error.
+    fn fit_shot(&self, colour: usize, read: f64) -> Option<f64> {
+        let blocks: Vec<&Block> = self
+            .blocks
+            .iter()
+            .filter(|b| b.colour == colour && b.signal > 0.0)
+            .collect();
+        let top = blocks.iter().map(|b| b.signal).fold(0.0, f64::max);
+        if top <= 0.0 {
+            return None;
+        }
+        let mut bins: Vec<Vec<&Block>> = vec![Vec::new(); BINS];
+        for block in blocks {
+            let bin = ((block.signal / top * BINS as f64) as usize).min(BINS - 1);
+            bins[bin].push(block);
+        }
+        // The lower quartile of a variance estimated from n differences
+        // sits below the true variance; scale it back.
+        let n = (BLOCK * (BLOCK - 1)) as f64;
+        let quartile_bias = 1.0 - 0.6745 * (2.0 / n).sqrt();
+        let (mut num, mut den, mut fitted) = (0.0, 0.0, 0);
+        for bin in bins.iter_mut().filter(|b| b.len() >= MIN_BLOCKS) {
+            bin.sort_by(|a, b| a.variance.total_cmp(&b.variance));
+            let variance = bin[bin.len() / 4].variance / quartile_bias;
+            let signal = bin.iter().map(|b| b.signal).sum::<f64>() / bin.len() as f64;
+            let weight = 1.0 / (variance * variance).max(f64::MIN_POSITIVE);
+            num += weight * signal * (variance - read);
+            den += weight * signal * signal;
+            fitted += 1;
+        }
+        let shot = num / den;
+        (fitted >= 2 && shot > 0.0).then_some(shot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iadng;
+    use crate::sinar_ia::{self, SinarIAMeta};
+
+    #[test]
+    fn test_noise() {
+        // Roughly normal noise from a fixed generator.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut normal = move || {
+            let mut sum = 0.0;
+            for _ in 0..12 {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                sum += (state >> 11) as f64 / (1u64 << 53) as f64;
+            }
+            sum - 6.0
+        };
+        let cfa = sinar_ia::E75_CFA;
+        let (width, height) = (512, 512);
+
+        // Two dark frames with 3 counts of noise each, and some hot pixels.
+        let mut black0 = vec![0u16; width * height];
+        let mut black1 = vec![0u16; width * height];
+        for i in 0..width * height {
+            black0[i] = (500.0 + 3.0 * normal()).round() as u16;
+            black1[i] = (500.0 + 3.0 * normal()).round() as u16;
+            if i % 997 == 0 {
+                black1[i] += 900;
+            }
+        }
+        let read = DarkStats::measure(&black0, &black1, width, cfa, 0).read_noise();
+        for variance in read {
+            assert!((variance - 18.0).abs() < 1.0, "read noise {}", variance);
+        }
+
+        // Flat patches from dark to bright, 0.5 counts of variance per count
+        // of signal, and a band of texture that the fit should ignore.
+        let (shot, read) = (0.5, [18.0; 3]);
+        let mut raw = vec![0u16; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+                let mut signal = 200.0 + 3000.0 * ((x / 32) * 16 + y / 32) as f64 / 256.0;
+                if y >= 448 {
+                    signal += 400.0 * ((x as f64) * 0.7).sin();
+                }
+                let noise = (shot * signal + 18.0f64).sqrt() * normal();
+                raw[i] = (black1[i] as f64 + signal + noise).round() as u16;
+            }
+        }
+        let stats = NoiseStats::measure(&raw, &black1, None, width, cfa, 0);
+        let model = stats.model(read).unwrap();
+        for fitted in model.shot {
+            assert!((fitted - shot).abs() < 0.05 * shot, "shot noise {}", fitted);
+        }
+        assert_eq!(model.read, read);
+        // In calibrated units, here twice the counts, measured in two bands.
+        let calibrated = |i: usize| 2.0 * (raw[i] as f64 - black1[i] as f64);
+        let half = width * height / 2;
+        let top = NoiseStats::measure(
+            &raw[..half],
+            &black1[..half],
+            Some(&calibrated),
+            width,
+            cfa,
+            0,
+        );
+        let bottom_calibrated = |i: usize| calibrated(half + i);
+        let bottom = NoiseStats::measure(
+            &raw[half..],
+            &black1[half..],
+            Some(&bottom_calibrated),
+            width,
+            cfa,
+            height / 2,
+        );
+        let scaled = top.merge(bottom).model(read).unwrap();
+        for (c, read) in read.iter().enumerate() {
+            assert!((scaled.shot[c] - 2.0 * model.shot[c]).abs() < 1e-9);
+            assert_eq!(scaled.read[c], 4.0 * read);
+        }
+        // A dark frame has no signal to fit.
+        assert_eq!(
+            NoiseStats::measure(&black1, &black1, None, width, cfa, 0).model(read),
+            None
+        );
+
+        // Written as `k * (s - zero)`, normalised.
+        let model = NoiseModel {
+            shot: [0.5, 1.0, 2.0],
+            read: [18.0, 20.0, 22.0],
+        };
+        let profile = model.profile([0.001; 3], [-10.0; 3]);
+        assert!((profile[0] - 0.0005).abs() < 1e-12);
+        assert!((profile[3] - 1e-6 * 10.0).abs() < 1e-12);
+        assert!(baseline_noise(&profile, 100) > baseline_noise(&profile, 400));
+
+        let meta = SinarIAMeta {
+            iso: 50,
+            width: 4,
+            height: 2,
+            noise: Some(model),
+            ..SinarIAMeta::default()
+        };
+        let dir = crate::test_dir("noise");
+        let path = dir.join("FRAME.dng");
+        let iadng::TestDng { root, raw, .. } = iadng::write_test_dng(&meta, &path);
+        // Samples from 0 to 7 are written from 0 to 65535.
+        let expected = model.profile([1.0 / 7.0; 3], [0.0; 3]);
+        for (read, expected) in raw.get(51041).unwrap().as_f64s().iter().zip(expected) {
+            assert!((read - expected).abs() < 1e-12);
+        }
+        let baseline = root.get(50731).unwrap().as_f64s()[0];
+        assert!((baseline - baseline_noise(&expected, meta.iso)).abs() < 1e-3);
+        assert_eq!(root.get(50732).unwrap().as_f64s(), [1.0]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
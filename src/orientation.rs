@@ -0,0 +1,117 @@
+/*
+Orientation of a frame, as a TIFF/EXIF Orientation value.
+
+The back reads the sensor out in the same order however it is mounted, so
+the orientation depends on how it sat on the camera, and is taken from the
+session: frames from an upright Hy6 body are stored transverse (7), which is
+the default, and a back turned on a view camera adapter is given with
+`--mount`. `--orientation` sets the value outright.
+
+DNGs write it in IFD0, which the thumbnail shares with the raw IFD, and in
+the raw IFD; TIFFs write it; PNGs are rotated to it.
+*/
+
+/// Orientation of frames from an upright Hy6.
+pub const HY6: u16 = 7;
+
+/// EXIF orientation names, by value.
+const NAMES: [(&str, u16); 8] = [
+    ("normal", 1),
+    ("mirror", 2),
+    ("rotate180", 3),
+    ("flip", 4),
+    ("transpose", 5),
+    ("rotate90", 6),
+    ("transverse", 7),
+    ("rotate270", 8),
+];
+
+/// How far the back was turned from the Hy6's upright mounting, clockwise
+/// as seen from behind the camera.
+#[derive(Debug, PartialEq, Clone, Copy, clap::ValueEnum)]
+pub enum Mount {
+    #[value(name = "0")]
+    Upright,
+    #[value(name = "90")]
+    Right,
+    #[value(name = "180")]
+    Inverted,
+    #[value(name = "270")]
+    Left,
+}
+
+impl Mount {
+    /// Quarter turns clockwise.
+    fn quarter_turns(&self) -> usize {
+        match self {
+            Mount::Upright => 0,
+            Mount::Right => 1,
+            Mount::Inverted => 2,
+            Mount::Left => 3,
+        }
+    }
+}
+
+/// Parse an orientation: its EXIF value, 1 to 8, or name (`normal`,
+/// `rotate90`, `transverse`, ...).
+pub fn parse_orientation(value: &str) -> Result<u16, String> {
+    let value = value.trim();
+    NAMES
+        .iter()
+        .find(|(name, number)| value.eq_ignore_ascii_case(name) || value == number.to_string())
+        .map(|(_, number)| *number)
+        .ok_or_else(|| {
+            let names: Vec<&str> = NAMES.iter().map(|(name, _)| *name).collect();
+            format!(
+                "'{}' is not an orientation: use 1-8 or one of {}",
+                value,
+                names.join(", ")
+            )
+        })
+}
+
+/// `orientation` followed by a quarter turn clockwise.
+pub fn rotate_cw(orientation: u16) -> u16 {
+    match orientation {
+        1 => 6,
+        6 => 3,
+        3 => 8,
+        8 => 1,
+        2 => 7,
+        7 => 4,
+        4 => 5,
+        5 => 2,
+        other => other,
+    }
+}
+
+/// The orientation for a session: `orientation` if given, otherwise the
+/// Hy6's turned by the back's `mount`.
+pub fn for_session(orientation: Option<u16>, mount: Mount) -> u16 {
+    orientation.unwrap_or_else(|| (0..mount.quarter_turns()).fold(HY6, |o, _| rotate_cw(o)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::orient;
+
+    #[test]
+    fn test_orientation() {
+        let data = [1, 2, 3, 4, 5, 6];
+        for o in 1..=8 {
+            let (turned, width, height) = orient(&data, 3, 2, 1, o);
+            assert_eq!(
+                orient(&turned, width, height, 1, 6),
+                orient(&data, 3, 2, 1, rotate_cw(o))
+            );
+        }
+        assert_eq!(for_session(None, Mount::Upright), HY6);
+        assert_eq!(for_session(None, Mount::Right), 4);
+        assert_eq!(for_session(None, Mount::Left), 2);
+        assert_eq!(for_session(Some(1), Mount::Right), 1);
+        assert_eq!(parse_orientation("Rotate90"), Ok(6));
+        assert_eq!(parse_orientation("3"), Ok(3));
+        assert!(parse_orientation("9").is_err());
+    }
+}
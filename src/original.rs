@@ -0,0 +1,245 @@
+/*
+Source files embedded in a DNG for archival.
+
+The IA file goes in OriginalRawFileData, with its name in
+OriginalRawFileName and the MD5 of the stored data in OriginalRawFileDigest.
+That tag holds a single file, so BR/WR references, when embedded too, are
+stored the same way as records in the DNG private data.
+
+Stored layout, as in the DNG spec (big endian): the data fork's
+uncompressed length, then for a non-empty fork a table of block count + 1
+offsets, relative to the start of the table, and the zlib-compressed
+64 KiB blocks. An empty resource fork follows.
+*/
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use log::{debug, warn};
+use rayon::prelude::{ParallelIterator, ParallelSlice};
+
+/// Uncompressed bytes per block.
+pub const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Which source files are embedded in each DNG.
+#[derive(Debug, PartialEq, Clone, Copy, clap::ValueEnum)]
+pub enum Embed {
+    /// Nothing
+    None,
+    /// The IA file
+    Ia,
+    /// The IA file and its BR/WR references
+    #[value(name = "ia+refs")]
+    IaRefs,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Compress `bytes` into the OriginalRawFileData layout.
+pub fn encode(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    encode_from(bytes, bytes.len() as u64)
+}
+
+/// Compress the `len` bytes read from `reader` into the OriginalRawFileData
+/// layout. Blocks are read and compressed a group at a time, so only the
+/// compressed data is ever held whole.
+pub fn encode_from(mut reader: impl Read, len: u64) -> io::Result<Vec<u8>> {
+    let len = u32::try_from(len)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file too large to embed"))?;
+    let mut out = len.to_be_bytes().to_vec();
+    let group = rayon::current_num_threads().max(1) * BLOCK_SIZE;
+    let mut blocks = Vec::with_capacity((len as usize).div_ceil(BLOCK_SIZE));
+    let mut buffer = Vec::new();
+    let mut left = len as usize;
+    while left > 0 {
+        buffer.resize(left.min(group), 0);
+        reader.read_exact(&mut buffer)?;
+        let compressed = buffer
+            .par_chunks(BLOCK_SIZE)
+            .map(|block| {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(block)?;
+                encoder.finish()
+            })
+            .collect::<io::Result<Vec<Vec<u8>>>>()?;
+        blocks.extend(compressed);
+        left -= buffer.len();
+    }
+    if !blocks.is_empty() {
+        let mut offset = (blocks.len() + 1) * 4;
+        for block in &blocks {
+            out.extend_from_slice(&(offset as u32).to_be_bytes());
+            offset += block.len();
+        }
+        out.extend_from_slice(&(offset as u32).to_be_bytes());
+        for block in &blocks {
+            out.extend_from_slice(block);
+        }
+    }
+    // Empty resource fork.
+    out.extend_from_slice(&0u32.to_be_bytes());
+    Ok(out)
+}
+
+/// Recover the data fork stored by `encode`.
+pub fn decode(data: &[u8]) -> io::Result<Vec<u8>> {
+    let read_u32 = |at: usize| -> io::Result<usize> {
+        data.get(at..at + 4)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()) as usize)
+            .ok_or_else(|| invalid("truncated original file data".to_string()))
+    };
+    let len = read_u32(0)?;
+    let blocks = len.div_ceil(BLOCK_SIZE);
+    let table = 4;
+    let mut out = Vec::with_capacity(len);
+    for block in 0..blocks {
+        let start = table + read_u32(table + block * 4)?;
+        let end = table + read_u32(table + (block + 1) * 4)?;
+        let compressed = data
+            .get(start..end)
+            .ok_or_else(|| invalid(format!("block {} out of range", block)))?;
+        ZlibDecoder::new(compressed).read_to_end(&mut out)?;
+    }
+    if out.len() != len {
+        return Err(invalid(format!(
+            "original file data holds {} bytes, expected {}",
+            out.len(),
+            len
+        )));
+    }
+    Ok(out)
+}
+
+/// A source file, compressed for embedding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmbeddedFile {
+    pub name: String,
+    /// `encode`d contents
+    pub data: Vec<u8>,
+}
+
+impl EmbeddedFile {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let name = path
+            .file_name()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let data = encode_from(file, len)?;
+        debug!("Embedding {} ({} bytes stored)", path.display(), data.len());
+        Ok(EmbeddedFile { name, data })
+    }
+
+    /// OriginalRawFileDigest: the MD5 of the stored data.
+    pub fn digest(&self) -> [u8; 16] {
+        md5::compute(&self.data).0
+    }
+
+    /// The file's original bytes.
+    pub fn contents(&self) -> io::Result<Vec<u8>> {
+        decode(&self.data)
+    }
+
+    /// A private data record: the name's length as a big-endian u16, the
+    /// name and the stored data.
+    pub fn to_record(&self) -> Vec<u8> {
+        let mut record = (self.name.len() as u16).to_be_bytes().to_vec();
+        record.extend_from_slice(self.name.as_bytes());
+        record.extend_from_slice(&self.data);
+        record
+    }
+
+    pub fn from_record(record: &[u8]) -> io::Result<Self> {
+        let truncated = || invalid("truncated embedded file record".to_string());
+        let len = record
+            .get(..2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+            .ok_or_else(truncated)?;
+        let name = record.get(2..2 + len).ok_or_else(truncated)?;
+        Ok(EmbeddedFile {
+            name: String::from_utf8_lossy(name).to_string(),
+            data: record[2 + len..].to_vec(),
+        })
+    }
+}
+
+/// The source files embedded in one DNG.
+#[derive(Debug, Clone, Default)]
+pub struct Originals {
+    pub ia: Option<EmbeddedFile>,
+    pub refs: Vec<EmbeddedFile>,
+}
+
+impl Originals {
+    /// Read and compress the files `embed` asks for. Missing references are
+    /// left out with a warning; a missing IA is an error.
+    pub fn collect(ia: &Path, refs: &[&Path], embed: Embed) -> io::Result<Self> {
+        let mut originals = Originals::default();
+        if embed == Embed::None {
+            return Ok(originals);
+        }
+        originals.ia = Some(EmbeddedFile::read(ia)?);
+        if embed == Embed::IaRefs {
+            for path in refs {
+                match EmbeddedFile::read(path) {
+                    Ok(file) => originals.refs.push(file),
+                    Err(e) => warn!("{}: not embedded: {}", path.display(), e),
+                }
+            }
+        }
+        Ok(originals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iadng;
+    use crate::sinar_ia::SinarIAMeta;
+
+    #[test]
+    fn test_embed_originals() {
+        // Several blocks, the last one partial, of poorly compressible data.
+        let bytes: Vec<u8> = (0..BLOCK_SIZE * 2 + 123)
+            .map(|i| (i * 7919 % 251) as u8)
+            .collect();
+        let stored = encode(&bytes).unwrap();
+        assert_eq!(stored[..4], (bytes.len() as u32).to_be_bytes());
+        assert_eq!(decode(&stored).unwrap(), bytes);
+        assert_eq!(decode(&encode(&[]).unwrap()).unwrap(), Vec::<u8>::new());
+        assert!(decode(&stored[..stored.len() / 2]).is_err());
+        // A reader that ends early is an error, not a short file.
+        assert!(encode_from(&bytes[..1000], bytes.len() as u64).is_err());
+
+        let dir = crate::test_dir("embed");
+        let ia = dir.join("00000001.IA");
+        let br = dir.join("00000002.BR");
+        std::fs::write(&ia, &bytes).unwrap();
+        std::fs::write(&br, vec![3; 1000]).unwrap();
+        let missing = dir.join("00000003.WR");
+
+        let none = Originals::collect(&ia, &[&br, &missing], Embed::None).unwrap();
+        assert!(none.ia.is_none() && none.refs.is_empty());
+        let originals = Originals::collect(&ia, &[&br, &missing], Embed::IaRefs).unwrap();
+        let embedded_ia = originals.ia.as_ref().unwrap();
+        assert_eq!(embedded_ia.name, "00000001.IA");
+        assert_eq!(embedded_ia.contents().unwrap(), bytes);
+        assert_eq!(originals.refs.len(), 1);
+
+        // References travel as private data records.
+        let data = iadng::private_data(&SinarIAMeta::default(), None, &originals.refs);
+        let records = iadng::parse_private_data(&data).unwrap();
+        assert_eq!(records[1].0, iadng::PRIVATE_REFERENCE);
+        let reference = EmbeddedFile::from_record(records[1].1).unwrap();
+        assert_eq!(reference, originals.refs[0]);
+        assert_eq!(reference.contents().unwrap(), vec![3; 1000]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
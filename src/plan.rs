@@ -0,0 +1,379 @@
+/*
+Batch planning: everything that can be known about a conversion from the
+META lumps and the BR/WR lump directories, without decoding RAW0.
+*/
+
+use std::fs;
+use std::io;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+
+use crate::calibrate::Calibration;
+use crate::capture::CaptureTime;
+use crate::demosaic;
+use crate::export::OutputFormat;
+use crate::iadng;
+use crate::naming::{NameTemplate, OnCollision, OutputNames};
+use crate::original::Embed;
+use crate::pwad::Pwad;
+use crate::sinar_ia::{
+    ConvertOptions, SinarIAMeta, BLACK0_KEY, BLACK1_KEY, THUMB_HT, THUMB_WD, WHITE_KEY,
+};
+
+// Rough allowance for IFDs, EXIF and tag data on top of the pixel data.
+const DNG_OVERHEAD: u64 = 64 * 1024;
+
+#[derive(Debug)]
+pub struct PlannedFrame {
+    pub ia: PathBuf,
+    pub meta: Option<SinarIAMeta>,
+    /// Output path relative to the output directory, if the frame will be written
+    pub output: Option<PathBuf>,
+    pub black_ref: Option<PathBuf>,
+    pub white_ref: Option<PathBuf>,
+    pub exists: bool,
+    pub est_bytes: u64,
+    pub problems: Vec<String>,
+}
+
+impl PlannedFrame {
+    /// Whether the frame can be converted at all.
+    pub fn is_convertible(&self) -> bool {
+        self.output.is_some() && self.black_ref.is_some()
+    }
+}
+
+/// Every `.IA` file under `path` (or `path` itself), sorted by name.
+pub fn find_ia_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "IA") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Estimated size of the file written for a frame. PNG and embedded
+/// originals are compressed, so their estimates are upper bounds.
+pub fn estimate_size(meta: &SinarIAMeta, options: &ConvertOptions) -> u64 {
+    let (width, height, samples) = match options.format {
+        OutputFormat::Dng => (meta.width as usize, meta.height as usize, 1),
+        _ => {
+            let (width, height) =
+                demosaic::output_size(meta.width as usize, meta.height as usize, options.demosaic);
+            (width, height, 3)
+        }
+    };
+    let thumb = match options.format {
+        OutputFormat::Dng | OutputFormat::LinearDng => (THUMB_WD * THUMB_HT * 3) as u64,
+        _ => 0,
+    };
+    // BR files hold two dark frames, WR files one white frame.
+    let frame = meta.width as u64 * meta.height as u64 * size_of::<u16>() as u64;
+    let embedded = match options.embed {
+        Embed::None => 0,
+        Embed::Ia => frame + thumb,
+        Embed::IaRefs => 4 * frame + thumb,
+    };
+    (width * height * samples * size_of::<u16>()) as u64 + thumb + embedded + DNG_OVERHEAD
+}
+
+// Open a BR or WR file and check that it holds each of `keys` as a frame the
+// size of `meta`'s, as conversion will read them.
+fn check_ref(path: &Path, keys: &[&str], meta: &SinarIAMeta) -> io::Result<()> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let pwad = Pwad::from_file(path)?;
+    let frame = meta.width as u64 * meta.height as u64 * size_of::<u16>() as u64;
+    for key in keys {
+        let size = pwad.open_lump(key)?.size();
+        if size < frame {
+            return Err(invalid(format!(
+                "{} lump is {} bytes, expected {}",
+                key, size, frame
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Whether `output` exists and was converted from a frame other than `meta`'s,
+// going by the META lump its DNG keeps. Outputs that can't be traced back,
+// such as TIFFs, count as the frame's own.
+fn from_other_frame(output: &Path, meta: &SinarIAMeta) -> bool {
+    output.is_file()
+        && matches!(iadng::read_private_meta(output), Ok(Some(lump)) if lump != meta.meta_lump)
+}
+
+fn resolve_ref(ia: &Path, name: &str) -> Option<PathBuf> {
+    let path = ia.parent()?.join(name);
+    if !name.is_empty() && path.is_file() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+pub fn plan_batch(
+    files: &[PathBuf],
+    output_dir: &Path,
+    template: &NameTemplate,
+    on_collision: OnCollision,
+    options: &ConvertOptions,
+) -> Vec<PlannedFrame> {
+    let mut names = OutputNames::new();
+    files
+        .iter()
+        .map(|ia| {
+            let mut frame = PlannedFrame {
+                ia: ia.clone(),
+                meta: None,
+                output: None,
+                black_ref: None,
+                white_ref: None,
+                exists: false,
+                est_bytes: 0,
+                problems: Vec::new(),
+            };
+            let mut meta = match SinarIAMeta::from_ia(ia) {
+                Ok(meta) => meta,
+                Err(e) => {
+                    frame.problems.push(format!("unreadable META: {}", e));
+                    return frame;
+                }
+            };
+            meta.captured = CaptureTime::of_ia(ia, &options.time);
+            options.lens.apply(&mut meta);
+            if !meta.is_known_model() {
+                frame
+                    .problems
+                    .push(format!("unknown model for serial {}", meta.serial));
+            }
+            frame.black_ref = resolve_ref(ia, &meta.black_ref);
+            match &frame.black_ref {
+                Some(black_ref) => {
+                    if let Err(e) = check_ref(black_ref, &[BLACK0_KEY, BLACK1_KEY], &meta) {
+                        frame.problems.push(format!(
+                            "unreadable black reference {}: {}",
+                            meta.black_ref, e
+                        ));
+                        frame.black_ref = None;
+                    }
+                }
+                None => frame
+                    .problems
+                    .push(format!("missing black reference {}", meta.black_ref)),
+            }
+            frame.white_ref = resolve_ref(ia, &meta.white_ref);
+            if options.calibration == Calibration::DarkFlat {
+                match &frame.white_ref {
+                    Some(white_ref) => {
+                        if let Err(e) = check_ref(white_ref, &[WHITE_KEY], &meta) {
+                            frame.problems.push(format!(
+                                "unreadable white reference {}, no flat field: {}",
+                                meta.white_ref, e
+                            ));
+                            frame.white_ref = None;
+                        }
+                    }
+                    None => frame.problems.push(format!(
+                        "missing white reference {}, no flat field",
+                        meta.white_ref
+                    )),
+                }
+            }
+            if meta.is_known_model() {
+                let candidate = template.render(&meta, ia, options.format.extension());
+                let mut claimed = names.claim(candidate.clone(), ia, on_collision);
+                // An output already on disk from another frame holds its name,
+                // rather than being skipped as done or overwritten.
+                while let Ok(name) = &claimed {
+                    let existing = output_dir.join(name);
+                    if !from_other_frame(&existing, &meta) {
+                        break;
+                    }
+                    names.reserve(name, &existing);
+                    claimed = names.claim(candidate.clone(), ia, on_collision);
+                }
+                match claimed {
+                    Ok(name) => {
+                        if name != candidate {
+                            frame.problems.push(format!(
+                                "name collision, renamed from {}",
+                                candidate.display()
+                            ));
+                        }
+                        frame.exists = output_dir.join(&name).exists();
+                        frame.est_bytes = estimate_size(&meta, options);
+                        frame.output = Some(name);
+                    }
+                    Err(owner) => frame
+                        .problems
+                        .push(format!("name collision with {}", owner.display())),
+                }
+            }
+            frame.meta = Some(meta);
+            frame
+        })
+        .collect()
+}
+
+/// Print the plan as a table followed by totals.
+pub fn print_plan(frames: &[PlannedFrame]) {
+    println!(
+        "{:<14} {:>8} {:<12} {:<32} {:<3} {:<3} notes",
+        "IA", "shutter", "model", "output", "BR", "WR"
+    );
+    let yes_no = |p: &Option<PathBuf>| if p.is_some() { "ok" } else { "--" };
+    for frame in frames {
+        let name = frame
+            .ia
+            .file_name()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let (shutter, model) = match &frame.meta {
+            Some(meta) => (meta.shutter_count.to_string(), meta.model.clone()),
+            None => ("?".to_string(), "?".to_string()),
+        };
+        let output = match &frame.output {
+            Some(output) if frame.exists => format!("{} (exists)", output.display()),
+            Some(output) => output.display().to_string(),
+            None => "-".to_string(),
+        };
+        println!(
+            "{:<14} {:>8} {:<12} {:<32} {:<3} {:<3} {}",
+            name,
+            shutter,
+            model,
+            output,
+            yes_no(&frame.black_ref),
+            yes_no(&frame.white_ref),
+            frame.problems.join("; ")
+        );
+    }
+    let convertible: Vec<&PlannedFrame> = frames.iter().filter(|f| f.is_convertible()).collect();
+    let new_bytes: u64 = convertible
+        .iter()
+        .filter(|f| !f.exists)
+        .map(|f| f.est_bytes)
+        .sum();
+    println!(
+        "\n{} frames, {} convertible, {} with problems, {} outputs already exist",
+        frames.len(),
+        convertible.len(),
+        frames.iter().filter(|f| !f.problems.is_empty()).count(),
+        convertible.iter().filter(|f| f.exists).count()
+    );
+    println!(
+        "Estimated disk usage for new files: {:.1} MiB",
+        new_bytes as f64 / (1024.0 * 1024.0)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pwad;
+    use crate::sinar_ia::META_KEY;
+
+    #[test]
+    fn test_existing_output_from_other_frame() {
+        let dir = crate::test_dir("plan");
+        let meta_lump = |shutter_count: u32| {
+            let mut lump = vec![0u8; 360];
+            lump[4..8].copy_from_slice(&shutter_count.to_le_bytes());
+            lump[272..280].copy_from_slice(b"e75-0042");
+            lump
+        };
+        let ia = dir.join("0000001.IA");
+        pwad::write_pwad(&ia, &[(META_KEY, meta_lump(1234))]).unwrap();
+
+        // 1234.dng is already there, but converted from another back's frame
+        // 1234, so this frame may not take its name.
+        let output_dir = dir.join("out");
+        fs::create_dir_all(&output_dir).unwrap();
+        let mut other = meta_lump(1234);
+        other[272..280].copy_from_slice(b"e75-0099");
+        let existing = SinarIAMeta {
+            width: 4,
+            height: 2,
+            meta_lump: other,
+            ..SinarIAMeta::default()
+        };
+        let path = output_dir.join("1234.dng");
+        iadng::write_test_dng(&existing, &path);
+
+        let options = ConvertOptions::default();
+        let plan = |policy| {
+            plan_batch(
+                std::slice::from_ref(&ia),
+                &output_dir,
+                &NameTemplate::default(),
+                policy,
+                &options,
+            )
+        };
+        let frames = plan(OnCollision::Suffix);
+        assert_eq!(frames[0].output, Some(PathBuf::from("1234_1.dng")));
+        assert!(!frames[0].exists);
+        let frames = plan(OnCollision::Error);
+        assert_eq!(frames[0].output, None);
+        assert!(frames[0]
+            .problems
+            .iter()
+            .any(|problem| problem.starts_with("name collision") && problem.contains("1234.dng")));
+
+        // Written from this very frame, it is the frame's own output.
+        let own = SinarIAMeta {
+            meta_lump: meta_lump(1234),
+            ..existing
+        };
+        iadng::write_test_dng(&own, &path);
+        let frames = plan(OnCollision::Error);
+        assert_eq!(frames[0].output, Some(PathBuf::from("1234.dng")));
+        assert!(frames[0].exists);
+    }
+
+    #[test]
+    fn test_unreadable_frames() {
+        let dir = crate::test_dir("plan-refs");
+        let mut lump = vec![0u8; 360];
+        lump[272..280].copy_from_slice(b"e75-0042");
+        lump[122..133].copy_from_slice(b"00000001.BR");
+        lump[186..197].copy_from_slice(b"00000002.WR");
+        let ia = dir.join("0000001.IA");
+        pwad::write_pwad(&ia, &[(META_KEY, lump.clone())]).unwrap();
+        // A META lump cut short, and one with a serial that isn't UTF-8.
+        let short = dir.join("0000002.IA");
+        pwad::write_pwad(&short, &[(META_KEY, &lump[..300])]).unwrap();
+        let garbled = dir.join("0000003.IA");
+        lump[272] = 0xff;
+        pwad::write_pwad(&garbled, &[(META_KEY, lump)]).unwrap();
+        // The references are there, but BLACK1 and WHITE are not.
+        pwad::write_pwad(&dir.join("00000001.BR"), &[(BLACK0_KEY, vec![0; 8])]).unwrap();
+        pwad::write_pwad(&dir.join("00000002.WR"), &[("THUMB", vec![0; 8])]).unwrap();
+
+        let files = [ia, short, garbled];
+        let frames = plan_batch(
+            &files,
+            &dir.join("out"),
+            &NameTemplate::default(),
+            OnCollision::Suffix,
+            &ConvertOptions::default(),
+        );
+        assert_eq!(frames[0].black_ref, None);
+        assert_eq!(frames[0].white_ref, None);
+        assert!(!frames[0].is_convertible());
+        assert!(frames[0].problems[0].starts_with("unreadable black reference 00000001.BR"));
+        assert!(frames[0].problems[1].starts_with("unreadable white reference 00000002.WR"));
+        for frame in &frames[1..] {
+            assert!(frame.meta.is_none());
+            assert!(frame.problems[0].starts_with("unreadable META"));
+        }
+    }
+}
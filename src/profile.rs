@@ -0,0 +1,966 @@
+/*
+Colour matrices fitted to a frame of an X-Rite ColorChecker Classic.
+
+`iatodng profile` finds the chart's 24 patches in a frame, averages the CFA
+samples of each colour in the middle of each patch, and fits the back's
+matrices for the light the chart was shot under:
+
+* ForwardMatrix maps white balanced camera RGB to XYZ D50. It is fitted to
+  the chart's published L*a*b* D50 values by least squares weighted by the
+  slope of L*, so dark patches count as much as light ones, and constrained
+  to map camera neutral to D50 white.
+* ColorMatrix follows from the ForwardMatrix, the camera neutral of an
+  unconstrained fit to all the patches, and a Bradford adaptation from the
+  illuminant to D50.
+
+The fit is reported as the CIEDE2000 difference of each patch and stored in
+the back's entry in the calibration database, see `backs`.
+
+The chart is placed by the centres of its corner patches: dark skin, bluish
+green, white and black. They can be given; otherwise the frame is reduced to
+a few hundred pixels across and searched for an upright 6x4 or 4x6 grid, in
+each of the four rotations, whose cells are flat and best fitted to the
+chart's colours by a 3x3 matrix. Charts shot at a steep angle need their
+corners given.
+
+The frame is an IA file, calibrated against its BR and WR references, or a
+CFA DNG written by iatodng.
+*/
+
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+use log::warn;
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+
+use crate::backs::{ColourCalibration, Illuminant};
+use crate::calibrate;
+use crate::demosaic::cfa_color;
+use crate::iadng::{
+    self, TAG_BLACK_LEVEL, TAG_DNG_PRIVATE_DATA, TAG_IMAGE_LENGTH, TAG_IMAGE_WIDTH,
+    TAG_SAMPLES_PER_PIXEL, TAG_STRIP_BYTE_COUNTS, TAG_STRIP_OFFSETS, TAG_SUBIFDS,
+};
+use crate::pwad;
+use crate::refcache::RefCache;
+use crate::sinar_ia::{SinarIAMeta, META_KEY, RAW_KEY};
+use crate::tiffread::TiffReader;
+
+pub const PATCHES: usize = 24;
+const COLUMNS: usize = 6;
+const ROWS: usize = 4;
+/// Half the side of the window averaged in each patch, in patch pitches.
+const WINDOW: f64 = 0.2;
+/// Width, in pixels, frames are reduced to for detection.
+const DETECT_WIDTH: usize = 480;
+/// Smallest patch pitch searched for, in reduced pixels.
+const MIN_PITCH: f64 = 8.0;
+/// Worst placement score taken for a chart.
+const MAX_SCORE: f64 = 0.05;
+/// How far above the best score a refined placement still counts as a tie.
+const PLATEAU: f64 = 0.002;
+
+/// X-Rite's L*a*b* (D50) values for charts made from November 2014.
+pub const CHART_LAB: [[f64; 3]; PATCHES] = [
+    [37.54, 14.37, 14.92],
+    [64.66, 19.27, 17.50],
+    [49.32, -3.82, -22.54],
+    [43.46, -12.74, 22.72],
+    [54.94, 9.61, -24.79],
+    [70.48, -32.26, -0.37],
+    [62.73, 35.83, 56.50],
+    [39.43, 10.75, -45.17],
+    [50.57, 48.64, 16.67],
+    [30.10, 22.54, -20.87],
+    [71.77, -24.13, 58.19],
+    [71.51, 18.24, 67.37],
+    [28.37, 15.42, -49.80],
+    [54.38, -39.72, 32.27],
+    [42.43, 51.05, 28.62],
+    [81.80, 2.67, 80.41],
+    [50.63, 51.28, -14.12],
+    [49.57, -29.71, -28.32],
+    [95.19, -1.03, 2.93],
+    [81.29, -0.57, 0.44],
+    [66.89, -0.75, -0.06],
+    [50.76, -0.13, 0.14],
+    [35.63, -0.46, -0.48],
+    [20.64, 0.07, -0.46],
+];
+
+pub const PATCH_NAMES: [&str; PATCHES] = [
+    "dark skin",
+    "light skin",
+    "blue sky",
+    "foliage",
+    "blue flower",
+    "bluish green",
+    "orange",
+    "purplish blue",
+    "moderate red",
+    "purple",
+    "yellow green",
+    "orange yellow",
+    "blue",
+    "green",
+    "red",
+    "yellow",
+    "magenta",
+    "cyan",
+    "white",
+    "neutral 8",
+    "neutral 6.5",
+    "neutral 5",
+    "neutral 3.5",
+    "black",
+];
+
+/// A 3x3 matrix, row by row.
+pub type Matrix = [f64; 9];
+
+pub(crate) fn mul(a: &Matrix, b: &Matrix) -> Matrix {
+    std::array::from_fn(|i| (0..3).map(|k| a[i / 3 * 3 + k] * b[k * 3 + i % 3]).sum())
+}
+
+pub(crate) fn apply(m: &Matrix, v: [f64; 3]) -> [f64; 3] {
+    std::array::from_fn(|r| (0..3).map(|c| m[r * 3 + c] * v[c]).sum())
+}
+
+pub(crate) fn diagonal(v: [f64; 3]) -> Matrix {
+    [v[0], 0.0, 0.0, 0.0, v[1], 0.0, 0.0, 0.0, v[2]]
+}
+
+pub(crate) fn inverse(m: &Matrix) -> Option<Matrix> {
+    let [a, b, c, d, e, f, g, h, i] = *m;
+    let adjugate = [
+        e * i - f * h,
+        c * h - b * i,
+        b * f - c * e,
+        f * g - d * i,
+        a * i - c * g,
+        c * d - a * f,
+        d * h - e * g,
+        b * g - a * h,
+        a * e - b * d,
+    ];
+    let det = a * adjugate[0] + b * adjugate[3] + c * adjugate[6];
+    det.is_normal().then(|| adjugate.map(|v| v / det))
+}
+
+const BRADFORD: Matrix = [
+    0.8951, 0.2664, -0.1614, -0.7502, 1.7135, 0.0367, 0.0389, -0.0685, 1.0296,
+];
+
+// Bradford chromatic adaptation from white `from` to white `to`.
+fn bradford(from: [f64; 3], to: [f64; 3]) -> Matrix {
+    let (from, to) = (apply(&BRADFORD, from), apply(&BRADFORD, to));
+    let scale = diagonal(std::array::from_fn(|i| to[i] / from[i]));
+    mul(&inverse(&BRADFORD).unwrap(), &mul(&scale, &BRADFORD))
+}
+
+const EPSILON: f64 = 216.0 / 24389.0;
+const KAPPA: f64 = 24389.0 / 27.0;
+
+pub fn xyz_to_lab(xyz: [f64; 3], white: [f64; 3]) -> [f64; 3] {
+    let f = |t: f64| {
+        if t > EPSILON {
+            t.cbrt()
+        } else {
+            (KAPPA * t + 16.0) / 116.0
+        }
+    };
+    let [fx, fy, fz]: [f64; 3] = std::array::from_fn(|i| f(xyz[i] / white[i]));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+pub fn lab_to_xyz(lab: [f64; 3], white: [f64; 3]) -> [f64; 3] {
+    let fy = (lab[0] + 16.0) / 116.0;
+    let f = [fy + lab[1] / 500.0, fy, fy - lab[2] / 200.0];
+    std::array::from_fn(|i| {
+        let cube = f[i].powi(3);
+        let t = if cube > EPSILON {
+            cube
+        } else {
+            (116.0 * f[i] - 16.0) / KAPPA
+        };
+        t * white[i]
+    })
+}
+
+/// CIEDE2000 colour difference.
+pub fn delta_e00(lab1: [f64; 3], lab2: [f64; 3]) -> f64 {
+    let ([l1, a1, b1], [l2, a2, b2]) = (lab1, lab2);
+    let pow25_7 = 25f64.powi(7);
+    let c7 = ((a1.hypot(b1) + a2.hypot(b2)) / 2.0).powi(7);
+    let g = 0.5 * (1.0 - (c7 / (c7 + pow25_7)).sqrt());
+    let (a1, a2) = ((1.0 + g) * a1, (1.0 + g) * a2);
+    let (c1, c2) = (a1.hypot(b1), a2.hypot(b2));
+    let hue = |a: f64, b: f64| {
+        if a == 0.0 && b == 0.0 {
+            0.0
+        } else {
+            b.atan2(a).to_degrees().rem_euclid(360.0)
+        }
+    };
+    let (h1, h2) = (hue(a1, b1), hue(a2, b2));
+    let chromatic = c1 * c2 != 0.0;
+
+    let dl = l2 - l1;
+    let dc = c2 - c1;
+    let dh = match h2 - h1 {
+        _ if !chromatic => 0.0,
+        d if d > 180.0 => d - 360.0,
+        d if d < -180.0 => d + 360.0,
+        d => d,
+    };
+    let dh = 2.0 * (c1 * c2).sqrt() * (dh.to_radians() / 2.0).sin();
+
+    let l = (l1 + l2) / 2.0;
+    let c = (c1 + c2) / 2.0;
+    let h = if !chromatic {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) / 2.0
+    } else {
+        (h1 + h2 - 360.0) / 2.0
+    };
+    let t = 1.0 - 0.17 * (h - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * h).to_radians().cos()
+        + 0.32 * (3.0 * h + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * h - 63.0).to_radians().cos();
+    let d_theta = 30.0 * (-((h - 275.0) / 25.0).powi(2)).exp();
+    let c7 = c.powi(7);
+    let rc = 2.0 * (c7 / (c7 + pow25_7)).sqrt();
+    let sl = 1.0 + 0.015 * (l - 50.0).powi(2) / (20.0 + (l - 50.0).powi(2)).sqrt();
+    let sc = 1.0 + 0.045 * c;
+    let sh = 1.0 + 0.015 * c * t;
+    let rt = -(2.0 * d_theta).to_radians().sin() * rc;
+    ((dl / sl).powi(2) + (dc / sc).powi(2) + (dh / sh).powi(2) + rt * (dc / sc) * (dh / sh)).sqrt()
+}
+
+// The chart's patches in XYZ D50.
+fn chart_xyz() -> [[f64; 3]; PATCHES] {
+    CHART_LAB.map(|lab| lab_to_xyz(lab, Illuminant::D50.white()))
+}
+
+/// Where the chart is in a frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chart {
+    /// Centres of dark skin, bluish green, white and black, as (x, y) in
+    /// sensor sites
+    pub corners: [[f64; 2]; 4],
+}
+
+impl Chart {
+    /// Parse four corner patch centres: `x,y x,y x,y x,y`.
+    pub fn parse(value: &str) -> Result<Chart, String> {
+        let corners: Vec<[f64; 2]> = value
+            .split_whitespace()
+            .map(|point| {
+                let (x, y) = point.split_once(',')?;
+                Some([x.trim().parse().ok()?, y.trim().parse().ok()?])
+            })
+            .collect::<Option<_>>()
+            .ok_or_else(|| format!("'{}' is not 4 points like 812,604", value))?;
+        let corners = corners
+            .try_into()
+            .map_err(|_| format!("'{}' is not 4 points like 812,604", value))?;
+        Ok(Chart { corners })
+    }
+
+    /// Centre of `patch`, interpolated between the corners.
+    pub fn centre(&self, patch: usize) -> [f64; 2] {
+        let u = (patch % COLUMNS) as f64 / (COLUMNS - 1) as f64;
+        let v = (patch / COLUMNS) as f64 / (ROWS - 1) as f64;
+        let [top_left, top_right, bottom_left, bottom_right] = self.corners;
+        std::array::from_fn(|i| {
+            let top = top_left[i] + u * (top_right[i] - top_left[i]);
+            let bottom = bottom_left[i] + u * (bottom_right[i] - bottom_left[i]);
+            top + v * (bottom - top)
+        })
+    }
+
+    /// Distance between neighbouring patch centres.
+    pub fn pitch(&self) -> f64 {
+        let distance = |a: [f64; 2], b: [f64; 2]| (a[0] - b[0]).hypot(a[1] - b[1]);
+        let [top_left, top_right, bottom_left, _] = self.corners;
+        (distance(top_left, top_right) / (COLUMNS - 1) as f64)
+            .min(distance(top_left, bottom_left) / (ROWS - 1) as f64)
+    }
+
+    /// Mean camera RGB in the middle of each patch of a CFA `image`.
+    pub fn sample(
+        &self,
+        image: &[f64],
+        width: usize,
+        height: usize,
+        cfa: [u8; 4],
+    ) -> Result<[[f64; 3]; PATCHES], String> {
+        let half = (WINDOW * self.pitch()).max(1.0);
+        let mut patches = [[0.0; 3]; PATCHES];
+        for (patch, rgb) in patches.iter_mut().enumerate() {
+            let [x, y] = self.centre(patch);
+            let (x0, x1) = ((x - half).round(), (x + half).round());
+            let (y0, y1) = ((y - half).round(), (y + half).round());
+            if x0 < 0.0 || y0 < 0.0 || x1 >= width as f64 || y1 >= height as f64 {
+                return Err(format!("{} is outside the frame", PATCH_NAMES[patch]));
+            }
+            let (mut sums, mut counts) = ([0.0; 3], [0usize; 3]);
+            for y in y0 as usize..=y1 as usize {
+                for x in x0 as usize..=x1 as usize {
+                    let c = cfa_color(cfa, x, y);
+                    sums[c] += image[y * width + x];
+                    counts[c] += 1;
+                }
+            }
+            *rgb = std::array::from_fn(|c| sums[c] / counts[c] as f64);
+        }
+        Ok(patches)
+    }
+}
+
+// Where chart patch `patch` lies in a grid of cells, for each rotation:
+// upright and turned 180 degrees in a 6x4 grid, turned clockwise and
+// anticlockwise in a 4x6 grid.
+fn grid_cell(rotation: usize, patch: usize) -> (usize, usize) {
+    let (row, column) = (patch / COLUMNS, patch % COLUMNS);
+    match rotation {
+        0 => (column, row),
+        1 => (COLUMNS - 1 - column, ROWS - 1 - row),
+        2 => (ROWS - 1 - row, column),
+        _ => (row, COLUMNS - 1 - column),
+    }
+}
+
+// A placement of the grid in a reduced frame.
+#[derive(Debug, Clone, Copy)]
+struct Placement {
+    score: f64,
+    x0: f64,
+    y0: f64,
+    pitch: f64,
+    rotation: usize,
+}
+
+// A frame reduced to camera RGB pixels, as sums over rectangles.
+struct Reduced {
+    width: usize,
+    height: usize,
+    // Sites per pixel side
+    factor: usize,
+    // Summed-area table of R, G, B and the squared sum of the three, with a
+    // leading row and column of zeros.
+    sums: Vec<[f64; 4]>,
+    // The chart in XYZ D50, and its squared deviation from its mean.
+    reference: [[f64; 3]; PATCHES],
+    spread: f64,
+}
+
+impl Reduced {
+    fn new(image: &[f64], width: usize, height: usize, cfa: [u8; 4]) -> Self {
+        let factor = 2 * width.div_ceil(2 * DETECT_WIDTH).max(1);
+        let (rw, rh) = (width / factor, height / factor);
+        let pixels: Vec<[f64; 3]> = (0..rw * rh)
+            .into_par_iter()
+            .map(|p| {
+                let (x0, y0) = ((p % rw) * factor, (p / rw) * factor);
+                let (mut sums, mut counts) = ([0.0; 3], [0.0; 3]);
+                for y in y0..y0 + factor {
+                    for x in x0..x0 + factor {
+                        let c = cfa_color(cfa, x, y);
+                        sums[c] += image[y * width + x];
+                        counts[c] += 1.0;
+                    }
+                }
+                std::array::from_fn(|c| sums[c] / counts[c])
+            })
+            .collect();
+        let mut sums = vec![[0.0; 4]; (rw + 1) * (rh + 1)];
+        for y in 0..rh {
+            for x in 0..rw {
+                let [r, g, b] = pixels[y * rw + x];
+                let pixel = [r, g, b, (r + g + b) * (r + g + b)];
+                let i = (y + 1) * (rw + 1) + x + 1;
+                sums[i] = std::array::from_fn(|k| {
+                    pixel[k] + sums[i - 1][k] + sums[i - rw - 1][k] - sums[i - rw - 2][k]
+                });
+            }
+        }
+        let reference = chart_xyz();
+        let mean: [f64; 3] =
+            std::array::from_fn(|k| reference.iter().map(|r| r[k]).sum::<f64>() / PATCHES as f64);
+        let spread = reference
+            .iter()
+            .flat_map(|r| (0..3).map(move |k| (r[k] - mean[k]).powi(2)))
+            .sum();
+        Reduced {
+            width: rw,
+            height: rh,
+            factor,
+            sums,
+            reference,
+            spread,
+        }
+    }
+
+    // Mean RGB and luminance variance of the pixels within `half` of (x, y).
+    fn window(&self, x: usize, y: usize, half: usize) -> ([f64; 3], f64) {
+        let stride = self.width + 1;
+        let (x0, x1, y0, y1) = (x - half, x + half + 1, y - half, y + half + 1);
+        let n = ((x1 - x0) * (y1 - y0)) as f64;
+        let sum: [f64; 4] = std::array::from_fn(|k| {
+            self.sums[y1 * stride + x1][k]
+                - self.sums[y0 * stride + x1][k]
+                - self.sums[y1 * stride + x0][k]
+                + self.sums[y0 * stride + x0][k]
+        });
+        let mean = [sum[0] / n, sum[1] / n, sum[2] / n];
+        let luminance = mean.iter().sum::<f64>();
+        (mean, (sum[3] / n - luminance * luminance).max(0.0))
+    }
+
+    // How well a grid with its first cell centred at (x0, y0) matches the
+    // chart, in its best rotation: lower is better.
+    fn score(&self, x0: f64, y0: f64, pitch: f64, portrait: bool) -> Option<Placement> {
+        let (columns, rows) = if portrait {
+            (ROWS, COLUMNS)
+        } else {
+            (COLUMNS, ROWS)
+        };
+        let half = (WINDOW * pitch).round().max(1.0) as usize;
+        let mut cells = [([0.0; 3], 0.0); PATCHES];
+        for gy in 0..rows {
+            for gx in 0..columns {
+                let x = (x0 + gx as f64 * pitch).round() as isize;
+                let y = (y0 + gy as f64 * pitch).round() as isize;
+                if x < half as isize
+                    || y < half as isize
+                    || x as usize + half >= self.width
+                    || y as usize + half >= self.height
+                {
+                    return None;
+                }
+                cells[gy * columns + gx] = self.window(x as usize, y as usize, half);
+            }
+        }
+        // Flatness: noise within cells against the spread between them.
+        let luminance: Vec<f64> = cells.iter().map(|(m, _)| m.iter().sum()).collect();
+        let mean = luminance.iter().sum::<f64>() / PATCHES as f64;
+        let between: f64 = luminance.iter().map(|l| (l - mean) * (l - mean)).sum();
+        let within: f64 = cells.iter().map(|(_, v)| v).sum();
+        let flatness = within / between;
+        // Not the chart, whatever its colours; skip the fits.
+        if flatness.is_nan() || flatness >= MAX_SCORE {
+            return None;
+        }
+
+        let rotations = if portrait { 2..4 } else { 0..2 };
+        rotations
+            .filter_map(|rotation| {
+                let measured: [[f64; 3]; PATCHES] = std::array::from_fn(|patch| {
+                    let (gx, gy) = grid_cell(rotation, patch);
+                    cells[gy * columns + gx].0
+                });
+                let residual = fit_residual(&measured, &self.reference)?;
+                Some(Placement {
+                    score: residual / self.spread + flatness,
+                    x0,
+                    y0,
+                    pitch,
+                    rotation,
+                })
+            })
+            .min_by(|a, b| a.score.total_cmp(&b.score))
+    }
+
+    // The chart of a placement, in sensor sites.
+    fn chart(&self, placement: &Placement) -> Chart {
+        let corner = |patch: usize| {
+            let (gx, gy) = grid_cell(placement.rotation, patch);
+            let x = (placement.x0 + gx as f64 * placement.pitch).round();
+            let y = (placement.y0 + gy as f64 * placement.pitch).round();
+            [
+                (x + 0.5) * self.factor as f64,
+                (y + 0.5) * self.factor as f64,
+            ]
+        };
+        Chart {
+            corners: [corner(0), corner(5), corner(18), corner(23)],
+        }
+    }
+}
+
+// Squared error left by the least squares 3x3 matrix from `measured` to
+// `reference`.
+fn fit_residual(measured: &[[f64; 3]; PATCHES], reference: &[[f64; 3]; PATCHES]) -> Option<f64> {
+    let (mut a, mut b) = ([0.0; 9], [0.0; 9]);
+    for (m, y) in measured.iter().zip(reference) {
+        for r in 0..3 {
+            for c in 0..3 {
+                a[r * 3 + c] += m[r] * m[c];
+                b[r * 3 + c] += y[r] * m[c];
+            }
+        }
+    }
+    let matrix = mul(&b, &inverse(&a)?);
+    Some(
+        measured
+            .iter()
+            .zip(reference)
+            .map(|(m, y)| {
+                let fitted = apply(&matrix, *m);
+                (0..3).map(|k| (fitted[k] - y[k]).powi(2)).sum::<f64>()
+            })
+            .sum(),
+    )
+}
+
+/// Find the chart in a CFA `image`, if there is one.
+pub fn detect(image: &[f64], width: usize, height: usize, cfa: [u8; 4]) -> Option<Chart> {
+    let reduced = Reduced::new(image, width, height, cfa);
+    let mut searches = Vec::new();
+    for portrait in [false, true] {
+        let (columns, rows) = if portrait {
+            (ROWS, COLUMNS)
+        } else {
+            (COLUMNS, ROWS)
+        };
+        let max_pitch =
+            (reduced.width as f64 / columns as f64).min(reduced.height as f64 / rows as f64);
+        let mut pitch = MIN_PITCH;
+        while pitch < max_pitch {
+            searches.push((pitch, portrait));
+            pitch *= 1.04;
+        }
+    }
+    let best = |a: Option<Placement>, b: Option<Placement>| match (a, b) {
+        (Some(a), Some(b)) => Some(if b.score < a.score { b } else { a }),
+        (a, b) => a.or(b),
+    };
+    let search = |pitch: f64, portrait: bool, xs: Range<f64>, ys: Range<f64>, step: f64| {
+        let mut found = None;
+        let mut y0 = ys.start;
+        while y0 < ys.end {
+            let mut x0 = xs.start;
+            while x0 < xs.end {
+                found = best(found, reduced.score(x0, y0, pitch, portrait));
+                x0 += step;
+            }
+            y0 += step;
+        }
+        found
+    };
+    let (w, h) = (reduced.width as f64, reduced.height as f64);
+    let coarse = searches
+        .into_par_iter()
+        .map(|(pitch, portrait)| search(pitch, portrait, 0.0..w, 0.0..h, (pitch / 4.0).max(1.0)))
+        .reduce(|| None, best)?;
+
+    // Refine around the best placement, pixel by pixel. The score is flat
+    // while the windows stay inside the patches, so take the middle of the
+    // placements that score about as well as the best.
+    let step = (coarse.pitch / 4.0).max(1.0).round() as isize;
+    let portrait = coarse.rotation >= 2;
+    let refined: Vec<Placement> = (-4..=4)
+        .flat_map(|k| (-step..=step).flat_map(move |dy| (-step..=step).map(move |dx| (k, dx, dy))))
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .filter_map(|(k, dx, dy)| {
+            let pitch = coarse.pitch * (1.0 + 0.01 * k as f64);
+            reduced.score(
+                coarse.x0 + dx as f64,
+                coarse.y0 + dy as f64,
+                pitch,
+                portrait,
+            )
+        })
+        .collect();
+    let least = refined
+        .iter()
+        .fold(coarse.score, |least, p| least.min(p.score));
+    let plateau: Vec<&Placement> = refined
+        .iter()
+        .filter(|p| p.score <= least + PLATEAU && p.rotation == coarse.rotation)
+        .collect();
+    let placement = match plateau.len() {
+        0 => coarse,
+        n => Placement {
+            score: least,
+            x0: plateau.iter().map(|p| p.x0).sum::<f64>() / n as f64,
+            y0: plateau.iter().map(|p| p.y0).sum::<f64>() / n as f64,
+            pitch: plateau.iter().map(|p| p.pitch).sum::<f64>() / n as f64,
+            rotation: coarse.rotation,
+        },
+    };
+    (placement.score < MAX_SCORE).then(|| reduced.chart(&placement))
+}
+
+/// Matrices fitted to a chart.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub illuminant: Illuminant,
+    pub color_matrix: Matrix,
+    pub forward_matrix: Matrix,
+    /// Camera RGB of a neutral, green 1
+    pub neutral: [f64; 3],
+    /// CIEDE2000 difference of each patch
+    pub delta_e: [f64; PATCHES],
+}
+
+impl Profile {
+    /// Fit the matrices to the mean camera RGB of each patch of a chart shot
+    /// under `illuminant`.
+    pub fn fit(patches: &[[f64; 3]; PATCHES], illuminant: Illuminant) -> Result<Profile, String> {
+        let d50 = Illuminant::D50.white();
+        let reference = chart_xyz();
+
+        // Weighted least squares sums of a camera to XYZ fit:
+        // (dL*/dY)^2, leaving out constants, evens the error over L*.
+        let normal = |camera: &[[f64; 3]; PATCHES]| {
+            let (mut a, mut b) = ([0.0; 9], [[0.0; 3]; 3]);
+            for (x, y) in camera.iter().zip(&reference) {
+                let w = y[1].max(0.01).powf(-4.0 / 3.0);
+                for r in 0..3 {
+                    for c in 0..3 {
+                        a[r * 3 + c] += w * x[r] * x[c];
+                    }
+                    for (k, b) in b.iter_mut().enumerate() {
+                        b[r] += w * y[k] * x[r];
+                    }
+                }
+            }
+            (a, b)
+        };
+        let singular = || "the chart's patches don't span the camera's colours".to_string();
+
+        // Camera RGB of D50 white, through an unconstrained fit: the grey
+        // patches alone are not quite neutral.
+        let (a, b) = normal(patches);
+        let a_inv = inverse(&a).ok_or_else(singular)?;
+        let mut to_xyz = [0.0; 9];
+        for (k, b) in b.iter().enumerate() {
+            to_xyz[k * 3..k * 3 + 3].copy_from_slice(&apply(&a_inv, *b));
+        }
+        let neutral = apply(&inverse(&to_xyz).ok_or_else(singular)?, d50);
+        if neutral.iter().any(|n| !(n.is_finite() && *n > 0.0)) {
+            return Err("the chart's white isn't a camera colour".to_string());
+        }
+        let balanced = patches.map(|p| std::array::from_fn::<f64, 3, _>(|c| p[c] / neutral[c]));
+
+        // Each row of the ForwardMatrix minimises the same error with its sum
+        // fixed to D50 white's component.
+        let (a, b) = normal(&balanced);
+        let a_inv = inverse(&a).ok_or_else(singular)?;
+        let ones = apply(&a_inv, [1.0; 3]);
+        let ones_sum: f64 = ones.iter().sum();
+        let mut forward_matrix = [0.0; 9];
+        for (k, b) in b.iter().enumerate() {
+            let row = apply(&a_inv, *b);
+            let lagrange = (d50[k] - row.iter().sum::<f64>()) / ones_sum;
+            for c in 0..3 {
+                forward_matrix[k * 3 + c] = row[c] + lagrange * ones[c];
+            }
+        }
+
+        // XYZ under the illuminant to D50, to white balanced camera RGB, to
+        // camera RGB; scaled so the illuminant's white peaks at 1.
+        let color_matrix = mul(
+            &mul(
+                &diagonal(neutral),
+                &inverse(&forward_matrix).ok_or_else(singular)?,
+            ),
+            &bradford(illuminant.white(), d50),
+        );
+        let peak = apply(&color_matrix, illuminant.white())
+            .into_iter()
+            .fold(0.0, f64::max);
+        let color_matrix = color_matrix.map(|v| v / peak);
+
+        let delta_e = std::array::from_fn(|i| {
+            let xyz = apply(&forward_matrix, balanced[i]);
+            delta_e00(xyz_to_lab(xyz, d50), CHART_LAB[i])
+        });
+        Ok(Profile {
+            illuminant,
+            color_matrix,
+            forward_matrix,
+            neutral: neutral.map(|n| n / neutral[1]),
+            delta_e,
+        })
+    }
+
+    pub fn mean_delta_e(&self) -> f64 {
+        self.delta_e.iter().sum::<f64>() / PATCHES as f64
+    }
+
+    pub fn max_delta_e(&self) -> f64 {
+        self.delta_e.iter().copied().fold(0.0, f64::max)
+    }
+
+    /// The matrices, for the back's entry in the calibration database.
+    pub fn calibration(&self) -> ColourCalibration {
+        ColourCalibration {
+            illuminant: self.illuminant,
+            color_matrix: self.color_matrix,
+            forward_matrix: Some(self.forward_matrix),
+        }
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A frame's CFA samples, black subtracted and linear.
+pub struct ChartFrame {
+    pub meta: SinarIAMeta,
+    pub image: Vec<f64>,
+}
+
+/// Read an IA file, calibrated against its references, or a CFA DNG
+/// written by iatodng.
+pub fn read_frame(path: &Path, cache: &RefCache) -> io::Result<ChartFrame> {
+    let is_dng = path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("dng"));
+    if is_dng {
+        return read_dng(path);
+    }
+    let pwad = pwad::Pwad::from_file(path)?;
+    let meta = SinarIAMeta::process_meta(&pwad.read_lump_by_tag(META_KEY)?)?;
+    if !meta.is_known_model() {
+        return Err(invalid(format!(
+            "Unknown model for serial '{}'",
+            meta.serial
+        )));
+    }
+    let (width, height) = (meta.width as usize, meta.height as usize);
+    let folder = path.parent().unwrap();
+    let raw = calibrate::decode_u16_le(&pwad.read_lump_by_tag(RAW_KEY)?, width, height);
+    let black = cache.dark(&folder.join(&meta.black_ref), width, height)?;
+    let white_ref = folder.join(&meta.white_ref);
+    let gains = cache
+        .gains::<f64>(&white_ref, width, height)
+        .map_err(|e| {
+            warn!(
+                "{}: chart measured without a flat field: {}",
+                white_ref.display(),
+                e
+            )
+        })
+        .ok();
+    let image = calibrate::calibrate::<f64>(&raw, &black, gains.as_ref().map(|g| g.as_slice()));
+    Ok(ChartFrame {
+        meta,
+        image: image.to_vec(),
+    })
+}
+
+// The raw IFD's samples, unscaled if they were calibrated, or less the black
+// level if they are sensor counts.
+fn read_dng(path: &Path) -> io::Result<ChartFrame> {
+    let mut tiff = TiffReader::open(path)?;
+    let root = tiff.read_ifd(tiff.first_ifd)?;
+    let records = root
+        .get(TAG_DNG_PRIVATE_DATA)
+        .and_then(|e| iadng::parse_private_data(&e.data))
+        .ok_or_else(|| invalid("not a DNG written by iatodng".to_string()))?;
+    let record = |name: [u8; 4]| {
+        records
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, data)| *data)
+    };
+    let mut meta = SinarIAMeta::process_meta(
+        record(iadng::PRIVATE_META)
+            .filter(|data| !data.is_empty())
+            .ok_or_else(|| invalid("no META lump in DNGPrivateData".to_string()))?,
+    )?;
+    let raw_offset = root
+        .get(TAG_SUBIFDS)
+        .and_then(|e| e.as_u32s().first().copied())
+        .ok_or_else(|| invalid("no raw SubIFD".to_string()))?;
+    let raw = tiff.read_ifd(raw_offset)?;
+    if raw.get(TAG_SAMPLES_PER_PIXEL).map(|e| e.as_u32s()) != Some(vec![1]) {
+        return Err(invalid("only CFA DNGs can be profiled".to_string()));
+    }
+    let offsets = raw
+        .get(TAG_STRIP_OFFSETS)
+        .map(|e| e.as_u32s())
+        .unwrap_or_default();
+    let sizes = raw
+        .get(TAG_STRIP_BYTE_COUNTS)
+        .map(|e| e.as_u32s())
+        .unwrap_or_default();
+    let mut bytes = Vec::new();
+    for (&offset, &size) in offsets.iter().zip(&sizes) {
+        bytes.extend_from_slice(&tiff.read_at(offset as u64, size as usize)?);
+    }
+    let size = |tag: u16| raw.get(tag).and_then(|e| e.as_u32s().first().copied());
+    (meta.width, meta.height) = match (size(TAG_IMAGE_WIDTH), size(TAG_IMAGE_LENGTH)) {
+        (Some(width), Some(height)) => (width, height),
+        _ => return Err(invalid("raw IFD has no size".to_string())),
+    };
+    let (width, height) = (meta.width as usize, meta.height as usize);
+    if bytes.len() != width * height * 2 {
+        return Err(invalid(format!(
+            "raw data holds {} bytes for {}x{} samples",
+            bytes.len(),
+            width,
+            height
+        )));
+    }
+    // write_dng_data stores samples big-endian whatever the file's byte order
+    let counts = bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]));
+    let image = match record(iadng::PRIVATE_SCALED) {
+        Some(scaled) if scaled.len() == 16 => {
+            let min = f64::from_be_bytes(scaled[..8].try_into().unwrap());
+            let max = f64::from_be_bytes(scaled[8..].try_into().unwrap());
+            let scale = (max - min) / u16::MAX as f64;
+            counts.map(|v| v as f64 * scale + min).collect()
+        }
+        Some(_) => return Err(invalid("bad SCAL record in DNGPrivateData".to_string())),
+        None => {
+            let black = raw
+                .get(TAG_BLACK_LEVEL)
+                .map(|e| e.as_f64s())
+                .filter(|b| b.len() == 4)
+                .unwrap_or(vec![0.0; 4]);
+            counts
+                .enumerate()
+                .map(|(i, v)| {
+                    let (x, y) = (i % width, i / width);
+                    v as f64 - black[((y & 1) << 1) | (x & 1)]
+                })
+                .collect()
+        }
+    };
+    Ok(ChartFrame { meta, image })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backs::BackCalibration;
+    use crate::original::Originals;
+    use crate::sinar_ia;
+    use ndarray::Array1;
+
+    #[test]
+    fn test_profile() {
+        // Sharma, Wu and Dalal's CIEDE2000 test pairs 1 and 17.
+        let delta_e = delta_e00([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485]);
+        assert!((delta_e - 2.0425).abs() < 1e-4, "ΔE00 {}", delta_e);
+        let delta_e = delta_e00([50.0, 2.5, 0.0], [73.0, 25.0, -18.0]);
+        assert!((delta_e - 27.1492).abs() < 1e-4, "ΔE00 {}", delta_e);
+
+        // A linear camera whose ForwardMatrix is ProPhoto's, shooting the
+        // chart under D50, turned 180 degrees, on a grey gradient.
+        let forward = [
+            0.7977, 0.1352, 0.0313, 0.2880, 0.7119, 0.0001, 0.0, 0.0, 0.82521,
+        ];
+        let neutral = [0.45, 1.0, 0.65];
+        let d50 = Illuminant::D50.white();
+        let to_camera = |xyz: [f64; 3]| {
+            let [a, b, c, d, e, f, g, h, i] = forward;
+            let det = a * (e * i - f * h) - b * (d * i - f * g) + c * (d * h - e * g);
+            let inverse = [
+                (e * i - f * h) / det,
+                (c * h - b * i) / det,
+                (b * f - c * e) / det,
+                (f * g - d * i) / det,
+                (a * i - c * g) / det,
+                (c * d - a * f) / det,
+                (d * h - e * g) / det,
+                (b * g - a * h) / det,
+                (a * e - b * d) / det,
+            ];
+            let balanced: [f64; 3] =
+                std::array::from_fn(|r| (0..3).map(|c| inverse[r * 3 + c] * xyz[c]).sum::<f64>());
+            std::array::from_fn::<f64, 3, _>(|c| 20000.0 * neutral[c] * balanced[c])
+        };
+        let (width, height) = (720, 540);
+        let (pitch, left, top) = (70.0, 160.0, 130.0);
+        let cfa = sinar_ia::E75_CFA;
+        let mut image = vec![0.0; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                let mut rgb = [2000.0 + 2.0 * x as f64; 3];
+                let (u, v) = ((x as f64 - left) / pitch, (y as f64 - top) / pitch);
+                if (-0.55..5.55).contains(&u) && (-0.55..3.55).contains(&v) {
+                    // The chart's black surround, then the patches.
+                    rgb = [30.0; 3];
+                    let (column, row) = (u.round(), v.round());
+                    if (u - column).abs() < 0.42 && (v - row).abs() < 0.42 {
+                        let patch = (3 - row as usize) * 6 + (5 - column as usize);
+                        rgb = to_camera(lab_to_xyz(CHART_LAB[patch], d50));
+                    }
+                }
+                image[y * width + x] = rgb[cfa_color(cfa, x, y)];
+            }
+        }
+
+        // Written and read back as a DNG.
+        let mut meta = SinarIAMeta {
+            serial: "e75-0042".to_string(),
+            width: width as u32,
+            height: height as u32,
+            meta_lump: vec![0; 360],
+            ..SinarIAMeta::default()
+        };
+        meta.meta_lump[272..280].copy_from_slice(b"e75-0042");
+        let dir = crate::test_dir("chart");
+        let path = dir.join("FRAME.dng");
+        iadng::write_1d_array_to_dng(
+            &Array1::from_vec(image),
+            &[0; 12],
+            &path,
+            &meta,
+            Originals::default(),
+        )
+        .unwrap();
+        let frame = read_frame(&path, &RefCache::new(0, None)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(frame.meta.serial, meta.serial);
+
+        let expected = Chart::parse("510,340 160,340 510,130 160,130").unwrap();
+        let chart = detect(&frame.image, width, height, cfa).unwrap();
+        for (found, expected) in chart.corners.iter().zip(expected.corners) {
+            assert!(
+                (found[0] - expected[0]).abs() < 8.0 && (found[1] - expected[1]).abs() < 8.0,
+                "corner {:?}, expected {:?}",
+                found,
+                expected
+            );
+        }
+        let patches = chart.sample(&frame.image, width, height, cfa).unwrap();
+        let fitted = Profile::fit(&patches, Illuminant::D50).unwrap();
+        assert!(fitted.max_delta_e() < 0.1, "ΔE00 {:?}", fitted.delta_e);
+        for (fitted, expected) in fitted.forward_matrix.iter().zip(forward) {
+            assert!((fitted - expected).abs() < 1e-3);
+        }
+        for (fitted, expected) in fitted.neutral.iter().zip(neutral) {
+            assert!((fitted - expected / neutral[1]).abs() < 1e-3);
+        }
+        // Under D50 the ColorMatrix takes D50 white to the camera neutral.
+        let [a, b, c, d, e, f, g, h, i] = fitted.color_matrix;
+        let white = [
+            a * d50[0] + b * d50[1] + c * d50[2],
+            d * d50[0] + e * d50[1] + f * d50[2],
+            g * d50[0] + h * d50[1] + i * d50[2],
+        ];
+        for (white, neutral) in white.iter().zip(neutral) {
+            assert!((white - neutral).abs() < 1e-3, "{:?}", white);
+        }
+        // Stored with the back's other colour calibrations.
+        let mut back = BackCalibration::default();
+        back.set_colour(fitted.calibration());
+        back.set_colour(fitted.calibration());
+        assert_eq!(back.colour.len(), 1);
+        assert_eq!(back.colour[0].forward_matrix, Some(fitted.forward_matrix));
+    }
+}
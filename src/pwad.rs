@@ -5,11 +5,10 @@ Generic PWAD reader
 
 extern crate byteorder;
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::fs::File;
-use std::io::{self, Read, Seek, SeekFrom};
-use std::path::PathBuf;
-use std::str::FromStr;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(Debug)]
 pub struct Pwad {
@@ -33,7 +32,8 @@ pub struct LumpDirectoryEntry {
 }
 
 impl Pwad {
-    pub fn from_file(file_path: &str) -> io::Result<Self> {
+    pub fn from_file(file_path: impl AsRef<Path>) -> io::Result<Self> {
+        let file_path = file_path.as_ref();
         let mut file = File::open(file_path)?;
         let header = read_wad_header(&mut file)?;
         let directory = read_lump_directory(&mut file, header.directory_offset, header.num_lumps)?;
@@ -41,10 +41,28 @@ impl Pwad {
         Ok(Pwad {
             header,
             directory,
-            filename: PathBuf::from_str(file_path).unwrap(),
+            filename: file_path.to_path_buf(),
         })
     }
 
+    pub fn open_lump(&self, tag: &str) -> io::Result<LumpReader> {
+        match self
+            .directory
+            .iter()
+            .find(|entry| entry.name.starts_with(tag))
+        {
+            Some(lump) => Ok(LumpReader {
+                file: File::open(&self.filename)?,
+                offset: lump.offset as u64,
+                size: lump.size as u64,
+            }),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Lump with tag '{}' not found", tag),
+            )),
+        }
+    }
+
     pub fn read_lump_by_tag(&self, tag: &str) -> io::Result<Vec<u8>> {
         let lump = self
             .directory
@@ -66,6 +84,64 @@ impl Pwad {
     }
 }
 
+/// Random access to one lump, for lumps too large to read in one go.
+#[derive(Debug)]
+pub struct LumpReader {
+    file: File,
+    offset: u64,
+    size: u64,
+}
+
+impl LumpReader {
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Fill `buffer` from `start` bytes into the lump.
+    pub fn read_at(&mut self, start: u64, buffer: &mut [u8]) -> io::Result<()> {
+        if start + buffer.len() as u64 > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Read of {}+{} past end of {} byte lump",
+                    start,
+                    buffer.len(),
+                    self.size
+                ),
+            ));
+        }
+        self.file.seek(SeekFrom::Start(self.offset + start))?;
+        self.file.read_exact(buffer)
+    }
+}
+
+/// Write a PWAD holding `lumps` in order. Names longer than 8 bytes are cut.
+pub fn write_pwad<D: AsRef<[u8]>>(path: &Path, lumps: &[(&str, D)]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let directory_offset = 12
+        + lumps
+            .iter()
+            .map(|(_, data)| data.as_ref().len())
+            .sum::<usize>();
+    file.write_all(b"PWAD")?;
+    file.write_u32::<LittleEndian>(lumps.len() as u32)?;
+    file.write_u32::<LittleEndian>(directory_offset as u32)?;
+    for (_, data) in lumps {
+        file.write_all(data.as_ref())?;
+    }
+    let mut offset = 12;
+    for (name, data) in lumps {
+        file.write_u32::<LittleEndian>(offset as u32)?;
+        file.write_u32::<LittleEndian>(data.as_ref().len() as u32)?;
+        let mut name = name.as_bytes().to_vec();
+        name.resize(8, 0);
+        file.write_all(&name)?;
+        offset += data.as_ref().len();
+    }
+    file.flush()?;
+    file.get_ref().sync_all()
+}
+
 fn read_wad_header<R: Read + Seek>(reader: &mut R) -> io::Result<WadHeader> {
     let mut identification = vec![0; 4];
     reader.read_exact(&mut identification)?;
@@ -0,0 +1,451 @@
+/*
+Cache of decoded calibration references.
+
+A session usually shares one BR and one WR file between all its frames, so
+the dark frame and flat-field gains are decoded once and kept in memory,
+keyed on the reference's path and modification time and the size decoded
+to, as one LCC frame gives a grid per frame size. With a cache
+directory the same data is also persisted as sidecar files, so repeated
+runs skip decoding and gain computation altogether.
+
+Sidecar layout (little endian): magic, format version, element tag, source
+mtime (seconds, nanoseconds), source length, width, height, then the
+samples.
+*/
+
+use std::any::Any;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+
+use crate::calibrate::{self, Sample};
+use crate::iadng;
+use crate::lcc::{self, LccMap};
+use crate::noise::DarkStats;
+use crate::pwad;
+use crate::sinar_ia::{BLACK0_KEY, BLACK1_KEY, WHITE_KEY};
+
+const MAGIC: &[u8; 4] = b"IACC";
+const VERSION: u32 = 1;
+const HEADER_LEN: usize = 4 + 4 + 1 + 8 + 4 + 8 + 4 + 4;
+
+/// Default in-memory limit: a few E75 references.
+pub const DEFAULT_LIMIT: usize = 1024 * 1024 * 1024;
+
+/// A sample type that can be stored in a sidecar file.
+pub trait Element: Copy + Send + Sync + 'static {
+    const TAG: u8;
+    const SIZE: usize;
+    fn write_le(self, out: &mut Vec<u8>);
+    fn read_le(bytes: &[u8]) -> Self;
+}
+
+impl Element for u16 {
+    const TAG: u8 = 1;
+    const SIZE: usize = 2;
+    fn write_le(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+    fn read_le(bytes: &[u8]) -> Self {
+        u16::from_le_bytes([bytes[0], bytes[1]])
+    }
+}
+
+impl Element for f32 {
+    const TAG: u8 = 2;
+    const SIZE: usize = 4;
+    fn write_le(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+    fn read_le(bytes: &[u8]) -> Self {
+        f32::from_le_bytes(bytes[..4].try_into().unwrap())
+    }
+}
+
+impl Element for f64 {
+    const TAG: u8 = 3;
+    const SIZE: usize = 8;
+    fn write_le(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+    fn read_le(bytes: &[u8]) -> Self {
+        f64::from_le_bytes(bytes[..8].try_into().unwrap())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Key {
+    path: PathBuf,
+    modified: SystemTime,
+    kind: String,
+    width: usize,
+    height: usize,
+}
+
+struct Entry {
+    key: Key,
+    bytes: usize,
+    data: Arc<dyn Any + Send + Sync>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u32,
+    pub sidecar_hits: u32,
+    pub misses: u32,
+}
+
+/// Decoded BR/WR data shared across a batch, bounded by `limit` bytes.
+pub struct RefCache {
+    limit: usize,
+    dir: Option<PathBuf>,
+    // Least recently used first.
+    entries: Mutex<Vec<Entry>>,
+    stats: Mutex<CacheStats>,
+}
+
+impl RefCache {
+    pub fn new(limit: usize, dir: Option<PathBuf>) -> Self {
+        RefCache {
+            limit,
+            dir,
+            entries: Mutex::new(Vec::new()),
+            stats: Mutex::new(CacheStats::default()),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        *self.stats.lock().unwrap()
+    }
+
+    /// The dark frame (BLACK1) of the BR file at `path`.
+    pub fn dark(&self, path: &Path, width: usize, height: usize) -> io::Result<Arc<Vec<u16>>> {
+        self.get("dark", path, width, height, |pwad| {
+            Ok(calibrate::decode_u16_le(
+                &pwad.read_lump_by_tag(BLACK1_KEY)?,
+                width,
+                height,
+            ))
+        })
+    }
+
+    /// Flat-field gains for `S` from the WR file at `path`.
+    pub fn gains<S: Sample>(
+        &self,
+        path: &Path,
+        width: usize,
+        height: usize,
+    ) -> io::Result<Arc<Vec<S::Gain>>> {
+        let kind = format!("gain-{}", std::any::type_name::<S>());
+        self.get(&kind, path, width, height, |pwad| {
+            let white = calibrate::decode_u16_le(&pwad.read_lump_by_tag(WHITE_KEY)?, width, height);
+            Ok(S::gains(&white))
+        })
+    }
+
+    /// Read noise variance per CFA colour of the BR file at `path`, see
+    /// `noise::DarkStats`.
+    pub fn read_noise(
+        &self,
+        path: &Path,
+        width: usize,
+        height: usize,
+        cfa: [u8; 4],
+    ) -> io::Result<Arc<Vec<f64>>> {
+        self.get("read-noise", path, 3, 1, |pwad| {
+            let black0 =
+                calibrate::decode_u16_le(&pwad.read_lump_by_tag(BLACK0_KEY)?, width, height);
+            let black1 =
+                calibrate::decode_u16_le(&pwad.read_lump_by_tag(BLACK1_KEY)?, width, height);
+            Ok(DarkStats::measure(&black0, &black1, width, cfa, 0)
+                .read_noise()
+                .to_vec())
+        })
+    }
+
+    /// Gains of the LCC frame at `path`, for a `width` x `height` frame; see
+    /// `lcc::LccMap`.
+    pub fn lcc(&self, path: &Path, width: usize, height: usize) -> io::Result<Arc<Vec<f32>>> {
+        // Sidecars hold one element per site of the size they are keyed on.
+        let (rows, cols) = LccMap::grid(width, height);
+        self.get("lcc", path, 4 * cols, rows, |_| {
+            let map = lcc::measure_frame(path, self)?;
+            if (map.width, map.height) != (width, height) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "LCC frame is {}x{}, expected {}x{}",
+                        map.width, map.height, width, height
+                    ),
+                ));
+            }
+            Ok(Arc::try_unwrap(map.gains).unwrap_or_else(|gains| (*gains).clone()))
+        })
+    }
+
+    fn get<T: Element>(
+        &self,
+        kind: &str,
+        path: &Path,
+        width: usize,
+        height: usize,
+        load: impl FnOnce(&pwad::Pwad) -> io::Result<Vec<T>>,
+    ) -> io::Result<Arc<Vec<T>>> {
+        let source = fs::metadata(path)?;
+        let key = Key {
+            path: path.to_path_buf(),
+            modified: source.modified()?,
+            kind: kind.to_string(),
+            width,
+            height,
+        };
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some(i) = entries.iter().position(|e| e.key == key) {
+                let entry = entries.remove(i);
+                let data = entry.data.clone();
+                entries.push(entry);
+                if let Ok(data) = data.downcast::<Vec<T>>() {
+                    self.stats.lock().unwrap().hits += 1;
+                    return Ok(data);
+                }
+            }
+        }
+
+        let sidecar = self
+            .dir
+            .as_ref()
+            .map(|dir| dir.join(sidecar_name(path, kind, width, height)));
+        let header = Header {
+            tag: T::TAG,
+            modified: key.modified,
+            source_len: source.len(),
+            width: width as u32,
+            height: height as u32,
+        };
+        let cached =
+            sidecar
+                .as_ref()
+                .and_then(|sidecar| match read_sidecar::<T>(sidecar, &header) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        warn!("{}: ignoring calibration cache: {}", sidecar.display(), e);
+                        None
+                    }
+                });
+        let data = match cached {
+            Some(data) => {
+                debug!("Loaded {} of {} from cache", kind, path.display());
+                self.stats.lock().unwrap().sidecar_hits += 1;
+                data
+            }
+            None => {
+                debug!("Decoding {} of {}", kind, path.display());
+                self.stats.lock().unwrap().misses += 1;
+                let data = load(&pwad::Pwad::from_file(path)?)?;
+                if let Some(sidecar) = &sidecar {
+                    if let Err(e) = write_sidecar(sidecar, &header, &data) {
+                        warn!(
+                            "{}: could not write calibration cache: {}",
+                            sidecar.display(),
+                            e
+                        );
+                    }
+                }
+                data
+            }
+        };
+        let data = Arc::new(data);
+        self.insert(key, data.len() * T::SIZE, data.clone());
+        Ok(data)
+    }
+
+    fn insert(&self, key: Key, bytes: usize, data: Arc<dyn Any + Send + Sync>) {
+        if bytes > self.limit {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let mut total: usize = entries.iter().map(|e| e.bytes).sum();
+        while total + bytes > self.limit && !entries.is_empty() {
+            let evicted = entries.remove(0);
+            debug!(
+                "Evicting {} of {} from calibration cache",
+                evicted.key.kind,
+                evicted.key.path.display()
+            );
+            total -= evicted.bytes;
+        }
+        entries.push(Entry { key, bytes, data });
+    }
+}
+
+impl Default for RefCache {
+    fn default() -> Self {
+        RefCache::new(DEFAULT_LIMIT, None)
+    }
+}
+
+struct Header {
+    tag: u8,
+    modified: SystemTime,
+    source_len: u64,
+    width: u32,
+    height: u32,
+}
+
+impl Header {
+    fn to_bytes(&self) -> Vec<u8> {
+        let since_epoch = self.modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut bytes = Vec::with_capacity(HEADER_LEN);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.push(self.tag);
+        bytes.extend_from_slice(&since_epoch.as_secs().to_le_bytes());
+        bytes.extend_from_slice(&since_epoch.subsec_nanos().to_le_bytes());
+        bytes.extend_from_slice(&self.source_len.to_le_bytes());
+        bytes.extend_from_slice(&self.width.to_le_bytes());
+        bytes.extend_from_slice(&self.height.to_le_bytes());
+        bytes
+    }
+}
+
+fn sidecar_name(path: &Path, kind: &str, width: usize, height: usize) -> String {
+    let name = path
+        .file_name()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let dir = path
+        .parent()
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_default();
+    // Keep references with the same name in different sessions apart.
+    let digest = format!("{:x}", md5::compute(dir.as_bytes()));
+    // LCC grids of different sizes come from the same frame.
+    format!(
+        "{}-{}-{}-{}x{}.cache",
+        name,
+        &digest[..8],
+        kind,
+        width,
+        height
+    )
+}
+
+// `Ok(None)` if there is no sidecar or it describes a different source.
+fn read_sidecar<T: Element>(path: &Path, header: &Header) -> io::Result<Option<Vec<T>>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut found = vec![0; HEADER_LEN];
+    file.read_exact(&mut found)?;
+    if found != header.to_bytes() {
+        debug!("{}: stale calibration cache", path.display());
+        return Ok(None);
+    }
+    let count = header.width as usize * header.height as usize;
+    let mut bytes = Vec::with_capacity(count * T::SIZE);
+    file.read_to_end(&mut bytes)?;
+    if bytes.len() != count * T::SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected {} bytes, found {}", count * T::SIZE, bytes.len()),
+        ));
+    }
+    Ok(Some(bytes.chunks_exact(T::SIZE).map(T::read_le).collect()))
+}
+
+fn write_sidecar<T: Element>(path: &Path, header: &Header, data: &[T]) -> io::Result<()> {
+    iadng::write_atomically(path, |tmp| {
+        let mut output = BufWriter::new(File::create(tmp)?);
+        output.write_all(&header.to_bytes())?;
+        let mut bytes = Vec::with_capacity(data.len() * T::SIZE);
+        for &v in data {
+            v.write_le(&mut bytes);
+        }
+        output.write_all(&bytes)?;
+        output.flush()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ref_cache() {
+        let dir = crate::test_dir("refcache");
+        let (width, height) = (4, 2);
+        let lump =
+            |v: u16| -> Vec<u8> { (0..width * height).flat_map(|_| v.to_le_bytes()).collect() };
+        let br = dir.join("00000001.BR");
+        let wr = dir.join("00000002.WR");
+        pwad::write_pwad(&br, &[("BLACK0", lump(90)), ("BLACK1", lump(100))]).unwrap();
+        pwad::write_pwad(&wr, &[("WHITE", lump(50_000))]).unwrap();
+        let sidecars = dir.join("cache");
+
+        let cache = RefCache::new(1024, Some(sidecars.clone()));
+        assert_eq!(*cache.dark(&br, width, height).unwrap(), vec![100; 8]);
+        assert_eq!(*cache.dark(&br, width, height).unwrap(), vec![100; 8]);
+        let gains = cache.gains::<f32>(&wr, width, height).unwrap();
+        assert_eq!(*gains, vec![1.0 / 50_000.0; 8]);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.sidecar_hits, stats.misses), (1, 0, 2));
+
+        // A fresh cache picks up the sidecars, until the reference changes.
+        let cache = RefCache::new(1024, Some(sidecars.clone()));
+        cache.dark(&br, width, height).unwrap();
+        cache.gains::<f32>(&wr, width, height).unwrap();
+        assert_eq!(cache.stats().sidecar_hits, 2);
+        pwad::write_pwad(&br, &[("BLACK0", lump(90)), ("BLACK1", lump(120))]).unwrap();
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+        std::fs::File::options()
+            .write(true)
+            .open(&br)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert_eq!(*cache.dark(&br, width, height).unwrap(), vec![120; 8]);
+        assert_eq!(cache.stats().misses, 1);
+
+        // Too small to hold both references: the older one is evicted.
+        let cache = RefCache::new(40, None);
+        cache.dark(&br, width, height).unwrap();
+        cache.gains::<f32>(&wr, width, height).unwrap();
+        cache.dark(&br, width, height).unwrap();
+        assert_eq!(cache.stats().misses, 3);
+
+        // An LCC frame applied to frames of two sizes gives a grid for each.
+        let cache = RefCache::new(1 << 20, Some(sidecars.clone()));
+        let grids = [LccMap::grid(1024, 768), LccMap::grid(2048, 1536)];
+        assert_ne!(grids[0], grids[1]);
+        let lcc = |cache: &RefCache, (rows, cols): (usize, usize)| {
+            cache
+                .get::<f32>("lcc", &wr, 4 * cols, rows, |_| {
+                    Ok(vec![rows as f32; 4 * cols * rows])
+                })
+                .unwrap()
+        };
+        for _ in 0..2 {
+            for (rows, cols) in grids {
+                assert_eq!(
+                    *lcc(&cache, (rows, cols)),
+                    vec![rows as f32; 4 * cols * rows]
+                );
+            }
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+        let cache = RefCache::new(1 << 20, Some(sidecars.clone()));
+        for grid in grids {
+            lcc(&cache, grid);
+        }
+        assert_eq!(cache.stats().sidecar_hits, 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+/*
+Reverse conversion: rebuild an IA file from a DNG written by `iadng`.
+
+The META lump travels in the DNG's private data and the thumbnail is the
+DNG's IFD0 image, so both come back byte for byte. RAW0 is the raw IFD's
+data, which only matches the original sensor data if the DNG was written
+without calibration; otherwise it holds the calibrated, rescaled frame.
+BR/WR references are not rebuilt.
+
+DNGs written with embedded originals don't need rebuilding: the IA and
+reference files come back out of them byte for byte.
+*/
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use crate::iadng::{
+    self, TAG_DNG_PRIVATE_DATA, TAG_IMAGE_LENGTH, TAG_IMAGE_WIDTH, TAG_ORIGINAL_RAW_FILE_DATA,
+    TAG_ORIGINAL_RAW_FILE_DIGEST, TAG_ORIGINAL_RAW_FILE_NAME, TAG_SAMPLES_PER_PIXEL,
+    TAG_STRIP_BYTE_COUNTS, TAG_STRIP_OFFSETS, TAG_SUBIFDS,
+};
+use crate::original::EmbeddedFile;
+use crate::pwad;
+use crate::sinar_ia::{SinarIAMeta, META_KEY, RAW_KEY, THUMB_KEY};
+use crate::tiffread::TiffReader;
+
+/// An IA frame recovered from a DNG.
+#[derive(Debug)]
+pub struct RestoredFrame {
+    pub meta: SinarIAMeta,
+    pub raw: Vec<u16>,
+    pub thumb: Vec<u8>,
+    /// Whether RAW0 is calibrated, rescaled data rather than the sensor's
+    pub scaled: bool,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read the META lump, thumbnail and raw data back out of `dng`.
+pub fn read_dng(dng: &Path) -> io::Result<RestoredFrame> {
+    let mut tiff = TiffReader::open(dng)?;
+    let root = tiff.read_ifd(tiff.first_ifd)?;
+    let private = root
+        .get(TAG_DNG_PRIVATE_DATA)
+        .ok_or_else(|| invalid("no DNGPrivateData, not written by iatodng".to_string()))?;
+    let records = iadng::parse_private_data(&private.data)
+        .ok_or_else(|| invalid("DNGPrivateData not written by iatodng".to_string()))?;
+    let meta_lump = records
+        .iter()
+        .find(|(name, _)| *name == iadng::PRIVATE_META)
+        .map(|(_, data)| data.to_vec())
+        .filter(|data| !data.is_empty())
+        .ok_or_else(|| invalid("no META lump in DNGPrivateData".to_string()))?;
+    let scaled = records
+        .iter()
+        .any(|(name, _)| *name == iadng::PRIVATE_SCALED);
+    let meta = SinarIAMeta::process_meta(&meta_lump)?;
+
+    let offsets = root
+        .get(TAG_STRIP_OFFSETS)
+        .map(|e| e.as_u32s())
+        .unwrap_or_default();
+    let sizes = root
+        .get(TAG_STRIP_BYTE_COUNTS)
+        .map(|e| e.as_u32s())
+        .unwrap_or_default();
+    let mut thumb = Vec::new();
+    for (&offset, &size) in offsets.iter().zip(sizes.iter()) {
+        thumb.extend_from_slice(&tiff.read_at(offset as u64, size as usize)?);
+    }
+
+    let raw_offset = root
+        .get(TAG_SUBIFDS)
+        .and_then(|e| e.as_u32s().first().copied())
+        .ok_or_else(|| invalid("no raw SubIFD".to_string()))?;
+    let raw_ifd = tiff.read_ifd(raw_offset)?;
+    let samples = raw_ifd
+        .get(TAG_SAMPLES_PER_PIXEL)
+        .map(|e| e.as_u32s())
+        .unwrap_or_default();
+    if samples != [1] {
+        return Err(invalid(format!(
+            "raw data has {:?} samples per pixel, only CFA DNGs can be restored",
+            samples
+        )));
+    }
+    let size = |tag: u16| raw_ifd.get(tag).and_then(|e| e.as_u32s().first().copied());
+    let (width, height) = (size(TAG_IMAGE_WIDTH), size(TAG_IMAGE_LENGTH));
+    if (width, height) != (Some(meta.width), Some(meta.height)) {
+        return Err(invalid(format!(
+            "raw data is {:?}x{:?}, META says {}x{}",
+            width, height, meta.width, meta.height
+        )));
+    }
+    let offsets = raw_ifd
+        .get(TAG_STRIP_OFFSETS)
+        .map(|e| e.as_u32s())
+        .unwrap_or_default();
+    let sizes = raw_ifd
+        .get(TAG_STRIP_BYTE_COUNTS)
+        .map(|e| e.as_u32s())
+        .unwrap_or_default();
+    let mut raw = Vec::with_capacity(meta.width as usize * meta.height as usize);
+    for (&offset, &size) in offsets.iter().zip(&sizes) {
+        // Samples are stored big-endian whatever the file's byte order.
+        let strip = tiff.read_at(offset as u64, size as usize)?;
+        raw.extend(
+            strip
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]])),
+        );
+    }
+    if raw.len() != meta.width as usize * meta.height as usize {
+        return Err(invalid(format!("raw data holds {} samples", raw.len())));
+    }
+    Ok(RestoredFrame {
+        meta,
+        raw,
+        thumb,
+        scaled,
+    })
+}
+
+/// Write `frame` as an IA PWAD with META, RAW0 and THUMB lumps.
+pub fn write_ia(frame: &RestoredFrame, path: &Path) -> io::Result<()> {
+    let raw: Vec<u8> = frame.raw.iter().flat_map(|v| v.to_le_bytes()).collect();
+    iadng::write_atomically(path, |tmp| {
+        pwad::write_pwad(
+            tmp,
+            &[
+                (META_KEY, &frame.meta.meta_lump[..]),
+                (RAW_KEY, &raw[..]),
+                (THUMB_KEY, &frame.thumb[..]),
+            ],
+        )
+    })
+}
+
+/// Rebuild `dng` as `<output_dir>/<dng stem>.IA`.
+pub fn dng_to_ia(dng: &Path, output_dir: &Path) -> io::Result<PathBuf> {
+    let frame = read_dng(dng)?;
+    if frame.scaled {
+        warn!(
+            "{}: raw data was calibrated and rescaled, RAW0 will not match the original",
+            dng.display()
+        );
+    }
+    let stem = dng
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let path = output_dir.join(format!("{}.IA", stem));
+    info!("Writing IA to {}", path.display());
+    write_ia(&frame, &path)?;
+    Ok(path)
+}
+
+/// The source files embedded in `dng`: the IA from OriginalRawFileData,
+/// checked against OriginalRawFileDigest, then any BR/WR references.
+pub fn read_originals(dng: &Path) -> io::Result<Vec<EmbeddedFile>> {
+    let mut tiff = TiffReader::open(dng)?;
+    let root = tiff.read_ifd(tiff.first_ifd)?;
+    let mut files = Vec::new();
+    if let Some(data) = root.get(TAG_ORIGINAL_RAW_FILE_DATA) {
+        let ia = EmbeddedFile {
+            name: root
+                .get(TAG_ORIGINAL_RAW_FILE_NAME)
+                .map(|e| e.as_string())
+                .unwrap_or_default(),
+            data: data.data.clone(),
+        };
+        if let Some(digest) = root.get(TAG_ORIGINAL_RAW_FILE_DIGEST) {
+            if digest.data != ia.digest() {
+                return Err(invalid("OriginalRawFileDigest mismatch".to_string()));
+            }
+        }
+        files.push(ia);
+    }
+    let records = root
+        .get(TAG_DNG_PRIVATE_DATA)
+        .and_then(|private| iadng::parse_private_data(&private.data))
+        .unwrap_or_default();
+    for (name, record) in records {
+        if name == iadng::PRIVATE_REFERENCE {
+            files.push(EmbeddedFile::from_record(record)?);
+        }
+    }
+    Ok(files)
+}
+
+/// Write the files embedded in `dng` to `output_dir` under their original
+/// names.
+pub fn extract_originals(dng: &Path, output_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let files = read_originals(dng)?;
+    if files.is_empty() {
+        return Err(invalid("no original files embedded".to_string()));
+    }
+    let mut written = Vec::new();
+    for file in files {
+        // Never let a stored name point outside `output_dir`.
+        let name = Path::new(&file.name)
+            .file_name()
+            .ok_or_else(|| invalid(format!("bad embedded file name '{}'", file.name)))?;
+        let path = output_dir.join(name);
+        let contents = file.contents()?;
+        info!("Extracting {} to {}", file.name, path.display());
+        iadng::write_atomically(&path, |tmp| fs::write(tmp, &contents))?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibrate::Calibration;
+    use crate::iadng::write_1d_array_to_dng;
+    use crate::original::Originals;
+    use crate::pwad::Pwad;
+    use crate::refcache::RefCache;
+    use crate::sinar_ia::{self, ConvertOptions, FrameOutcome, META_LEN, THUMB_HT, THUMB_WD};
+    use ndarray::Array1;
+
+    #[test]
+    fn test_dng_to_ia() {
+        // A full E22 frame, converted without calibration.
+        let dir = crate::test_dir("restore");
+        let mut meta = vec![0u8; META_LEN];
+        meta[272..280].copy_from_slice(b"e22-0007");
+        let (width, height) = (5344, 4008);
+        let raw: Vec<u8> = (0..width * height)
+            .flat_map(|i: u32| ((i.wrapping_mul(7919) >> 3) as u16).to_le_bytes())
+            .collect();
+        let thumb: Vec<u8> = (0..THUMB_WD * THUMB_HT * 3).map(|i| i as u8).collect();
+        let ia = dir.join("6C486AFC.IA");
+        pwad::write_pwad(
+            &ia,
+            &[(META_KEY, &meta[..]), (RAW_KEY, &raw), (THUMB_KEY, &thumb)],
+        )
+        .unwrap();
+        let options = ConvertOptions {
+            calibration: Calibration::None,
+            ..ConvertOptions::default()
+        };
+        let dng = dir.join("out/1234.dng");
+        let outcome = sinar_ia::process_ia(&ia, &dng, &options, &RefCache::new(0, None));
+        assert!(matches!(outcome, FrameOutcome::Converted(_)));
+
+        let restored = dng_to_ia(&dng, &dir.join("restored")).unwrap();
+        assert_eq!(restored, dir.join("restored/1234.IA"));
+        let restored = Pwad::from_file(&restored).unwrap();
+        assert_eq!(restored.read_lump_by_tag(META_KEY).unwrap(), meta);
+        assert!(restored.read_lump_by_tag(RAW_KEY).unwrap() == raw);
+        assert_eq!(restored.read_lump_by_tag(THUMB_KEY).unwrap(), thumb);
+
+        // A DNG without a META record is refused.
+        let image = Array1::from_vec((0..8).map(|v| v as f64).collect());
+        let small = SinarIAMeta {
+            width: 4,
+            height: 2,
+            ..SinarIAMeta::default()
+        };
+        let other = dir.join("other.dng");
+        write_1d_array_to_dng(&image, &[0; 12], &other, &small, Originals::default()).unwrap();
+        let error = dng_to_ia(&other, &dir.join("restored")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(!dir.join("restored/other.IA").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,651 @@
+/*
+Contact sheets: every frame's THUMB lump laid out in a grid with a caption,
+for a client to review a session before anything is converted.
+
+Only the META and THUMB lumps are read, never RAW0, so a full card takes
+seconds. Thumbnails are placed at their own size, turned to the session's
+orientation, `columns` by `rows` to a page:
+
+* JPEG writes one file per page, `sheet.jpg` for a single page and
+  `sheet-1.jpg`, `sheet-2.jpg`, ... for more.
+* PDF writes one document of A4 landscape pages, the thumbnails as lossless
+  images and the captions as text.
+
+Captions give the IA file's name and shutter count, then the ISO, shutter
+time, aperture and white balance from META.
+*/
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use log::warn;
+use rayon::prelude::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+
+use crate::export::orient;
+use crate::iadng;
+use crate::pwad::Pwad;
+use crate::sinar_ia::{SinarIAMeta, META_KEY, THUMB_HT, THUMB_KEY, THUMB_WD};
+
+/// Side of the square each thumbnail is centred in, whichever way it turns.
+const CELL: usize = if THUMB_WD > THUMB_HT {
+    THUMB_WD as usize
+} else {
+    THUMB_HT as usize
+};
+const MARGIN: usize = 32;
+const GAP: usize = 24;
+/// Size of a font pixel, in sheet pixels.
+const SCALE: usize = 2;
+const ADVANCE: usize = (GLYPH_WIDTH + 1) * SCALE;
+const LINE: usize = (GLYPH_HEIGHT + 4) * SCALE;
+/// Room under each thumbnail for its two caption lines.
+const CAPTION: usize = 2 * LINE + SCALE;
+const PAPER: u8 = 255;
+const INK: u8 = 0;
+const JPEG_QUALITY: u8 = 90;
+/// A4 landscape, in points.
+const PDF_PAGE: [f64; 2] = [842.0, 595.0];
+const PDF_MARGIN: f64 = 18.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SheetFormat {
+    Jpeg,
+    Pdf,
+}
+
+impl SheetFormat {
+    /// The format named by `path`'s extension.
+    pub fn of(path: &Path) -> Result<Self, String> {
+        let extension = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        match extension.as_str() {
+            "jpg" | "jpeg" => Ok(SheetFormat::Jpeg),
+            "pdf" => Ok(SheetFormat::Pdf),
+            _ => Err(format!(
+                "{}: contact sheets are written as .jpg or .pdf",
+                path.display()
+            )),
+        }
+    }
+}
+
+/// How many thumbnails go across and down a page.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Layout {
+    pub columns: usize,
+    pub rows: usize,
+}
+
+impl Layout {
+    fn per_page(&self) -> usize {
+        self.columns * self.rows
+    }
+
+    /// Width and height of a page, in sheet pixels.
+    pub fn page_size(&self) -> (usize, usize) {
+        (
+            2 * MARGIN + self.columns * CELL + (self.columns - 1) * GAP,
+            2 * MARGIN + self.rows * (CELL + CAPTION) + (self.rows - 1) * GAP,
+        )
+    }
+
+    // Top left corner of the `index`th cell of a page.
+    fn cell(&self, index: usize) -> (usize, usize) {
+        let (column, row) = (index % self.columns, index / self.columns);
+        (
+            MARGIN + column * (CELL + GAP),
+            MARGIN + row * (CELL + CAPTION + GAP),
+        )
+    }
+}
+
+/// An IA file's THUMB lump: 8-bit RGB.
+#[derive(Debug, Clone)]
+pub struct Thumbnail {
+    pub rgb: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Thumbnail {
+    /// Read the THUMB lump of `pwad`, turned to a TIFF/EXIF `orientation`.
+    pub fn read(pwad: &Pwad, orientation: u16) -> io::Result<Thumbnail> {
+        let rgb = pwad.read_lump_by_tag(THUMB_KEY)?;
+        let (width, height) = (THUMB_WD as usize, THUMB_HT as usize);
+        if rgb.len() != width * height * 3 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "THUMB lump holds {} bytes, not {}x{} RGB",
+                    rgb.len(),
+                    width,
+                    height
+                ),
+            ));
+        }
+        let (rgb, width, height) = orient(&rgb, width, height, 3, orientation);
+        Ok(Thumbnail { rgb, width, height })
+    }
+}
+
+/// A frame's thumbnail and caption.
+#[derive(Debug, Clone)]
+pub struct SheetFrame {
+    pub thumbnail: Thumbnail,
+    pub caption: [String; 2],
+}
+
+impl SheetFrame {
+    pub fn read(ia: &Path, orientation: u16) -> io::Result<SheetFrame> {
+        let pwad = Pwad::from_file(ia)?;
+        let meta = SinarIAMeta::process_meta(&pwad.read_lump_by_tag(META_KEY)?)?;
+        let name = ia
+            .file_name()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Ok(SheetFrame {
+            thumbnail: Thumbnail::read(&pwad, orientation)?,
+            caption: caption(&name, &meta),
+        })
+    }
+}
+
+/// A shutter time in microseconds as photographers write it: `1/125 s`,
+/// `2 s`, `1.5 s`.
+pub fn shutter_time(us: u32) -> String {
+    let seconds = us as f64 / 1_000_000.0;
+    if seconds >= 1.0 || us == 0 {
+        format!("{} s", (seconds * 10.0).round() / 10.0)
+    } else {
+        format!("1/{} s", (1.0 / seconds).round())
+    }
+}
+
+/// The two caption lines of a frame.
+pub fn caption(name: &str, meta: &SinarIAMeta) -> [String; 2] {
+    let mut exposure = vec![format!("ISO {}", meta.iso)];
+    // The shutter time set on the back, or failing that the one it measured.
+    match (meta.req_shutter_us, meta.measured_shutter_us) {
+        (0, 0) => {}
+        (0, us) | (us, _) => exposure.push(shutter_time(us)),
+    }
+    if meta.f_stop > 0.0 {
+        exposure.push(format!("f/{}", (meta.f_stop * 10.0).round() / 10.0));
+    }
+    exposure.push(format!("{:?}", meta.white_balance_name));
+    [
+        format!("{}  #{}", name, meta.shutter_count),
+        exposure.join("  "),
+    ]
+}
+
+/// Lay out the frames of `files` on contact sheets at `output`, returning
+/// the files written. Frames that can't be read are left out with a warning.
+pub fn write_sheets(
+    files: &[PathBuf],
+    output: &Path,
+    layout: Layout,
+    orientation: u16,
+) -> io::Result<Vec<PathBuf>> {
+    let format =
+        SheetFormat::of(output).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let frames: Vec<SheetFrame> = files
+        .par_iter()
+        .filter_map(|ia| match SheetFrame::read(ia, orientation) {
+            Ok(frame) => Some(frame),
+            Err(e) => {
+                warn!("Leaving out {}: {}", ia.display(), e);
+                None
+            }
+        })
+        .collect();
+    if frames.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no frames with a thumbnail",
+        ));
+    }
+    let pages: Vec<&[SheetFrame]> = frames.chunks(layout.per_page()).collect();
+    match format {
+        SheetFormat::Pdf => {
+            let pdf = pdf(&pages, layout)?;
+            iadng::write_atomically(output, |tmp| {
+                let mut file = File::create(tmp)?;
+                file.write_all(&pdf)?;
+                file.sync_all()
+            })?;
+            Ok(vec![output.to_path_buf()])
+        }
+        SheetFormat::Jpeg => {
+            let paths = page_paths(output, pages.len());
+            pages
+                .par_iter()
+                .zip(paths.par_iter())
+                .try_for_each(|(frames, path)| {
+                    let (page, width, height) = render_page(frames, layout);
+                    iadng::write_atomically(path, |tmp| write_jpeg(&page, width, height, tmp))
+                })?;
+            Ok(paths)
+        }
+    }
+}
+
+// `sheet.jpg` for one page, `sheet-1.jpg`, `sheet-2.jpg`, ... for more.
+fn page_paths(output: &Path, pages: usize) -> Vec<PathBuf> {
+    if pages == 1 {
+        return vec![output.to_path_buf()];
+    }
+    let stem = output
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = output
+        .extension()
+        .map(|e| e.to_string_lossy().to_string())
+        .unwrap_or_default();
+    (1..=pages)
+        .map(|page| output.with_file_name(format!("{}-{}.{}", stem, page, extension)))
+        .collect()
+}
+
+// Where a thumbnail sits in its cell: centred across, at the bottom.
+fn placement(layout: Layout, index: usize, thumbnail: &Thumbnail) -> (usize, usize) {
+    let (x, y) = layout.cell(index);
+    (
+        x + (CELL - thumbnail.width) / 2,
+        y + CELL - thumbnail.height,
+    )
+}
+
+/// Draw a page of `frames` as RGB, returning it with its width and height.
+pub fn render_page(frames: &[SheetFrame], layout: Layout) -> (Vec<u8>, usize, usize) {
+    let (width, height) = layout.page_size();
+    let mut page = vec![PAPER; width * height * 3];
+    for (index, frame) in frames.iter().enumerate() {
+        let thumbnail = &frame.thumbnail;
+        let (x, y) = placement(layout, index, thumbnail);
+        let row_bytes = thumbnail.width * 3;
+        for row in 0..thumbnail.height {
+            let at = ((y + row) * width + x) * 3;
+            page[at..at + row_bytes]
+                .copy_from_slice(&thumbnail.rgb[row * row_bytes..(row + 1) * row_bytes]);
+        }
+        let (x, y) = layout.cell(index);
+        for (line, text) in frame.caption.iter().enumerate() {
+            draw_text(&mut page, width, x, y + CELL + SCALE + line * LINE, text);
+        }
+    }
+    (page, width, height)
+}
+
+// As much of a caption line as fits under a thumbnail.
+fn fitted(text: &str) -> impl Iterator<Item = char> + '_ {
+    text.chars().take(CELL / ADVANCE)
+}
+
+fn draw_text(page: &mut [u8], width: usize, x: usize, y: usize, text: &str) {
+    for (i, c) in fitted(text).enumerate() {
+        let glyph = FONT
+            .get((c as usize).wrapping_sub(' ' as usize))
+            .unwrap_or(&FONT['?' as usize - ' ' as usize]);
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits >> (GLYPH_WIDTH - 1 - column) & 1 == 0 {
+                    continue;
+                }
+                let (left, top) = (x + i * ADVANCE + column * SCALE, y + row * SCALE);
+                for dy in 0..SCALE {
+                    let at = ((top + dy) * width + left) * 3;
+                    page[at..at + SCALE * 3].fill(INK);
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn write_jpeg(rgb: &[u8], width: usize, height: usize, path: &Path) -> io::Result<()> {
+    let jpeg_error = |e: jpeg_encoder::EncodingError| io::Error::other(e.to_string());
+    let mut jpeg = Vec::new();
+    jpeg_encoder::Encoder::new(&mut jpeg, JPEG_QUALITY)
+        .encode(
+            rgb,
+            width as u16,
+            height as u16,
+            jpeg_encoder::ColorType::Rgb,
+        )
+        .map_err(jpeg_error)?;
+    let mut file = File::create(path)?;
+    file.write_all(&jpeg)?;
+    file.sync_all()
+}
+
+// A caption line as a PDF string literal; Helvetica's standard encoding covers ASCII.
+fn pdf_string(text: &str) -> String {
+    let mut literal = String::from("(");
+    for c in fitted(text) {
+        match c {
+            '\\' | '(' | ')' => {
+                literal.push('\\');
+                literal.push(c);
+            }
+            ' '..='~' => literal.push(c),
+            _ => literal.push('?'),
+        }
+    }
+    literal.push(')');
+    literal
+}
+
+fn pdf_stream(dictionary: &str, data: &[u8]) -> Vec<u8> {
+    let mut object = format!("<< {} /Length {} >>\nstream\n", dictionary, data.len()).into_bytes();
+    object.extend_from_slice(data);
+    object.extend_from_slice(b"\nendstream");
+    object
+}
+
+/// The pages as a PDF document.
+fn pdf(pages: &[&[SheetFrame]], layout: Layout) -> io::Result<Vec<u8>> {
+    // Objects 1 and 2 are the catalog and the page tree, 3 the font.
+    let mut objects: Vec<Vec<u8>> = vec![
+        b"<< /Type /Catalog /Pages 2 0 R >>".to_vec(),
+        Vec::new(),
+        b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica >>".to_vec(),
+    ];
+    let (width, height) = layout.page_size();
+    let scale = ((PDF_PAGE[0] - 2.0 * PDF_MARGIN) / width as f64)
+        .min((PDF_PAGE[1] - 2.0 * PDF_MARGIN) / height as f64);
+    let origin = [
+        (PDF_PAGE[0] - width as f64 * scale) / 2.0,
+        (PDF_PAGE[1] - height as f64 * scale) / 2.0,
+    ];
+    let mut kids = Vec::new();
+    for frames in pages {
+        // Drawn in sheet pixels, y running up from the page's bottom edge.
+        let mut content = format!(
+            "{:.5} 0 0 {:.5} {:.2} {:.2} cm\n",
+            scale, scale, origin[0], origin[1]
+        );
+        let mut images = String::new();
+        for (index, frame) in frames.iter().enumerate() {
+            let thumbnail = &frame.thumbnail;
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&thumbnail.rgb)?;
+            objects.push(pdf_stream(
+                &format!(
+                    "/Type /XObject /Subtype /Image /Width {} /Height {} \
+                     /ColorSpace /DeviceRGB /BitsPerComponent 8 /Filter /FlateDecode",
+                    thumbnail.width, thumbnail.height
+                ),
+                &encoder.finish()?,
+            ));
+            images.push_str(&format!("/Im{} {} 0 R ", index, objects.len()));
+            let (x, y) = placement(layout, index, thumbnail);
+            content.push_str(&format!(
+                "q {} 0 0 {} {} {} cm /Im{} Do Q\n",
+                thumbnail.width,
+                thumbnail.height,
+                x,
+                height - y - thumbnail.height,
+                index
+            ));
+            let (x, y) = layout.cell(index);
+            for (line, text) in frame.caption.iter().enumerate() {
+                let baseline = y + CELL + SCALE + line * LINE + GLYPH_HEIGHT * SCALE;
+                content.push_str(&format!(
+                    "BT /F1 {} Tf {} {} Td {} Tj ET\n",
+                    GLYPH_HEIGHT * SCALE * 3 / 2,
+                    x,
+                    height - baseline,
+                    pdf_string(text)
+                ));
+            }
+        }
+        objects.push(pdf_stream("", content.as_bytes()));
+        let contents = objects.len();
+        objects.push(
+            format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] \
+                 /Resources << /Font << /F1 3 0 R >> /XObject << {}>> >> /Contents {} 0 R >>",
+                PDF_PAGE[0], PDF_PAGE[1], images, contents
+            )
+            .into_bytes(),
+        );
+        kids.push(format!("{} 0 R", objects.len()));
+    }
+    objects[1] = format!(
+        "<< /Type /Pages /Kids [{}] /Count {} >>",
+        kids.join(" "),
+        kids.len()
+    )
+    .into_bytes();
+
+    let mut pdf = b"%PDF-1.4\n".to_vec();
+    let mut offsets = Vec::new();
+    for (i, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        pdf.extend_from_slice(format!("{} 0 obj\n", i + 1).as_bytes());
+        pdf.extend_from_slice(object);
+        pdf.extend_from_slice(b"\nendobj\n");
+    }
+    let xref = pdf.len();
+    pdf.extend_from_slice(
+        format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes(),
+    );
+    for offset in offsets {
+        pdf.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+    }
+    pdf.extend_from_slice(
+        format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1,
+            xref
+        )
+        .as_bytes(),
+    );
+    Ok(pdf)
+}
+
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+
+// A 5x7 font for printable ASCII, from space; a row per byte, the leftmost
+// pixel in bit 4.
+const FONT: [[u8; GLYPH_HEIGHT]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x04, 0x04, 0x04, 0x04, 0x00, 0x00, 0x04], // !
+    [0x0a, 0x0a, 0x0a, 0x00, 0x00, 0x00, 0x00], // "
+    [0x0a, 0x0a, 0x1f, 0x0a, 0x1f, 0x0a, 0x0a], // #
+    [0x04, 0x0f, 0x14, 0x0e, 0x05, 0x1e, 0x04], // $
+    [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03], // %
+    [0x0c, 0x12, 0x14, 0x08, 0x15, 0x12, 0x0d], // &
+    [0x0c, 0x04, 0x08, 0x00, 0x00, 0x00, 0x00], // '
+    [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02], // (
+    [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08], // )
+    [0x00, 0x04, 0x15, 0x0e, 0x15, 0x04, 0x00], // *
+    [0x00, 0x04, 0x04, 0x1f, 0x04, 0x04, 0x00], // +
+    [0x00, 0x00, 0x00, 0x00, 0x0c, 0x04, 0x08], // ,
+    [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00], // -
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c], // .
+    [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00], // /
+    [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e], // 0
+    [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e], // 1
+    [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f], // 2
+    [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e], // 3
+    [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02], // 4
+    [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e], // 5
+    [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e], // 6
+    [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08], // 7
+    [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e], // 8
+    [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c], // 9
+    [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x0c, 0x00], // :
+    [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x04, 0x08], // ;
+    [0x02, 0x04, 0x08, 0x10, 0x08, 0x04, 0x02], // <
+    [0x00, 0x00, 0x1f, 0x00, 0x1f, 0x00, 0x00], // =
+    [0x08, 0x04, 0x02, 0x01, 0x02, 0x04, 0x08], // >
+    [0x0e, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04], // ?
+    [0x0e, 0x11, 0x01, 0x0d, 0x15, 0x15, 0x0e], // @
+    [0x0e, 0x11, 0x11, 0x11, 0x1f, 0x11, 0x11], // A
+    [0x1e, 0x11, 0x11, 0x1e, 0x11, 0x11, 0x1e], // B
+    [0x0e, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0e], // C
+    [0x1c, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1c], // D
+    [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x1f], // E
+    [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x10], // F
+    [0x0e, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0f], // G
+    [0x11, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11], // H
+    [0x0e, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e], // I
+    [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0c], // J
+    [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11], // K
+    [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1f], // L
+    [0x11, 0x1b, 0x15, 0x15, 0x11, 0x11, 0x11], // M
+    [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11], // N
+    [0x0e, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e], // O
+    [0x1e, 0x11, 0x11, 0x1e, 0x10, 0x10, 0x10], // P
+    [0x0e, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0d], // Q
+    [0x1e, 0x11, 0x11, 0x1e, 0x14, 0x12, 0x11], // R
+    [0x0f, 0x10, 0x10, 0x0e, 0x01, 0x01, 0x1e], // S
+    [0x1f, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04], // T
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e], // U
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x0a, 0x04], // V
+    [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0a], // W
+    [0x11, 0x11, 0x0a, 0x04, 0x0a, 0x11, 0x11], // X
+    [0x11, 0x11, 0x11, 0x0a, 0x04, 0x04, 0x04], // Y
+    [0x1f, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1f], // Z
+    [0x0e, 0x08, 0x08, 0x08, 0x08, 0x08, 0x0e], // [
+    [0x00, 0x10, 0x08, 0x04, 0x02, 0x01, 0x00], // \
+    [0x0e, 0x02, 0x02, 0x02, 0x02, 0x02, 0x0e], // ]
+    [0x04, 0x0a, 0x11, 0x00, 0x00, 0x00, 0x00], // ^
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1f], // _
+    [0x08, 0x04, 0x02, 0x00, 0x00, 0x00, 0x00], // `
+    [0x00, 0x00, 0x0e, 0x01, 0x0f, 0x11, 0x0f], // a
+    [0x10, 0x10, 0x16, 0x19, 0x11, 0x11, 0x1e], // b
+    [0x00, 0x00, 0x0e, 0x10, 0x10, 0x11, 0x0e], // c
+    [0x01, 0x01, 0x0d, 0x13, 0x11, 0x11, 0x0f], // d
+    [0x00, 0x00, 0x0e, 0x11, 0x1f, 0x10, 0x0e], // e
+    [0x06, 0x09, 0x08, 0x1c, 0x08, 0x08, 0x08], // f
+    [0x00, 0x0f, 0x11, 0x11, 0x0f, 0x01, 0x0e], // g
+    [0x10, 0x10, 0x16, 0x19, 0x11, 0x11, 0x11], // h
+    [0x04, 0x00, 0x0c, 0x04, 0x04, 0x04, 0x0e], // i
+    [0x02, 0x00, 0x06, 0x02, 0x02, 0x12, 0x0c], // j
+    [0x10, 0x10, 0x12, 0x14, 0x18, 0x14, 0x12], // k
+    [0x0c, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e], // l
+    [0x00, 0x00, 0x1a, 0x15, 0x15, 0x11, 0x11], // m
+    [0x00, 0x00, 0x16, 0x19, 0x11, 0x11, 0x11], // n
+    [0x00, 0x00, 0x0e, 0x11, 0x11, 0x11, 0x0e], // o
+    [0x00, 0x00, 0x1e, 0x11, 0x1e, 0x10, 0x10], // p
+    [0x00, 0x00, 0x0d, 0x13, 0x0f, 0x01, 0x01], // q
+    [0x00, 0x00, 0x16, 0x19, 0x10, 0x10, 0x10], // r
+    [0x00, 0x00, 0x0e, 0x10, 0x0e, 0x01, 0x1e], // s
+    [0x08, 0x08, 0x1c, 0x08, 0x08, 0x09, 0x06], // t
+    [0x00, 0x00, 0x11, 0x11, 0x11, 0x13, 0x0d], // u
+    [0x00, 0x00, 0x11, 0x11, 0x11, 0x0a, 0x04], // v
+    [0x00, 0x00, 0x11, 0x11, 0x15, 0x15, 0x0a], // w
+    [0x00, 0x00, 0x11, 0x0a, 0x04, 0x0a, 0x11], // x
+    [0x00, 0x00, 0x11, 0x11, 0x0f, 0x01, 0x0e], // y
+    [0x00, 0x00, 0x1f, 0x02, 0x04, 0x08, 0x1f], // z
+    [0x02, 0x04, 0x04, 0x08, 0x04, 0x04, 0x02], // {
+    [0x04, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04], // |
+    [0x08, 0x04, 0x04, 0x02, 0x04, 0x04, 0x08], // }
+    [0x00, 0x00, 0x08, 0x15, 0x02, 0x00, 0x00], // ~
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orientation;
+    use crate::pwad;
+
+    #[test]
+    fn test_contact_sheet() {
+        let dir = crate::test_dir("sheet");
+        let (width, height) = (THUMB_WD as usize, THUMB_HT as usize);
+        let mut files = Vec::new();
+        for frame in 0..5u8 {
+            let mut meta = vec![0u8; 360];
+            meta[4..8].copy_from_slice(&(1200 + frame as u32).to_le_bytes());
+            meta[20..29].copy_from_slice(b"Sinar Hy6");
+            meta[100..102].copy_from_slice(&4u16.to_le_bytes());
+            meta[104..108].copy_from_slice(&7_900u32.to_le_bytes());
+            meta[252..256].copy_from_slice(&100u32.to_le_bytes());
+            meta[272..280].copy_from_slice(b"e75-0042");
+            meta[344..348].copy_from_slice(&8_000u32.to_le_bytes());
+            meta[352..354].copy_from_slice(&(11 * 256u16).to_le_bytes());
+            // A thumbnail with its first row red, the rest in the frame's grey.
+            let mut thumb = vec![40 * frame; width * height * 3];
+            for pixel in thumb[..width * 3].chunks_exact_mut(3) {
+                pixel.copy_from_slice(&[255, 0, 0]);
+            }
+            let path = dir.join(format!("{:07X}.IA", frame));
+            pwad::write_pwad(&path, &[(META_KEY, meta), ("THUMB", thumb)]).unwrap();
+            files.push(path);
+        }
+        // Without a THUMB lump the frame is left out.
+        let mut meta = vec![0u8; 360];
+        meta[272..280].copy_from_slice(b"e75-0042");
+        pwad::write_pwad(&dir.join("NOTHUMB.IA"), &[(META_KEY, meta)]).unwrap();
+        files.push(dir.join("NOTHUMB.IA"));
+
+        // Turned to the Hy6's transverse orientation, the top row becomes
+        // the right hand column.
+        let frame = SheetFrame::read(&files[1], orientation::HY6).unwrap();
+        let thumbnail = &frame.thumbnail;
+        assert_eq!((thumbnail.width, thumbnail.height), (height, width));
+        let at = |x: usize, y: usize| &thumbnail.rgb[(y * height + x) * 3..][..3];
+        assert_eq!(at(height - 1, 0), [255, 0, 0]);
+        assert_eq!(at(0, 0), [40; 3]);
+        assert_eq!(
+            frame.caption,
+            [
+                "0000001.IA  #1201".to_string(),
+                "ISO 100  1/125 s  f/11  Sun".to_string()
+            ]
+        );
+        assert_eq!(shutter_time(2_500_000), "2.5 s");
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            // IA names need not be UTF-8.
+            let name = dir.join(std::ffi::OsStr::from_bytes(b"\xff.IA"));
+            std::fs::copy(&files[1], &name).unwrap();
+            let frame = SheetFrame::read(&name, orientation::HY6).unwrap();
+            assert_eq!(frame.caption[0], "\u{FFFD}.IA  #1201");
+        }
+
+        let layout = Layout {
+            columns: 2,
+            rows: 2,
+        };
+        let (page, page_width, page_height) = render_page(&[frame], layout);
+        assert_eq!((page_width, page_height), layout.page_size());
+        assert!(page.contains(&0), "no caption drawn");
+
+        let written = write_sheets(&files, &dir.join("sheet.jpg"), layout, 1).unwrap();
+        assert_eq!(written, [dir.join("sheet-1.jpg"), dir.join("sheet-2.jpg")]);
+        for path in &written {
+            assert_eq!(std::fs::read(path).unwrap()[..2], [0xff, 0xd8]);
+        }
+        let written = write_sheets(&files, &dir.join("sheet.pdf"), layout, 1).unwrap();
+        let pdf = std::fs::read(&written[0]).unwrap();
+        let text = String::from_utf8_lossy(&pdf);
+        assert!(text.starts_with("%PDF-1.4"));
+        assert!(text.contains("/Count 2"));
+        assert!(text.contains("(0000004.IA  #1204) Tj"));
+        // Every object is where the cross-reference table says.
+        let xref = text.rfind("xref\n").unwrap();
+        for (i, line) in text[xref..]
+            .lines()
+            .skip(3)
+            .take_while(|l| l.ends_with(" n "))
+            .enumerate()
+        {
+            let offset: usize = line[..10].parse().unwrap();
+            assert!(text[offset..].starts_with(&format!("{} 0 obj", i + 1)));
+        }
+        assert!(write_sheets(&files, &dir.join("sheet.png"), layout, 1).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
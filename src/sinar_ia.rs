@@ -1,11 +1,29 @@
 extern crate ndarray;
 
-use crate::{iadng, pwad};
-use ndarray::{Array1, Array2, Zip};
+use crate::backs::{BackCalibration, BackDb};
+use crate::calibrate::{self, Calibration, Precision, Sample, SensorLevels};
+use crate::capture::{CaptureTime, TimeCorrection};
+use crate::dcp::Dcp;
+use crate::export::{self, OutputFormat};
+use crate::lcc::{LccApply, LccMap, LccOptions};
+use crate::lens::{self, Lens, LensOptions};
+use crate::noise::{NoiseModel, NoiseStats};
+use crate::orientation;
+use crate::original::{Embed, Originals};
+use crate::refcache::RefCache;
+use crate::stream::{self, StreamFrame, StreamedRaw};
+use crate::xmp::{Annotations, XmpOptions};
+use crate::{demosaic, iadng, pwad};
+use ndarray::Array2;
 use phf::phf_map;
 use std::convert::TryInto;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::str;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
 
 //Contants for parsing the IA file
 pub const META_KEY: &str = "META";
@@ -14,9 +32,13 @@ pub const THUMB_KEY: &str = "THUMB";
 pub const BLACK0_KEY: &str = "BLACK0";
 pub const BLACK1_KEY: &str = "BLACK1";
 pub const WHITE_KEY: &str = "WHITE";
+/// Bytes of the META lump that `process_meta` reads.
+pub const META_LEN: usize = 360;
 pub const CROP: u32 = 8;
 pub const THUMB_WD: u32 = 356;
 pub const THUMB_HT: u32 = 476;
+/// META's camera name for the Hy6 body.
+pub const HY6_CAMERA: &str = "Sinar Hy6";
 pub const E75_CFA: [u8; 4] = [0u8, 1u8, 1u8, 2u8];
 
 pub static MODEL_NAMES: phf::Map<&'static str, &'static str> = phf_map! {
@@ -24,12 +46,17 @@ pub static MODEL_NAMES: phf::Map<&'static str, &'static str> = phf_map! {
     "e75" => "Emotion 75",
 };
 
+pub static MODEL_TO_CFA: phf::Map<&'static str, [u8; 4]> = phf_map! {
+    "e22" => E75_CFA,
+    "e75" => E75_CFA,
+};
+
 pub static MODEL_TO_SIZE: phf::Map<&'static str, (u32, u32)> = phf_map! {
     "e22" => (5344, 4008),
     "e75" => (6668, 4992),
 };
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
 pub enum WhiteBalance {
     Manual = 7,
     Flash = 0,
@@ -38,6 +65,7 @@ pub enum WhiteBalance {
     Shadow = 3,
     Sun = 4,
     Cloudy = 5,
+    #[default]
     Unknown = 6,
 }
 
@@ -55,9 +83,32 @@ impl WhiteBalance {
             _ => WhiteBalance::Unknown,
         }
     }
+
+    /// EXIF LightSource of the preset.
+    pub fn light_source(&self) -> u16 {
+        match self {
+            WhiteBalance::Sun => 1,
+            WhiteBalance::Neon => 2,
+            WhiteBalance::Tungsten => 3,
+            WhiteBalance::Flash => 4,
+            WhiteBalance::Cloudy => 10,
+            WhiteBalance::Shadow => 11,
+            WhiteBalance::Manual => 255,
+            WhiteBalance::Unknown => 0,
+        }
+    }
+
+    /// EXIF WhiteBalance: 1 (manual) for anything picked on the back, 0
+    /// (auto) otherwise.
+    pub fn exif_mode(&self) -> u16 {
+        match self {
+            WhiteBalance::Unknown => 0,
+            _ => 1,
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct SinarIAMeta {
     pub shutter_count: u32,
     pub camera: String,
@@ -73,39 +124,74 @@ pub struct SinarIAMeta {
     pub white_balance_name: WhiteBalance,
     pub focal_length: f32,
     pub white_ref: String,
+    /// The META lump as read, so it can be carried through to the DNG.
+    pub meta_lump: Vec<u8>,
+    /// When the frame was taken; not part of META, see `capture`.
+    pub captured: Option<CaptureTime>,
+    /// The user's descriptive fields, see `xmp`.
+    pub annotations: Annotations,
+    /// TIFF/EXIF orientation, see `orientation`.
+    pub orientation: u16,
+    /// The lens: a Hy6 lens identified from META, or one assigned from
+    /// the lens database.
+    pub lens: Option<Lens>,
+    /// LCC gains to write as DNG GainMap opcodes, see `lcc`.
+    pub gain_map: Option<Arc<LccMap>>,
+    /// Noise of the calibrated samples, or of the sensor counts if
+    /// uncalibrated; see `noise`.
+    pub noise: Option<NoiseModel>,
+    /// The back's entry in the calibration database, see `backs`.
+    pub back: Option<Arc<BackCalibration>>,
+    /// The back's camera profile to embed in DNGs, see `dcp`.
+    pub profile: Option<Arc<Dcp>>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
 }
 
 impl SinarIAMeta {
-    pub fn process_meta(meta: &[u8]) -> Self {
-        let shutter_count = u32::from_le_bytes(meta[4..8].try_into().unwrap());
-        let camera = str::from_utf8(&meta[20..64])
-            .unwrap()
-            .trim_end_matches('\x00')
-            .to_string();
-        let white_balance_name =
-            WhiteBalance::from(u16::from_le_bytes(meta[100..102].try_into().unwrap()));
-        let shutter_time_us = u32::from_le_bytes(meta[104..108].try_into().unwrap());
-        let black_ref = str::from_utf8(&meta[108 + 14..172])
-            .unwrap()
-            .trim_end_matches('\x00')
-            .to_string();
-        let white_ref = str::from_utf8(&meta[172 + 14..236])
-            .unwrap()
-            .trim_end_matches('\x00')
-            .to_string();
-        let iso = u32::from_le_bytes(meta[252..256].try_into().unwrap());
-        let serial = str::from_utf8(&meta[272..288])
-            .unwrap()
-            .trim_end_matches('\x00')
-            .to_string();
-        let shutter_time_us_2 = u32::from_le_bytes(meta[344..348].try_into().unwrap());
-        let f_stop = (u16::from_le_bytes(meta[352..354].try_into().unwrap()) as f32) / 256.0;
-        let focal_length = u32::from_le_bytes(meta[356..360].try_into().unwrap()) as f32 / 1000.0;
+    /// Parse a META lump. A lump too short for its fields, or with a text
+    /// field that is not UTF-8, is `InvalidData` rather than a panic, so one
+    /// damaged frame doesn't stop a batch.
+    pub fn process_meta(meta: &[u8]) -> io::Result<Self> {
+        if meta.len() < META_LEN {
+            return Err(invalid(format!(
+                "META lump is {} bytes, expected {}",
+                meta.len(),
+                META_LEN
+            )));
+        }
+        let u16_at = |at: usize| u16::from_le_bytes(meta[at..at + 2].try_into().unwrap());
+        let u32_at = |at: usize| u32::from_le_bytes(meta[at..at + 4].try_into().unwrap());
+        // Text fields are NUL padded.
+        let text_at = |start: usize, end: usize| -> io::Result<String> {
+            let field = &meta[start..end];
+            let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+            str::from_utf8(&field[..len])
+                .map(str::to_string)
+                .map_err(|e| invalid(format!("META text at byte {}: {}", start, e)))
+        };
+        let shutter_count = u32_at(4);
+        let camera = text_at(20, 64)?;
+        let white_balance_name = WhiteBalance::from(u16_at(100));
+        let shutter_time_us = u32_at(104);
+        let black_ref = text_at(108 + 14, 172)?;
+        let white_ref = text_at(172 + 14, 236)?;
+        let iso = u32_at(252);
+        let serial = text_at(272, 288)?;
+        let shutter_time_us_2 = u32_at(344);
+        let f_stop = u16_at(352) as f32 / 256.0;
+        let focal_length = u32_at(356) as f32 / 1000.0;
         let short_model = serial.split('-').next().unwrap();
-        let model = MODEL_NAMES[short_model];
-        let (height, width) = MODEL_TO_SIZE[short_model];
+        let model = MODEL_NAMES.get(short_model).copied().unwrap_or("Unknown");
+        let (height, width) = MODEL_TO_SIZE.get(short_model).copied().unwrap_or((0, 0));
+        let lens = match camera.as_str() {
+            HY6_CAMERA => lens::guess_hy6(focal_length),
+            _ => None,
+        };
 
-        SinarIAMeta {
+        Ok(SinarIAMeta {
             shutter_count,
             camera,
             measured_shutter_us: shutter_time_us,
@@ -120,24 +206,36 @@ impl SinarIAMeta {
             width,
             white_balance_name,
             focal_length,
-        }
+            meta_lump: meta.to_vec(),
+            captured: None,
+            annotations: Annotations::default(),
+            orientation: orientation::HY6,
+            lens,
+            gain_map: None,
+            noise: None,
+            back: None,
+            profile: None,
+        })
     }
-}
 
-fn bufferu8_u16_to_1d_array_f64(buffer: &Vec<u8>, width: usize, height: usize) -> Array1<f64> {
-    assert_eq!(buffer.len(), width * height * 2);
-
-    let mut array = Array1::zeros(height * width);
+    /// Whether the serial's model prefix is one we know the sensor size of.
+    pub fn is_known_model(&self) -> bool {
+        MODEL_TO_SIZE.contains_key(self.serial.split('-').next().unwrap_or(""))
+    }
 
-    {
-        let mut array_view = array.view_mut();
-        for (i, chunk) in buffer.chunks_exact(2).enumerate() {
-            let value = u16::from_le_bytes(chunk.try_into().unwrap()) as f64 / (u16::MAX as f64);
-            array_view[i] = value;
-        }
+    /// The 2x2 CFA pattern of the back's sensor.
+    pub fn cfa_pattern(&self) -> [u8; 4] {
+        MODEL_TO_CFA
+            .get(self.serial.split('-').next().unwrap_or(""))
+            .copied()
+            .unwrap_or(E75_CFA)
     }
 
-    array
+    /// Read and parse only the META lump of an IA file.
+    pub fn from_ia(path: &Path) -> io::Result<Self> {
+        let pwad = pwad::Pwad::from_file(path)?;
+        SinarIAMeta::process_meta(&pwad.read_lump_by_tag(META_KEY)?)
+    }
 }
 
 //unused
@@ -169,87 +267,463 @@ fn subract_black_ref_st(
     result
 }
 
-fn subract_black_ref_mut(
-    image: &mut Array1<f64>,
-    black_ref0: &Array1<f64>,
-    black_ref1: &Array1<f64>,
-) {
-    assert_eq!(image.shape(), black_ref0.shape());
-    {
-        Zip::from(image)
-            .and(black_ref0)
-            .and(black_ref1)
-            .par_for_each(|i, &b0, &b1| {
-                *i = (*i - b0) - (b1 - b0);
-            });
+/// Settings shared by every frame in a conversion batch.
+#[derive(Debug, Clone)]
+pub struct ConvertOptions {
+    pub on_exist: iadng::OnExist,
+    pub format: OutputFormat,
+    pub demosaic: demosaic::Algorithm,
+    pub calibration: Calibration,
+    pub precision: Precision,
+    /// Corrections for the back's clock
+    pub time: TimeCorrection,
+    /// Source files to embed in DNG output
+    pub embed: Embed,
+    /// TIFF/EXIF orientation of every frame
+    pub orientation: u16,
+    /// Lens and aperture overrides
+    pub lens: LensOptions,
+    /// Descriptive fields for the XMP packet
+    pub xmp: XmpOptions,
+    /// LCC frames and how to apply them
+    pub lcc: LccOptions,
+    /// Calibrations of the backs in use
+    pub backs: BackDb,
+    /// Stream CFA DNGs from the files in row bands instead of decoding whole
+    /// frames. Ignored with `Calibration::None`, which only holds RAW0.
+    pub stream: bool,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        ConvertOptions {
+            on_exist: iadng::OnExist::Skip,
+            format: OutputFormat::Dng,
+            demosaic: demosaic::Algorithm::Directional,
+            calibration: Calibration::DarkFlat,
+            precision: Precision::F64,
+            embed: Embed::None,
+            time: TimeCorrection::default(),
+            orientation: orientation::HY6,
+            lens: LensOptions::default(),
+            xmp: XmpOptions::default(),
+            lcc: LccOptions::default(),
+            backs: BackDb::default(),
+            stream: false,
+        }
     }
 }
 
-fn apply_white_ref_mut(image: &mut Array1<f64>, white_ref: &Array1<f64>) {
-    assert_eq!(image.shape(), white_ref.shape());
-    {
-        Zip::from(image).and(white_ref).par_for_each(|i, &w| {
-            *i /= w;
-        });
-    }
-}
-
-pub fn process_ia(path: &PathBuf, output_dir: &PathBuf) -> Option<()> {
-    let metadata = pwad::Pwad::from_file(path.to_str()?).unwrap();
-    metadata
-        .read_lump_by_tag(META_KEY)
-        .and_then(|meta| {
-            let ia = SinarIAMeta::process_meta(&meta);
-            let black_full_path = path.parent().unwrap().join(&ia.black_ref);
-            let white_full_path: PathBuf = path.parent().unwrap().join(&ia.white_ref);
-            println!(
-                "Processing IA: {}...\n\tblack: {}\n\twhite: {}",
-                path.to_str().unwrap(),
-                black_full_path.to_str().unwrap(),
-                white_full_path.to_str().unwrap()
+/// Wall-clock time spent in each stage of a conversion.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameTimings {
+    pub read: Duration,
+    pub calibrate: Duration,
+    pub write: Duration,
+    pub total: Duration,
+}
+
+/// What happened to one converted frame.
+#[derive(Debug, Clone)]
+pub struct FrameReport {
+    pub ia: PathBuf,
+    pub output: PathBuf,
+    pub flat_field: bool,
+    pub timings: FrameTimings,
+    pub stats: iadng::WriteStats,
+}
+
+#[derive(Debug, Clone)]
+pub enum FrameOutcome {
+    Converted(FrameReport),
+    Skipped,
+    Failed(String),
+}
+
+/// Convert one IA file to the DNG at `new_dng`, creating parent directories.
+/// BR/WR references are taken from `cache` when a previous frame loaded them.
+pub fn process_ia(
+    path: &Path,
+    new_dng: &Path,
+    options: &ConvertOptions,
+    cache: &RefCache,
+) -> FrameOutcome {
+    let start = Instant::now();
+    match convert_ia(path, new_dng, options, cache) {
+        Ok(Some(mut report)) => {
+            report.timings.total = start.elapsed();
+            info!(
+                "Converted {} -> {} in {:.2?} (read {:.2?}, calibrate {:.2?}, write {:.2?})",
+                path.display(),
+                report.output.display(),
+                report.timings.total,
+                report.timings.read,
+                report.timings.calibrate,
+                report.timings.write
             );
-            pwad::Pwad::from_file(black_full_path.to_str().unwrap()).and_then(|black| {
-                Ok({
-                    let mut raw = bufferu8_u16_to_1d_array_f64(
-                        &metadata.read_lump_by_tag(RAW_KEY)?,
-                        ia.width as usize,
-                        ia.height as usize,
-                    );
-                    let black_ref0 = bufferu8_u16_to_1d_array_f64(
-                        &black.read_lump_by_tag(BLACK0_KEY)?,
-                        ia.width as usize,
-                        ia.height as usize,
-                    );
-                    let black_ref1 = bufferu8_u16_to_1d_array_f64(
-                        &black.read_lump_by_tag(BLACK1_KEY)?,
-                        ia.width as usize,
-                        ia.height as usize,
-                    );
-                    subract_black_ref_mut(&mut raw, &black_ref0, &black_ref1);
-                    pwad::Pwad::from_file(white_full_path.to_str().unwrap())
-                        .and_then(|white| {
-                            Ok({
-                                apply_white_ref_mut(
-                                    &mut raw,
-                                    &bufferu8_u16_to_1d_array_f64(
-                                        &white.read_lump_by_tag(WHITE_KEY)?,
-                                        ia.width as usize,
-                                        ia.height as usize,
-                                    ),
-                                );
-                            })
-                        })
-                        .ok();
-                    iadng::write_1d_array_to_dng(
-                        &raw,
-                        &metadata.read_lump_by_tag(THUMB_KEY)?,
-                        &output_dir,
-                        &ia,
-                    )
-                    .unwrap()
-                })
+            FrameOutcome::Converted(report)
+        }
+        Ok(None) => FrameOutcome::Skipped,
+        Err(e) => {
+            error!("{}: {}", path.display(), e);
+            FrameOutcome::Failed(e.to_string())
+        }
+    }
+}
+
+// An IA frame's sensor data, where its references live and the files to
+// embed in its DNG.
+struct RawFrame {
+    meta: SinarIAMeta,
+    raw: Vec<u16>,
+    thumb: Vec<u8>,
+    black_ref: PathBuf,
+    white_ref: PathBuf,
+    originals: Originals,
+    /// LCC correction to bake in
+    lcc: Option<Arc<LccMap>>,
+}
+
+fn convert_ia(
+    path: &Path,
+    new_dng: &Path,
+    options: &ConvertOptions,
+    cache: &RefCache,
+) -> io::Result<Option<FrameReport>> {
+    if options.calibration == Calibration::None && options.format != OutputFormat::Dng {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "uncalibrated conversion only supports DNG output",
+        ));
+    }
+    if options.embed != Embed::None && options.format.extension() != "dng" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "original files can only be embedded in DNG output",
+        ));
+    }
+    if !options.lcc.frames.is_empty() {
+        match options.lcc.apply {
+            LccApply::GainMap if options.format != OutputFormat::Dng => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "GainMap LCC correction needs CFA DNG output",
+                ));
+            }
+            LccApply::Bake if options.calibration == Calibration::None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "LCC correction can't be baked into uncalibrated output",
+                ));
+            }
+            _ => {}
+        }
+    }
+    let mut timings = FrameTimings::default();
+    let stage = Instant::now();
+    let metadata = pwad::Pwad::from_file(path)?;
+    let mut ia = SinarIAMeta::process_meta(&metadata.read_lump_by_tag(META_KEY)?)?;
+    ia.captured = CaptureTime::of_ia(path, &options.time);
+    ia.annotations = options.xmp.for_ia(path)?;
+    ia.orientation = options.orientation;
+    options.lens.apply(&mut ia);
+    ia.back = options.backs.get(&ia.serial).cloned();
+    if !ia.is_known_model() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unknown model for serial '{}'", ia.serial),
+        ));
+    }
+    if options.format.extension() == "dng" {
+        ia.profile = options.backs.profile(&ia.serial).cloned();
+        if let Some(profile) = &ia.profile {
+            profile
+                .check_camera(&iadng::unique_camera_model(&ia))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+    }
+    let black_full_path = path.parent().unwrap().join(&ia.black_ref);
+    let white_full_path: PathBuf = path.parent().unwrap().join(&ia.white_ref);
+    info!(
+        "Processing IA: {} (black: {}, white: {})",
+        path.display(),
+        black_full_path.display(),
+        white_full_path.display()
+    );
+    let new_dng = match iadng::resolve_output(new_dng, &ia, options) {
+        Some(new_dng) => new_dng,
+        None => return Ok(None),
+    };
+    let originals = Originals::collect(path, &[&black_full_path, &white_full_path], options.embed)?;
+    let lcc = match options.lcc.for_frame(&ia) {
+        Some(frame) => {
+            info!("Correcting lens cast from {}", frame.path.display());
+            let (width, height) = (ia.width as usize, ia.height as usize);
+            let gains = cache.lcc(&frame.path, width, height)?;
+            Some(Arc::new(LccMap::from_gains(gains, width, height)?))
+        }
+        None => None,
+    };
+    let lcc = match options.lcc.apply {
+        LccApply::GainMap => {
+            ia.gain_map = lcc;
+            None
+        }
+        LccApply::Bake => lcc,
+    };
+    if options.stream && options.calibration != Calibration::None {
+        return convert_streamed(
+            path,
+            metadata,
+            ia,
+            black_full_path,
+            (options.calibration == Calibration::DarkFlat).then_some(white_full_path),
+            new_dng,
+            originals,
+            lcc,
+            options.precision,
+            stage,
+        )
+        .map(Some);
+    }
+    let frame = RawFrame {
+        raw: calibrate::decode_u16_le(
+            &metadata.read_lump_by_tag(RAW_KEY)?,
+            ia.width as usize,
+            ia.height as usize,
+        ),
+        meta: ia,
+        thumb: metadata.read_lump_by_tag(THUMB_KEY)?,
+        black_ref: black_full_path,
+        white_ref: white_full_path,
+        originals,
+        lcc,
+    };
+    timings.read = stage.elapsed();
+
+    if options.calibration == Calibration::None {
+        let stats = write_uncalibrated(frame, &new_dng, cache, &mut timings)?;
+        return Ok(Some(FrameReport {
+            ia: path.to_path_buf(),
+            output: new_dng,
+            flat_field: false,
+            timings,
+            stats,
+        }));
+    }
+    let (stats, flat_field) = match options.precision {
+        Precision::F64 => calibrate_and_write::<f64>(frame, &new_dng, options, cache, &mut timings),
+        Precision::F32 => calibrate_and_write::<f32>(frame, &new_dng, options, cache, &mut timings),
+        Precision::U16 => calibrate_and_write::<u16>(frame, &new_dng, options, cache, &mut timings),
+    }?;
+
+    Ok(Some(FrameReport {
+        ia: path.to_path_buf(),
+        output: new_dng,
+        flat_field,
+        timings,
+        stats,
+    }))
+}
+
+// Calibrate in `S` and write. Returns whether a flat field was applied.
+fn calibrate_and_write<S: Sample>(
+    mut frame: RawFrame,
+    new_dng: &Path,
+    options: &ConvertOptions,
+    cache: &RefCache,
+    timings: &mut FrameTimings,
+) -> io::Result<(iadng::WriteStats, bool)> {
+    let (width, height) = (frame.meta.width as usize, frame.meta.height as usize);
+    let stage = Instant::now();
+    let black = cache.dark(&frame.black_ref, width, height)?;
+    let mut gains = match options.calibration {
+        Calibration::DarkFlat => cache
+            .gains::<S>(&frame.white_ref, width, height)
+            .map_err(|e| {
+                warn!(
+                    "{}: no flat field applied: {}",
+                    frame.white_ref.display(),
+                    e
+                )
             })
+            .ok(),
+        _ => None,
+    };
+    let flat_field = gains.is_some();
+    timings.read += stage.elapsed();
+
+    let stage = Instant::now();
+    if let Some(lcc) = &frame.lcc {
+        let mut baked = match gains {
+            Some(gains) => gains.to_vec(),
+            None => vec![S::unit_gain(); width * height],
+        };
+        lcc.bake::<S>(&mut baked, 0);
+        gains = Some(Arc::new(baked));
+    }
+    let image = calibrate::calibrate::<S>(&frame.raw, &black, gains.as_ref().map(|g| g.as_slice()));
+    frame.meta.noise = frame_noise(&frame, &black, Some(&|i: usize| image[i].to_f64()), cache);
+    drop(frame.raw);
+    timings.calibrate = stage.elapsed();
+
+    let stage = Instant::now();
+    let stats = export::write_output(
+        &image,
+        &frame.thumb,
+        new_dng,
+        &frame.meta,
+        options,
+        frame.originals,
+    )?;
+    timings.write = stage.elapsed();
+    Ok((stats, flat_field))
+}
+
+// The frame's noise, from its sensor counts and, if calibrated, the
+// samples they were calibrated to. `None` if the BR file has no BLACK0.
+fn measure_noise(
+    frame: &RawFrame,
+    black: &[u16],
+    calibrated: Option<&(dyn Fn(usize) -> f64 + Sync)>,
+    cache: &RefCache,
+) -> Option<NoiseModel> {
+    let (width, height) = (frame.meta.width as usize, frame.meta.height as usize);
+    let cfa = frame.meta.cfa_pattern();
+    let read = cache
+        .read_noise(&frame.black_ref, width, height, cfa)
+        .map_err(|e| {
+            warn!(
+                "{}: no noise profile written: {}",
+                frame.black_ref.display(),
+                e
+            )
         })
-        .map_err(|e| println!("Error: {}", e))
-        .ok()
+        .ok()?;
+    NoiseStats::measure(&frame.raw, black, calibrated, width, cfa, 0)
+        .model([read[0], read[1], read[2]])
+}
+
+// The frame's noise, from the back's calibration if it has one.
+fn frame_noise(
+    frame: &RawFrame,
+    black: &[u16],
+    calibrated: Option<&(dyn Fn(usize) -> f64 + Sync)>,
+    cache: &RefCache,
+) -> Option<NoiseModel> {
+    match frame.meta.back.as_ref().and_then(|back| back.noise) {
+        Some(noise) => {
+            let (width, cfa) = (frame.meta.width as usize, frame.meta.cfa_pattern());
+            NoiseStats::measure(&frame.raw, black, calibrated, width, cfa, 0).scale(noise)
+        }
+        None => measure_noise(frame, black, calibrated, cache),
+    }
+}
+
+// Write RAW0 untouched, with black levels from the BR frame unless the back's
+// calibration has them.
+fn write_uncalibrated(
+    mut frame: RawFrame,
+    new_dng: &Path,
+    cache: &RefCache,
+    timings: &mut FrameTimings,
+) -> io::Result<iadng::WriteStats> {
+    let (width, height) = (frame.meta.width as usize, frame.meta.height as usize);
+    let stage = Instant::now();
+    let mut levels = match cache.dark(&frame.black_ref, width, height) {
+        Ok(dark) => {
+            frame.meta.noise = frame_noise(&frame, &dark, None, cache);
+            SensorLevels::from_dark(&dark, width)
+        }
+        Err(e) => {
+            warn!(
+                "{}: no black level recorded: {}",
+                frame.black_ref.display(),
+                e
+            );
+            SensorLevels::full_range()
+        }
+    };
+    if let Some(back) = &frame.meta.back {
+        levels.black = back.black_level.unwrap_or(levels.black);
+        levels.white = back.white_level.unwrap_or(levels.white);
+    }
+    timings.read += stage.elapsed();
+
+    let stage = Instant::now();
+    let raw = iadng::SensorRaw {
+        data: &frame.raw,
+        levels,
+    };
+    let stats = iadng::write_sensor_dng(raw, &frame.thumb, new_dng, &frame.meta, frame.originals)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    timings.write = stage.elapsed();
+    Ok(stats)
+}
+
+// Convert a frame in row bands straight from the IA and reference files.
+#[allow(clippy::too_many_arguments)]
+fn convert_streamed(
+    path: &Path,
+    metadata: pwad::Pwad,
+    mut ia: SinarIAMeta,
+    black_ref: PathBuf,
+    white_ref: Option<PathBuf>,
+    new_dng: PathBuf,
+    originals: Originals,
+    lcc: Option<Arc<LccMap>>,
+    precision: Precision,
+    start: Instant,
+) -> io::Result<FrameReport> {
+    let mut timings = FrameTimings::default();
+    let black = pwad::Pwad::from_file(black_ref)?;
+    let white = white_ref.and_then(|white_ref| {
+        pwad::Pwad::from_file(&white_ref)
+            .and_then(|white| {
+                white.open_lump(WHITE_KEY)?;
+                Ok(white)
+            })
+            .map_err(|e| warn!("{}: no flat field applied: {}", white_ref.display(), e))
+            .ok()
+    });
+    let thumb = metadata.read_lump_by_tag(THUMB_KEY)?;
+    let frame = StreamFrame::new(metadata, black, white, &ia, stream::BAND_ROWS)?.with_lcc(lcc);
+    timings.read = start.elapsed();
+
+    let write = match precision {
+        Precision::F64 => write_streamed::<f64>,
+        Precision::F32 => write_streamed::<f32>,
+        Precision::U16 => write_streamed::<u16>,
+    };
+    let stats = write(&frame, &mut ia, &thumb, &new_dng, originals, &mut timings)?;
+
+    Ok(FrameReport {
+        ia: path.to_path_buf(),
+        output: new_dng,
+        flat_field: frame.has_flat_field(),
+        timings,
+        stats,
+    })
+}
+
+// Calibrate a streamed frame in `S`, both passes, and write it.
+fn write_streamed<S: Sample>(
+    frame: &StreamFrame,
+    ia: &mut SinarIAMeta,
+    thumb: &[u8],
+    new_dng: &Path,
+    originals: Originals,
+    timings: &mut FrameTimings,
+) -> io::Result<iadng::WriteStats> {
+    let stage = Instant::now();
+    let mut raw = StreamedRaw::<S>::new(frame);
+    raw.analyse()?;
+    ia.noise = raw.noise(ia.back.as_ref().and_then(|back| back.noise));
+    timings.calibrate = stage.elapsed();
+
+    let stage = Instant::now();
+    let stats = stream::write_streamed_dng(raw, thumb, new_dng, ia, originals)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    timings.write = stage.elapsed();
+    Ok(stats)
 }
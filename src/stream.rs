@@ -0,0 +1,448 @@
+/*
+Row-band streaming conversion to CFA DNG.
+
+Instead of decoding RAW0 and the references as whole frames, matching bands
+of rows are read straight from the IA, BR and WR lumps. Scaling to 16 bits
+needs the calibrated frame's range, so the frame is streamed twice: once to
+collect the range and white balance, and once to calibrate and write each
+band as a DNG strip. Each worker only ever holds a band, a few megabytes for
+an E75 at the default band height.
+
+Bands are calibrated in the `--precision` sample type, so the output
+matches the whole-frame conversion; the 16-bit gains depend on the
+darkest white site, so the WR is scanned for it before the first pass. A
+baked LCC correction is applied to each band's gains. The first pass also
+collects the noise statistics of each band, reading BLACK0 for the read
+noise.
+*/
+
+use std::io;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Arc;
+
+use log::info;
+use ndarray::Array1;
+use rawler::formats::tiff::TiffError;
+use rayon::prelude::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+
+use crate::calibrate::{self, Sample};
+use crate::demosaic::cfa_color;
+use crate::iadng::{self, RawStrips, WriteStats};
+use crate::lcc::LccMap;
+use crate::noise::{DarkStats, NoiseModel, NoiseStats};
+use crate::original::Originals;
+use crate::pwad::{LumpReader, Pwad};
+use crate::sinar_ia::{SinarIAMeta, BLACK0_KEY, BLACK1_KEY, RAW_KEY, WHITE_KEY};
+
+/// Rows per band, and per DNG strip.
+pub const BAND_ROWS: usize = 64;
+
+/// An IA frame and its references, readable a band of rows at a time.
+#[derive(Debug)]
+pub struct StreamFrame {
+    ia: Pwad,
+    black: Pwad,
+    white: Option<Pwad>,
+    width: usize,
+    height: usize,
+    cfa: [u8; 4],
+    band_rows: usize,
+    lcc: Option<Arc<LccMap>>,
+    // Darkest non-zero site of the whole white reference, which fixed-point
+    // gains are relative to.
+    darkest_white: u16,
+}
+
+// One worker's open lumps.
+struct Readers {
+    raw: LumpReader,
+    black: LumpReader,
+    black0: Option<LumpReader>,
+    white: Option<LumpReader>,
+}
+
+// A calibrated band, with the sensor counts and dark frame it came from.
+struct Band<S: Sample> {
+    y0: usize,
+    raw: Vec<u16>,
+    black: Vec<u16>,
+    image: Array1<S>,
+}
+
+// Range, per-colour sums and noise statistics of a calibrated band, or of
+// the whole frame.
+#[derive(Debug, Clone, Default)]
+struct BandStats {
+    min: f64,
+    max: f64,
+    sums: [f64; 3],
+    counts: [u64; 3],
+    dark: Option<DarkStats>,
+    noise: NoiseStats,
+}
+
+impl BandStats {
+    fn merge(mut self, other: BandStats) -> BandStats {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        for c in 0..3 {
+            self.sums[c] += other.sums[c];
+            self.counts[c] += other.counts[c];
+        }
+        self.dark = match (self.dark, other.dark) {
+            (Some(a), Some(b)) => Some(a.merge(b)),
+            (a, b) => a.or(b),
+        };
+        self.noise = self.noise.merge(other.noise);
+        self
+    }
+}
+
+fn same_kind(e: &io::Error) -> io::Error {
+    io::Error::new(e.kind(), e.to_string())
+}
+
+impl StreamFrame {
+    /// Check that every lump holds a full frame for `meta`.
+    pub fn new(
+        ia: Pwad,
+        black: Pwad,
+        white: Option<Pwad>,
+        meta: &SinarIAMeta,
+        band_rows: usize,
+    ) -> io::Result<Self> {
+        let mut frame = StreamFrame {
+            ia,
+            black,
+            white,
+            width: meta.width as usize,
+            height: meta.height as usize,
+            cfa: meta.cfa_pattern(),
+            band_rows: band_rows.max(1),
+            lcc: None,
+            darkest_white: 1,
+        };
+        let mut readers = frame.readers()?;
+        let expected = (frame.width * frame.height * 2) as u64;
+        for (name, lump) in [
+            (RAW_KEY, Some(&readers.raw)),
+            (BLACK1_KEY, Some(&readers.black)),
+            (BLACK0_KEY, readers.black0.as_ref()),
+            (WHITE_KEY, readers.white.as_ref()),
+        ] {
+            if let Some(lump) = lump {
+                if lump.size() != expected {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "{} lump holds {} bytes, expected {}",
+                            name,
+                            lump.size(),
+                            expected
+                        ),
+                    ));
+                }
+            }
+        }
+        if let Some(white) = &mut readers.white {
+            let mut darkest = None;
+            for band in 0..frame.bands() {
+                let white = frame.read_band(white, band)?;
+                let band_darkest = white.into_iter().filter(|&w| w > 0).min();
+                darkest = darkest.into_iter().chain(band_darkest).min();
+            }
+            frame.darkest_white = darkest.unwrap_or(1);
+        }
+        Ok(frame)
+    }
+
+    /// Bake the LCC correction `lcc` into every band.
+    pub fn with_lcc(mut self, lcc: Option<Arc<LccMap>>) -> Self {
+        self.lcc = lcc;
+        self
+    }
+
+    pub fn has_flat_field(&self) -> bool {
+        self.white.is_some()
+    }
+
+    fn bands(&self) -> usize {
+        self.height.div_ceil(self.band_rows)
+    }
+
+    fn readers(&self) -> io::Result<Readers> {
+        Ok(Readers {
+            raw: self.ia.open_lump(RAW_KEY)?,
+            black: self.black.open_lump(BLACK1_KEY)?,
+            black0: self.black.open_lump(BLACK0_KEY).ok(),
+            white: match &self.white {
+                Some(white) => Some(white.open_lump(WHITE_KEY)?),
+                None => None,
+            },
+        })
+    }
+
+    // The samples of band `band` in `lump`.
+    fn read_band(&self, lump: &mut LumpReader, band: usize) -> io::Result<Vec<u16>> {
+        let y0 = band * self.band_rows;
+        let rows = self.band_rows.min(self.height - y0);
+        let mut bytes = vec![0; rows * self.width * 2];
+        lump.read_at((y0 * self.width * 2) as u64, &mut bytes)?;
+        Ok(calibrate::decode_u16_le(&bytes, self.width, rows))
+    }
+
+    // Calibrated samples of band `band`.
+    fn calibrate_band<S: Sample>(&self, readers: &mut Readers, band: usize) -> io::Result<Band<S>> {
+        let y0 = band * self.band_rows;
+        let raw = self.read_band(&mut readers.raw, band)?;
+        let black = self.read_band(&mut readers.black, band)?;
+        let mut gains = match &mut readers.white {
+            Some(white) => Some(S::band_gains(
+                &self.read_band(white, band)?,
+                self.darkest_white,
+            )),
+            None => None,
+        };
+        if let Some(lcc) = &self.lcc {
+            let gains = gains.get_or_insert_with(|| vec![S::unit_gain(); raw.len()]);
+            lcc.bake::<S>(gains, y0);
+        }
+        let image = calibrate::calibrate::<S>(&raw, &black, gains.as_deref());
+        Ok(Band {
+            y0,
+            raw,
+            black,
+            image,
+        })
+    }
+
+    fn band_stats<S: Sample>(&self, readers: &mut Readers, band: usize) -> io::Result<BandStats> {
+        let Band {
+            y0,
+            raw,
+            black,
+            image,
+        } = self.calibrate_band::<S>(readers, band)?;
+        let dark = match &mut readers.black0 {
+            Some(lump) => {
+                let mut bytes = vec![0; raw.len() * 2];
+                lump.read_at((y0 * self.width * 2) as u64, &mut bytes)?;
+                let black0 = calibrate::decode_u16_le(&bytes, self.width, raw.len() / self.width);
+                Some(DarkStats::measure(
+                    &black0, &black, self.width, self.cfa, y0,
+                ))
+            }
+            None => None,
+        };
+        let mut stats = BandStats {
+            dark,
+            noise: NoiseStats::measure(
+                &raw,
+                &black,
+                Some(&|i: usize| image[i].to_f64()),
+                self.width,
+                self.cfa,
+                y0,
+            ),
+            ..BandStats::default()
+        };
+        for (i, v) in image.iter().map(|v| v.to_f64()).enumerate() {
+            stats.min = stats.min.min(v);
+            stats.max = stats.max.max(v);
+            let c = cfa_color(self.cfa, i % self.width, y0 + i / self.width);
+            stats.sums[c] += v;
+            stats.counts[c] += 1;
+        }
+        Ok(stats)
+    }
+}
+
+/// The raw IFD data of a `StreamFrame`, calibrated in `S` and produced band
+/// by band.
+pub(crate) struct StreamedRaw<'a, S: Sample> {
+    frame: &'a StreamFrame,
+    stats: Option<BandStats>,
+    sample: PhantomData<S>,
+}
+
+impl<'a, S: Sample> StreamedRaw<'a, S> {
+    pub(crate) fn new(frame: &'a StreamFrame) -> Self {
+        StreamedRaw {
+            frame,
+            stats: None,
+            sample: PhantomData,
+        }
+    }
+
+    /// The frame's noise, once analysed: `known`, in counts, if given,
+    /// otherwise measured; `None` then without a BLACK0 lump.
+    pub(crate) fn noise(&self, known: Option<NoiseModel>) -> Option<NoiseModel> {
+        let stats = self.stats.as_ref()?;
+        match known {
+            Some(known) => stats.noise.scale(known),
+            None => stats.noise.model(stats.dark.as_ref()?.read_noise()),
+        }
+    }
+
+    /// First pass: the calibrated frame's range and colour sums.
+    pub(crate) fn analyse(&mut self) -> io::Result<()> {
+        if self.stats.is_some() {
+            return Ok(());
+        }
+        let frame = self.frame;
+        let stats = (0..frame.bands())
+            .into_par_iter()
+            .map_init(
+                || frame.readers(),
+                |readers, band| {
+                    let readers = readers.as_mut().map_err(|e| same_kind(e))?;
+                    frame.band_stats::<S>(readers, band)
+                },
+            )
+            .try_reduce(BandStats::default, |a, b| Ok(a.merge(b)))?;
+        self.stats = Some(stats);
+        Ok(())
+    }
+}
+
+impl<'a, S: Sample> RawStrips for StreamedRaw<'a, S> {
+    fn samples(&self) -> u16 {
+        1
+    }
+
+    fn white_balance(&mut self, _meta: &SinarIAMeta) -> Result<(f64, f64, f64), TiffError> {
+        self.analyse()?;
+        let stats = self.stats.as_ref().unwrap();
+        let avg: Vec<f64> = (0..3)
+            .map(|c| stats.sums[c] / stats.counts[c] as f64)
+            .collect();
+        let max_avg = avg[0].max(avg[1]).max(avg[2]);
+        Ok((avg[0] / max_avg, avg[1] / max_avg, avg[2] / max_avg))
+    }
+
+    // Second pass: one strip per band. Bands are calibrated in parallel a
+    // group at a time and written in order.
+    fn write_strips(
+        &mut self,
+        write: &mut dyn FnMut(&[u16]) -> Result<(), TiffError>,
+    ) -> Result<WriteStats, TiffError> {
+        self.analyse()?;
+        let BandStats { min, max, .. } = *self.stats.as_ref().unwrap();
+        let scale = u16::MAX as f64 / (max - min);
+        let frame = self.frame;
+        let bands: Vec<usize> = (0..frame.bands()).collect();
+        for group in bands.chunks(rayon::current_num_threads().max(1)) {
+            let strips = group
+                .par_iter()
+                .map_init(
+                    || frame.readers(),
+                    |readers, &band| {
+                        let readers = readers.as_mut().map_err(|e| same_kind(e))?;
+                        let band = frame.calibrate_band::<S>(readers, band)?;
+                        Ok(band
+                            .image
+                            .iter()
+                            .map(|v| ((v.to_f64() - min) * scale).round() as u16)
+                            .collect::<Vec<u16>>())
+                    },
+                )
+                .collect::<io::Result<Vec<Vec<u16>>>>()?;
+            for strip in &strips {
+                write(strip)?;
+            }
+        }
+        Ok(WriteStats {
+            min,
+            max,
+            scale,
+            ..Default::default()
+        })
+    }
+}
+
+/// Write a streamed frame as a CFA DNG. `analyse` may be called first to
+/// time the two passes separately.
+pub(crate) fn write_streamed_dng<S: Sample>(
+    raw: StreamedRaw<S>,
+    thumb: &[u8],
+    new_dng: &Path,
+    meta: &SinarIAMeta,
+    originals: Originals,
+) -> Result<WriteStats, TiffError> {
+    info!("Writing DNG to {}", new_dng.display());
+    iadng::write_atomically(new_dng, |tmp| {
+        iadng::write_dng_file(raw, thumb, tmp, meta, originals)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pwad;
+
+    #[test]
+    fn test_stream_matches_whole_frame() {
+        let dir = crate::test_dir("stream");
+        let meta = SinarIAMeta {
+            serial: "e75-0042".to_string(),
+            width: 6,
+            height: 10,
+            ..SinarIAMeta::default()
+        };
+        let sites = 60;
+        let lump = |f: &dyn Fn(usize) -> u16| -> Vec<u8> {
+            (0..sites).flat_map(|i| f(i).to_le_bytes()).collect()
+        };
+        let raw: Vec<u16> = (0..sites).map(|i| 900 + (i * 397 % 5000) as u16).collect();
+        let black: Vec<u16> = (0..sites).map(|i| 1000 + (i % 7) as u16).collect();
+        let white: Vec<u16> = (0..sites).map(|i| 40_000 + (i * 13) as u16).collect();
+        pwad::write_pwad(&dir.join("FRAME.IA"), &[("RAW0", lump(&|i| raw[i]))]).unwrap();
+        pwad::write_pwad(&dir.join("FRAME.BR"), &[("BLACK1", lump(&|i| black[i]))]).unwrap();
+        pwad::write_pwad(&dir.join("FRAME.WR"), &[("WHITE", lump(&|i| white[i]))]).unwrap();
+        let open = |name: &str| Pwad::from_file(dir.join(name)).unwrap();
+
+        let frame = StreamFrame::new(
+            open("FRAME.IA"),
+            open("FRAME.BR"),
+            Some(open("FRAME.WR")),
+            &meta,
+            4,
+        )
+        .unwrap();
+        check_stream::<f64>(&frame, &meta, &raw, &black, &white);
+        check_stream::<f32>(&frame, &meta, &raw, &black, &white);
+        check_stream::<u16>(&frame, &meta, &raw, &black, &white);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Streamed in `S`, the frame comes out as the whole-frame path writes it.
+    fn check_stream<S: Sample>(
+        frame: &StreamFrame,
+        meta: &SinarIAMeta,
+        raw: &[u16],
+        black: &[u16],
+        white: &[u16],
+    ) {
+        let mut streamed = StreamedRaw::<S>::new(frame);
+        let mut strips = Vec::new();
+        streamed
+            .write_strips(&mut |strip| {
+                strips.push(strip.to_vec());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            strips.iter().map(|s| s.len()).collect::<Vec<_>>(),
+            vec![24, 24, 12]
+        );
+
+        let image = calibrate::calibrate::<S>(raw, black, Some(&S::gains(white)));
+        let (expected, _) = iadng::scale_to_u16(&image);
+        assert_eq!(strips.concat(), expected);
+        let wb = streamed.white_balance(meta).unwrap();
+        let expected_wb = iadng::DngRaw::Cfa(&image).white_balance(meta).unwrap();
+        assert!((wb.0 - expected_wb.0).abs() < 1e-12);
+        assert!((wb.2 - expected_wb.2).abs() < 1e-12);
+    }
+}
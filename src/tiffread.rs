@@ -0,0 +1,274 @@
+/*
+Minimal TIFF/DNG directory reader.
+
+Only walks IFDs and hands back raw tag data; used to check DNGs we wrote
+and to read TIFF-like side files.
+*/
+
+extern crate byteorder;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+#[derive(Debug, Clone)]
+pub struct IfdEntry {
+    pub tag: u16,
+    pub typ: u16,
+    pub count: u32,
+    pub data: Vec<u8>,
+    pub big_endian: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Ifd {
+    pub offset: u32,
+    pub entries: Vec<IfdEntry>,
+    pub next: u32,
+}
+
+#[derive(Debug)]
+pub struct TiffReader<R: Read + Seek> {
+    reader: R,
+    pub big_endian: bool,
+    pub magic: u16,
+    pub first_ifd: u32,
+    pub len: u64,
+}
+
+fn type_size(typ: u16) -> usize {
+    match typ {
+        1 | 2 | 6 | 7 => 1,
+        3 | 8 => 2,
+        4 | 9 | 11 | 13 => 4,
+        5 | 10 | 12 => 8,
+        _ => 1,
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl TiffReader<BufReader<File>> {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        TiffReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> TiffReader<R> {
+    /// Read the header. Any 16-bit magic is accepted so that TIFF variants
+    /// such as DCP ("IIRC") can be read too.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let mut order = [0u8; 2];
+        reader.read_exact(&mut order)?;
+        let big_endian = match &order {
+            b"II" => false,
+            b"MM" => true,
+            _ => return Err(invalid("Not a TIFF file".to_string())),
+        };
+        let (magic, first_ifd) = if big_endian {
+            (
+                reader.read_u16::<BigEndian>()?,
+                reader.read_u32::<BigEndian>()?,
+            )
+        } else {
+            (
+                reader.read_u16::<LittleEndian>()?,
+                reader.read_u32::<LittleEndian>()?,
+            )
+        };
+        Ok(TiffReader {
+            reader,
+            big_endian,
+            magic,
+            first_ifd,
+            len,
+        })
+    }
+
+    fn read_u16(&mut self) -> io::Result<u16> {
+        if self.big_endian {
+            self.reader.read_u16::<BigEndian>()
+        } else {
+            self.reader.read_u16::<LittleEndian>()
+        }
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        if self.big_endian {
+            self.reader.read_u32::<BigEndian>()
+        } else {
+            self.reader.read_u32::<LittleEndian>()
+        }
+    }
+
+    /// Read `len` bytes at `offset`, failing if the range runs past the end of the file.
+    pub fn read_at(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        if offset + len as u64 > self.len {
+            return Err(invalid(format!(
+                "Data at {}+{} runs past end of file ({} bytes)",
+                offset, len, self.len
+            )));
+        }
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let mut buffer = vec![0; len];
+        self.reader.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    pub fn read_ifd(&mut self, offset: u32) -> io::Result<Ifd> {
+        if offset as u64 + 2 > self.len {
+            return Err(invalid(format!("IFD offset {} past end of file", offset)));
+        }
+        self.reader.seek(SeekFrom::Start(offset as u64))?;
+        let num_entries = self.read_u16()?;
+        let mut raw = Vec::with_capacity(num_entries as usize);
+        for _ in 0..num_entries {
+            let tag = self.read_u16()?;
+            let typ = self.read_u16()?;
+            let count = self.read_u32()?;
+            let mut value = [0u8; 4];
+            self.reader.read_exact(&mut value)?;
+            raw.push((tag, typ, count, value));
+        }
+        let next = self.read_u32()?;
+        let mut entries = Vec::with_capacity(raw.len());
+        for (tag, typ, count, value) in raw {
+            let size = type_size(typ) * count as usize;
+            let data = if size <= 4 {
+                value[..size].to_vec()
+            } else {
+                let data_offset = if self.big_endian {
+                    BigEndian::read_u32(&value)
+                } else {
+                    LittleEndian::read_u32(&value)
+                };
+                self.read_at(data_offset as u64, size)?
+            };
+            entries.push(IfdEntry {
+                tag,
+                typ,
+                count,
+                data,
+                big_endian: self.big_endian,
+            });
+        }
+        Ok(Ifd {
+            offset,
+            entries,
+            next,
+        })
+    }
+
+    /// Read IFD0 and every IFD chained after it.
+    pub fn read_ifd_chain(&mut self) -> io::Result<Vec<Ifd>> {
+        let mut ifds = Vec::new();
+        let mut offset = self.first_ifd;
+        while offset != 0 {
+            if ifds.iter().any(|ifd: &Ifd| ifd.offset == offset) {
+                return Err(invalid(format!("IFD loop at offset {}", offset)));
+            }
+            let ifd = self.read_ifd(offset)?;
+            offset = ifd.next;
+            ifds.push(ifd);
+        }
+        Ok(ifds)
+    }
+}
+
+impl Ifd {
+    pub fn get(&self, tag: u16) -> Option<&IfdEntry> {
+        self.entries.iter().find(|e| e.tag == tag)
+    }
+}
+
+impl IfdEntry {
+    /// Integer values (BYTE, SHORT, LONG and their signed variants).
+    pub fn as_u32s(&self) -> Vec<u32> {
+        let size = type_size(self.typ);
+        self.data
+            .chunks_exact(size)
+            .map(|c| match (size, self.big_endian) {
+                (1, _) => c[0] as u32,
+                (2, true) => BigEndian::read_u16(c) as u32,
+                (2, false) => LittleEndian::read_u16(c) as u32,
+                (_, true) => BigEndian::read_u32(c),
+                (_, false) => LittleEndian::read_u32(c),
+            })
+            .collect()
+    }
+
+    /// Numeric values of any type as f64, rationals divided out.
+    pub fn as_f64s(&self) -> Vec<f64> {
+        let read_u32 = |c: &[u8]| {
+            if self.big_endian {
+                BigEndian::read_u32(c)
+            } else {
+                LittleEndian::read_u32(c)
+            }
+        };
+        match self.typ {
+            5 => self
+                .data
+                .chunks_exact(8)
+                .map(|c| read_u32(&c[..4]) as f64 / read_u32(&c[4..]) as f64)
+                .collect(),
+            10 => self
+                .data
+                .chunks_exact(8)
+                .map(|c| read_u32(&c[..4]) as i32 as f64 / read_u32(&c[4..]) as i32 as f64)
+                .collect(),
+            11 => self
+                .data
+                .chunks_exact(4)
+                .map(|c| f32::from_bits(read_u32(c)) as f64)
+                .collect(),
+            12 => self
+                .data
+                .chunks_exact(8)
+                .map(|c| {
+                    if self.big_endian {
+                        BigEndian::read_f64(c)
+                    } else {
+                        LittleEndian::read_f64(c)
+                    }
+                })
+                .collect(),
+            8 => self
+                .as_u32s()
+                .into_iter()
+                .map(|v| v as u16 as i16 as f64)
+                .collect(),
+            9 => self
+                .as_u32s()
+                .into_iter()
+                .map(|v| v as i32 as f64)
+                .collect(),
+            _ => self.as_u32s().into_iter().map(|v| v as f64).collect(),
+        }
+    }
+
+    /// RATIONAL and SRATIONAL values as numerator and denominator words.
+    pub fn as_u32_pairs(&self) -> Vec<[u32; 2]> {
+        self.data
+            .chunks_exact(8)
+            .map(|c| match self.big_endian {
+                true => [BigEndian::read_u32(&c[..4]), BigEndian::read_u32(&c[4..])],
+                false => [
+                    LittleEndian::read_u32(&c[..4]),
+                    LittleEndian::read_u32(&c[4..]),
+                ],
+            })
+            .collect()
+    }
+
+    pub fn as_string(&self) -> String {
+        String::from_utf8_lossy(&self.data)
+            .trim_end_matches('\x00')
+            .to_string()
+    }
+}
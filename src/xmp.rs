@@ -0,0 +1,428 @@
+/*
+XMP metadata for DNGs.
+
+Every DNG gets an XMP packet (the XMLPacket tag) restating the Sinar
+metadata, plus descriptive fields supplied by the user. Those come from, in
+increasing priority:
+
+1. an `iatodng.yaml`, `iatodng.yml` or `iatodng.json` file in the IA's
+   folder, applying to the whole shoot;
+2. command line flags, applying to the whole run;
+3. with `--xmp-sidecar`, an `.xmp` sidecar next to the IA, for ratings,
+   labels and keywords added per frame in another application.
+
+Keywords from all three are kept; other fields are replaced.
+*/
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::debug;
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::name::{Namespace, ResolveResult};
+use quick_xml::reader::NsReader;
+use serde::Deserialize;
+
+use crate::sinar_ia::SinarIAMeta;
+
+/// Per-folder metadata files, tried in order.
+pub const FOLDER_FILES: [&str; 3] = ["iatodng.yaml", "iatodng.yml", "iatodng.json"];
+
+const NS_RDF: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+const NS_XMP: &str = "http://ns.adobe.com/xap/1.0/";
+const NS_DC: &str = "http://purl.org/dc/elements/1.1/";
+const NS_TIFF: &str = "http://ns.adobe.com/tiff/1.0/";
+const NS_EXIF: &str = "http://ns.adobe.com/exif/1.0/";
+const NS_AUX: &str = "http://ns.adobe.com/exif/1.0/aux/";
+const NS_PHOTOSHOP: &str = "http://ns.adobe.com/photoshop/1.0/";
+/// Fields with no standard property: the client and the BR/WR references.
+const NS_IATODNG: &str = "urn:iatodng:1.0/";
+
+/// Descriptive metadata supplied by the user.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Annotations {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub creator: Option<String>,
+    pub copyright: Option<String>,
+    pub client: Option<String>,
+    /// Job number, as photoshop:TransmissionReference
+    pub job: Option<String>,
+    pub keywords: Vec<String>,
+    /// -1 (rejected) to 5
+    pub rating: Option<i8>,
+    /// Colour label, e.g. `Red`
+    pub label: Option<String>,
+}
+
+impl Annotations {
+    /// Take the fields set in `other`, adding its keywords to ours.
+    pub fn merge(&mut self, other: Annotations) {
+        fn take(field: &mut Option<String>, other: Option<String>) {
+            if other.is_some() {
+                *field = other;
+            }
+        }
+        take(&mut self.title, other.title);
+        take(&mut self.description, other.description);
+        take(&mut self.creator, other.creator);
+        take(&mut self.copyright, other.copyright);
+        take(&mut self.client, other.client);
+        take(&mut self.job, other.job);
+        take(&mut self.label, other.label);
+        if other.rating.is_some() {
+            self.rating = other.rating;
+        }
+        for keyword in other.keywords {
+            if !self.keywords.contains(&keyword) {
+                self.keywords.push(keyword);
+            }
+        }
+    }
+
+    fn check(self, source: &Path) -> io::Result<Self> {
+        match self.rating {
+            Some(rating) if !(-1..=5).contains(&rating) => Err(invalid(format!(
+                "{}: rating {} is not between -1 and 5",
+                source.display(),
+                rating
+            ))),
+            _ => Ok(self),
+        }
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Where the user's fields come from.
+#[derive(Debug, Clone, Default)]
+pub struct XmpOptions {
+    /// Fields given on the command line
+    pub annotations: Annotations,
+    /// Merge ratings, labels and keywords from `.xmp` sidecars
+    pub sidecar: bool,
+}
+
+impl XmpOptions {
+    /// The user's fields for the IA file at `ia`.
+    pub fn for_ia(&self, ia: &Path) -> io::Result<Annotations> {
+        let mut annotations = match ia.parent() {
+            Some(folder) => read_folder(folder)?.unwrap_or_default(),
+            None => Annotations::default(),
+        };
+        annotations.merge(self.annotations.clone());
+        if self.sidecar {
+            if let Some(path) = sidecar_path(ia) {
+                debug!("Merging {}", path.display());
+                annotations.merge(read_sidecar(&path)?);
+            }
+        }
+        Ok(annotations)
+    }
+}
+
+/// The metadata file in `folder`, if there is one. JSON is read as YAML,
+/// which it is a subset of.
+pub fn read_folder(folder: &Path) -> io::Result<Option<Annotations>> {
+    let path = match FOLDER_FILES
+        .iter()
+        .map(|name| folder.join(name))
+        .find(|path| path.is_file())
+    {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let text = fs::read_to_string(&path)?;
+    let annotations: Annotations =
+        serde_yaml::from_str(&text).map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+    annotations.check(&path).map(Some)
+}
+
+/// The sidecar of the IA file at `ia`: `<stem>.xmp`, or `<name>.xmp` as
+/// some applications write it.
+pub fn sidecar_path(ia: &Path) -> Option<PathBuf> {
+    let name = ia.file_name()?.to_string_lossy().to_string();
+    let stem = ia.file_stem()?.to_string_lossy().to_string();
+    [&stem, &name]
+        .iter()
+        .flat_map(|base| [format!("{}.xmp", base), format!("{}.XMP", base)])
+        .map(|sidecar| ia.with_file_name(sidecar))
+        .find(|path| path.is_file())
+}
+
+pub fn read_sidecar(path: &Path) -> io::Result<Annotations> {
+    parse_sidecar(&fs::read_to_string(path)?)
+        .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?
+        .check(path)
+}
+
+fn bound_to(ns: &ResolveResult, uri: &str) -> bool {
+    matches!(ns, ResolveResult::Bound(Namespace(bound)) if *bound == uri.as_bytes())
+}
+
+// Rating or label, given as an xmp: element or attribute.
+fn take_xmp(annotations: &mut Annotations, name: &[u8], value: &str) {
+    match name {
+        b"Rating" => annotations.rating = value.parse::<f32>().ok().map(|r| r as i8),
+        b"Label" => annotations.label = Some(value.to_string()),
+        _ => (),
+    }
+}
+
+fn take_attributes(
+    reader: &NsReader<&[u8]>,
+    start: &BytesStart,
+    annotations: &mut Annotations,
+) -> Result<(), quick_xml::Error> {
+    for attribute in start.attributes() {
+        let attribute = attribute?;
+        let (ns, name) = reader.resolve_attribute(attribute.key);
+        if bound_to(&ns, NS_XMP) {
+            take_xmp(annotations, name.as_ref(), &attribute.unescape_value()?);
+        }
+    }
+    Ok(())
+}
+
+/// The rating, label and keywords (dc:subject) in an XMP packet.
+pub fn parse_sidecar(xml: &str) -> Result<Annotations, quick_xml::Error> {
+    let mut reader = NsReader::from_str(xml);
+    let mut annotations = Annotations::default();
+    // Namespace URI and local name of the open elements.
+    let mut open: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+    loop {
+        match reader.read_resolved_event()? {
+            (ns, Event::Start(start)) => {
+                let uri = match ns {
+                    ResolveResult::Bound(Namespace(uri)) => uri.to_vec(),
+                    _ => Vec::new(),
+                };
+                take_attributes(&reader, &start, &mut annotations)?;
+                open.push((uri, start.local_name().as_ref().to_vec()));
+            }
+            (_, Event::Empty(start)) => take_attributes(&reader, &start, &mut annotations)?,
+            (_, Event::End(_)) => {
+                open.pop();
+            }
+            (_, Event::Text(text)) => {
+                let value = text.unescape()?;
+                let value = value.trim();
+                let in_subject = open
+                    .iter()
+                    .any(|(ns, name)| ns == NS_DC.as_bytes() && name == b"subject");
+                match open.last() {
+                    _ if value.is_empty() => (),
+                    Some((ns, name)) if ns == NS_RDF.as_bytes() && name == b"li" && in_subject => {
+                        let keyword = value.to_string();
+                        if !annotations.keywords.contains(&keyword) {
+                            annotations.keywords.push(keyword);
+                        }
+                    }
+                    Some((ns, name)) if ns == NS_XMP.as_bytes() => {
+                        take_xmp(&mut annotations, name, value)
+                    }
+                    _ => (),
+                }
+            }
+            (_, Event::Eof) => break,
+            _ => (),
+        }
+    }
+    Ok(annotations)
+}
+
+// Properties of one rdf:Description, as XML.
+#[derive(Default)]
+struct Properties(String);
+
+impl Properties {
+    fn simple(&mut self, name: &str, value: &str) {
+        self.0 += &format!("   <{0}>{1}</{0}>\n", name, escape(value));
+    }
+
+    fn alt(&mut self, name: &str, value: &str) {
+        self.0 += &format!(
+            "   <{0}>\n    <rdf:Alt>\n     <rdf:li xml:lang=\"x-default\">{1}</rdf:li>\n    </rdf:Alt>\n   </{0}>\n",
+            name,
+            escape(value)
+        );
+    }
+
+    fn list(&mut self, name: &str, kind: &str, values: &[String]) {
+        if values.is_empty() {
+            return;
+        }
+        self.0 += &format!("   <{}>\n    <rdf:{}>\n", name, kind);
+        for value in values {
+            self.0 += &format!("     <rdf:li>{}</rdf:li>\n", escape(value.as_str()));
+        }
+        self.0 += &format!("    </rdf:{}>\n   </{}>\n", kind, name);
+    }
+}
+
+/// The XMP packet for `meta` and its annotations.
+pub fn packet(meta: &SinarIAMeta) -> String {
+    let annotations = &meta.annotations;
+    let mut properties = Properties::default();
+    properties.simple("xmp:CreatorTool", "iatodng_rs v1.0");
+    if let Some(captured) = &meta.captured {
+        properties.simple("xmp:CreateDate", &captured.xmp_datetime());
+        properties.simple("exif:DateTimeOriginal", &captured.xmp_datetime());
+    }
+    if let Some(rating) = annotations.rating {
+        properties.simple("xmp:Rating", &rating.to_string());
+    }
+    if let Some(label) = &annotations.label {
+        properties.simple("xmp:Label", label);
+    }
+    properties.simple("tiff:Make", &meta.camera);
+    properties.simple("tiff:Model", &meta.model);
+    if meta.f_stop > 0.0 {
+        properties.simple(
+            "exif:FNumber",
+            &format!("{}/10", (meta.f_stop * 10.0).round()),
+        );
+    }
+    if meta.measured_shutter_us > 0 {
+        properties.simple(
+            "exif:ExposureTime",
+            &format!("{}/1000000", meta.measured_shutter_us),
+        );
+    }
+    if meta.iso > 0 {
+        properties.list("exif:ISOSpeedRatings", "Seq", &[meta.iso.to_string()]);
+    }
+    properties.simple("aux:SerialNumber", &meta.serial);
+    properties.simple("aux:ImageNumber", &meta.shutter_count.to_string());
+    match &meta.lens {
+        Some(lens) => properties.simple("aux:Lens", &lens.model),
+        None if meta.focal_length > 0.0 => {
+            properties.simple("aux:Lens", &format!("{} mm", meta.focal_length))
+        }
+        None => (),
+    }
+    if let Some(title) = &annotations.title {
+        properties.alt("dc:title", title);
+    }
+    if let Some(description) = &annotations.description {
+        properties.alt("dc:description", description);
+    }
+    if let Some(creator) = &annotations.creator {
+        properties.list("dc:creator", "Seq", std::slice::from_ref(creator));
+    }
+    if let Some(copyright) = &annotations.copyright {
+        properties.alt("dc:rights", copyright);
+    }
+    properties.list("dc:subject", "Bag", &annotations.keywords);
+    if let Some(job) = &annotations.job {
+        properties.simple("photoshop:TransmissionReference", job);
+    }
+    if let Some(client) = &annotations.client {
+        properties.simple("iatodng:Client", client);
+    }
+    properties.simple("iatodng:BlackReference", &meta.black_ref);
+    properties.simple("iatodng:WhiteReference", &meta.white_ref);
+
+    let namespaces = [
+        ("xmp", NS_XMP),
+        ("dc", NS_DC),
+        ("tiff", NS_TIFF),
+        ("exif", NS_EXIF),
+        ("aux", NS_AUX),
+        ("photoshop", NS_PHOTOSHOP),
+        ("iatodng", NS_IATODNG),
+    ]
+    .iter()
+    .map(|(prefix, uri)| format!("\n    xmlns:{}=\"{}\"", prefix, uri))
+    .collect::<String>();
+    format!(
+        "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n\
+         <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n \
+         <rdf:RDF xmlns:rdf=\"{}\">\n  \
+         <rdf:Description rdf:about=\"\"{}>\n\
+         {}  </rdf:Description>\n \
+         </rdf:RDF>\n\
+         </x:xmpmeta>\n\
+         <?xpacket end=\"w\"?>",
+        NS_RDF, namespaces, properties.0
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xmp_sources() {
+        let dir = crate::test_dir("xmp");
+        let ia = dir.join("6C486AFC.IA");
+        std::fs::write(
+            dir.join("iatodng.yaml"),
+            "client: Smith & Co\njob: J-1\nrating: 1\nkeywords: [studio, catalogue]\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("6C486AFC.xmp"),
+            r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:xap="http://ns.adobe.com/xap/1.0/"
+    xmlns:dc="http://purl.org/dc/elements/1.1/" xap:Rating="4">
+   <xap:Label>Red</xap:Label>
+   <dc:subject><rdf:Bag><rdf:li>select</rdf:li><rdf:li>studio</rdf:li></rdf:Bag></dc:subject>
+   <dc:title><rdf:Alt><rdf:li xml:lang="x-default">not a keyword</rdf:li></rdf:Alt></dc:title>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#,
+        )
+        .unwrap();
+        let options = XmpOptions {
+            annotations: Annotations {
+                job: Some("J-2".to_string()),
+                keywords: vec!["hero".to_string()],
+                ..Annotations::default()
+            },
+            sidecar: false,
+        };
+        let without = options.for_ia(&ia).unwrap();
+        assert_eq!(without.client.as_deref(), Some("Smith & Co"));
+        assert_eq!(without.job.as_deref(), Some("J-2"));
+        assert_eq!(without.rating, Some(1));
+        assert_eq!(without.keywords, ["studio", "catalogue", "hero"]);
+        let options = XmpOptions {
+            sidecar: true,
+            ..options
+        };
+        let annotations = options.for_ia(&ia).unwrap();
+        assert_eq!(annotations.rating, Some(4));
+        assert_eq!(annotations.label.as_deref(), Some("Red"));
+        assert_eq!(
+            annotations.keywords,
+            ["studio", "catalogue", "hero", "select"]
+        );
+
+        let meta = SinarIAMeta {
+            shutter_count: 1234,
+            annotations: annotations.clone(),
+            ..SinarIAMeta::default()
+        };
+        let packet = packet(&meta);
+        assert!(packet.contains("<iatodng:Client>Smith &amp; Co</iatodng:Client>"));
+        assert!(packet.contains("<photoshop:TransmissionReference>J-2<"));
+        assert!(packet.contains("<aux:ImageNumber>1234<"));
+        let reread = parse_sidecar(&packet).unwrap();
+        assert_eq!(reread.rating, annotations.rating);
+        assert_eq!(reread.label, annotations.label);
+        assert_eq!(reread.keywords, annotations.keywords);
+
+        std::fs::write(dir.join("iatodng.yaml"), "rating: 9\n").unwrap();
+        assert!(options.for_ia(&ia).is_err());
+        std::fs::write(dir.join("iatodng.yaml"), "cleint: typo\n").unwrap();
+        assert!(options.for_ia(&ia).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}